@@ -1,22 +1,49 @@
 use libdoh::*;
 
 use crate::constants::*;
+use crate::utils::{parse_dns64_prefix, parse_port_range};
 
-use clap::Arg;
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs};
-use std::time::Duration;
-
-#[cfg(feature = "tls")]
+use clap::{Arg, ArgMatches};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+use tokio::runtime;
 
-pub fn parse_opts(globals: &mut Globals) {
-    use crate::utils::{verify_remote_server, verify_sock_addr};
+// The default values are borrowed by the matches, which are only lent to `f`
+pub fn with_matches<T>(f: impl FnOnce(&ArgMatches) -> T) -> T {
+    use crate::utils::{
+        verify_dns64_prefix, verify_edns_size, verify_ip_addr, verify_ipv4_prefix_len,
+        verify_ipv6_prefix_len, verify_network, verify_nonzero, verify_port_range,
+        verify_remote_server, verify_response_header, verify_sock_addr,
+    };
 
     let max_clients = MAX_CLIENTS.to_string();
+    let max_request_size = MAX_REQUEST_SIZE.to_string();
+    let max_client_packet_size = MAX_CLIENT_PACKET_SIZE.to_string();
+    let body_read_timeout_ms = BODY_READ_TIMEOUT_MS.to_string();
+    let tcp_pool_size = TCP_POOL_SIZE.to_string();
+    let tcp_idle_timeout = TCP_IDLE_TIMEOUT_SEC.to_string();
+    let udp_edns_size = UDP_EDNS_SIZE.to_string();
+    let tcp_edns_size = TCP_EDNS_SIZE.to_string();
+    let max_concurrent_streams = MAX_CONCURRENT_STREAMS.to_string();
     let timeout_sec = TIMEOUT_SEC.to_string();
+    let upstream_retries = UPSTREAM_RETRIES.to_string();
+    let upstream_failure_threshold = UPSTREAM_FAILURE_THRESHOLD.to_string();
+    let upstream_cooldown_secs = UPSTREAM_COOLDOWN_SECS.to_string();
+    let shutdown_grace_secs = SHUTDOWN_GRACE_SECS.to_string();
     let min_ttl = MIN_TTL.to_string();
     let max_ttl = MAX_TTL.to_string();
     let err_ttl = ERR_TTL.to_string();
+    let unreachable_ede_code = UNREACHABLE_EDE_CODE.to_string();
+    let cache_size = CACHE_SIZE.to_string();
+    let prefetch_min_hits = PREFETCH_MIN_HITS.to_string();
+    let overrides_ttl = OVERRIDES_TTL.to_string();
+    let dns64_prefix = format!("{}/96", DNS64_PREFIX);
+    let client_subnet_prefix_v4 = CLIENT_SUBNET_PREFIX_V4.to_string();
+    let malformed_log_interval_ms = MALFORMED_LOG_INTERVAL_MS.to_string();
+    let malformed_log_hexdump_len = MALFORMED_LOG_HEXDUMP_LEN.to_string();
+    let client_subnet_prefix_v6 = CLIENT_SUBNET_PREFIX_V6.to_string();
 
     let _ = include_str!("../Cargo.toml");
     let options = app_from_crate!()
@@ -29,14 +56,36 @@ pub fn parse_opts(globals: &mut Globals) {
                 .validator(verify_sock_addr)
                 .help("Address to listen to"),
         )
+        .arg(
+            Arg::with_name("reuse_port")
+                .long("reuse-port")
+                .help("Set SO_REUSEPORT on the listening socket, so that multiple processes can share the listen address"),
+        )
         .arg(
             Arg::with_name("server_address")
                 .short("u")
                 .long("server-address")
                 .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
                 .default_value(SERVER_ADDRESS)
                 .validator(verify_remote_server)
-                .help("Address to connect to"),
+                .help("Address to connect to (can be repeated for failover)"),
+        )
+        .arg(
+            Arg::with_name("upstream_srv")
+                .long("upstream-srv")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("SRV record listing upstream servers, looked up using the system name server (can be repeated)"),
+        )
+        .arg(
+            Arg::with_name("upstream_refresh_secs")
+                .long("upstream-refresh-secs")
+                .takes_value(true)
+                .default_value("0")
+                .help("Resolve upstream host names and SRV records again at this interval, in seconds (0 to disable)"),
         )
         .arg(
             Arg::with_name("local_bind_address")
@@ -46,6 +95,93 @@ pub fn parse_opts(globals: &mut Globals) {
                 .validator(verify_sock_addr)
                 .help("Address to connect from"),
         )
+        .arg(
+            Arg::with_name("local_bind_port_range")
+                .long("local-bind-port-range")
+                .takes_value(true)
+                .validator(verify_port_range)
+                .help("Range of source ports for UDP queries to upstream servers (e.g. 50000-51000)"),
+        )
+        .arg(
+            Arg::with_name("tcp_pool_size")
+                .long("tcp-pool-size")
+                .takes_value(true)
+                .default_value(&tcp_pool_size)
+                .help("Maximum number of persistent TCP connections per upstream server (0 to disable)"),
+        )
+        .arg(
+            Arg::with_name("tcp_idle_timeout")
+                .long("tcp-idle-timeout")
+                .takes_value(true)
+                .default_value(&tcp_idle_timeout)
+                .help("Close pooled TCP connections after this many idle seconds"),
+        )
+        .arg(
+            Arg::with_name("tcp_keepalive")
+                .long("tcp-keepalive")
+                .help("Ask upstream servers for their TCP idle timeout (EDNS keepalive) and close pooled connections before it expires"),
+        )
+        .arg(
+            Arg::with_name("upstream_tcp_fastopen")
+                .long("upstream-tcp-fastopen")
+                .help("Use TCP Fast Open to send the first query in the SYN of upstream TCP connections, where supported"),
+        )
+        .arg(
+            Arg::with_name("udp_edns_size")
+                .long("udp-edns-size")
+                .takes_value(true)
+                .default_value(&udp_edns_size)
+                .validator(verify_edns_size)
+                .help("EDNS payload size advertised to upstream servers over UDP"),
+        )
+        .arg(
+            Arg::with_name("adaptive_edns_size")
+                .long("adaptive-edns-size")
+                .help("Lower the EDNS payload size advertised to an upstream server to the largest response received from it over UDP after repeated UDP timeouts, so that larger responses are retried over TCP"),
+        )
+        .arg(
+            Arg::with_name("tcp_edns_size")
+                .long("tcp-edns-size")
+                .takes_value(true)
+                .default_value(&tcp_edns_size)
+                .validator(verify_edns_size)
+                .help("EDNS payload size advertised when retrying truncated responses over TCP"),
+        )
+        .arg(
+            Arg::with_name("force_tcp")
+                .long("force-tcp")
+                .help("Always use TCP to query upstream servers"),
+        )
+        .arg(
+            Arg::with_name("qname_minimization")
+                .long("qname-minimization")
+                .help("Query parent names first and stop on NXDOMAIN (only useful with an iterative upstream)"),
+        )
+        .arg(
+            Arg::with_name("hostname")
+                .long("hostname")
+                .takes_value(true)
+                .help("Host name of the server; requests for other hosts are rejected with a 421 status code"),
+        )
+        .arg(
+            Arg::with_name("response_headers")
+                .long("header")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(verify_response_header)
+                .help("Extra HTTP header added to every response, as \"Name: value\" (can be repeated)"),
+        )
+        .arg(
+            Arg::with_name("enable_hsts")
+                .long("hsts")
+                .help("Add a Strict-Transport-Security header to every response"),
+        )
+        .arg(
+            Arg::with_name("server_timing")
+                .long("server-timing")
+                .help("Add a Server-Timing header with the duration of the cache lookup, upstream query and response rewriting (reveals internal timings)"),
+        )
         .arg(
             Arg::with_name("path")
                 .short("p")
@@ -54,6 +190,14 @@ pub fn parse_opts(globals: &mut Globals) {
                 .default_value(PATH)
                 .help("URI path"),
         )
+        .arg(
+            Arg::with_name("path_aliases")
+                .long("path-alias")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Additional URI path to accept DoH queries on (can be repeated)"),
+        )
         .arg(
             Arg::with_name("max_clients")
                 .short("c")
@@ -62,6 +206,49 @@ pub fn parse_opts(globals: &mut Globals) {
                 .default_value(&max_clients)
                 .help("Maximum number of simultaneous clients"),
         )
+        .arg(
+            Arg::with_name("max_concurrent_streams")
+                .long("max-concurrent-streams")
+                .takes_value(true)
+                .default_value(&max_concurrent_streams)
+                .help("Maximum number of concurrent HTTP/2 streams per connection"),
+        )
+        .arg(
+            Arg::with_name("overload_response")
+                .long("overload-response")
+                .takes_value(true)
+                .possible_values(&["503", "servfail"])
+                .default_value("503")
+                .help("Response sent when the number of in-flight queries reaches max-clients"),
+        )
+        .arg(
+            Arg::with_name("max_request_size")
+                .long("max-request-size")
+                .takes_value(true)
+                .default_value(&max_request_size)
+                .help("Maximum size of a POST body or a GET parameter sent by a client, in bytes"),
+        )
+        .arg(
+            Arg::with_name("max_client_packet_size")
+                .long("max-client-packet-size")
+                .takes_value(true)
+                .default_value(&max_client_packet_size)
+                .help("Maximum size of a DNS message sent by a client, in bytes, above which 413 is returned; capped at 4096, the largest message the parser accepts"),
+        )
+        .arg(
+            Arg::with_name("body_read_timeout_ms")
+                .long("body-read-timeout-ms")
+                .takes_value(true)
+                .default_value(&body_read_timeout_ms)
+                .help("Time allowed to receive the body of a POST query, in milliseconds, after which 408 is returned"),
+        )
+        .arg(
+            Arg::with_name("body_min_rate")
+                .long("body-min-rate")
+                .takes_value(true)
+                .default_value("0")
+                .help("Minimum rate at which the body of a POST query must be received after the first second, in bytes per second (0 for no minimum)"),
+        )
         .arg(
             Arg::with_name("timeout")
                 .short("t")
@@ -70,6 +257,72 @@ pub fn parse_opts(globals: &mut Globals) {
                 .default_value(&timeout_sec)
                 .help("Timeout, in seconds"),
         )
+        .arg(
+            Arg::with_name("upstream_timeout_ms")
+                .long("upstream-timeout-ms")
+                .takes_value(true)
+                .help("Timeout for upstream responses, in milliseconds, after which SERVFAIL is returned (must not exceed --timeout)"),
+        )
+        .arg(
+            Arg::with_name("upstream_retries")
+                .long("upstream-retries")
+                .takes_value(true)
+                .default_value(&upstream_retries)
+                .help("Number of UDP retransmissions before the upstream timeout expires"),
+        )
+        .arg(
+            Arg::with_name("upstream_failure_threshold")
+                .long("upstream-failure-threshold")
+                .takes_value(true)
+                .default_value(&upstream_failure_threshold)
+                .help("Number of consecutive failures after which an upstream server is temporarily skipped"),
+        )
+        .arg(
+            Arg::with_name("upstream_cooldown_secs")
+                .long("upstream-cooldown-secs")
+                .takes_value(true)
+                .default_value(&upstream_cooldown_secs)
+                .help("Time to skip a failing upstream server for, in seconds, before sending it a trial query"),
+        )
+        .arg(
+            Arg::with_name("max_inflight_per_upstream")
+                .long("max-inflight-per-upstream")
+                .takes_value(true)
+                .default_value("0")
+                .help("Maximum number of concurrent queries to each upstream server; saturated servers are skipped (0 for no limit)"),
+        )
+        .arg(
+            Arg::with_name("race_upstreams")
+                .long("race-upstreams")
+                .help("Send queries to the first two available upstream servers at once, and use the fastest response"),
+        )
+        .arg(
+            Arg::with_name("runtime_threads")
+                .long("runtime-threads")
+                .takes_value(true)
+                .validator(verify_nonzero)
+                .help("Number of worker threads (defaults to the number of CPUs)"),
+        )
+        .arg(
+            Arg::with_name("blocking_threads")
+                .long("blocking-threads")
+                .takes_value(true)
+                .validator(verify_nonzero)
+                .help("Maximum number of additional threads for blocking operations"),
+        )
+        .arg(
+            Arg::with_name("current_thread_runtime")
+                .long("current-thread-runtime")
+                .conflicts_with_all(&["runtime_threads", "blocking_threads"])
+                .help("Run everything on a single thread instead of a pool of worker threads"),
+        )
+        .arg(
+            Arg::with_name("shutdown_grace_secs")
+                .long("shutdown-grace-secs")
+                .takes_value(true)
+                .default_value(&shutdown_grace_secs)
+                .help("On SIGTERM or SIGINT, time to wait for connected clients before exiting, in seconds"),
+        )
         .arg(
             Arg::with_name("min_ttl")
                 .short("T")
@@ -94,6 +347,31 @@ pub fn parse_opts(globals: &mut Globals) {
                 .default_value(&err_ttl)
                 .help("TTL for errors, in seconds"),
         )
+        .arg(
+            Arg::with_name("nxdomain_ttl")
+                .long("nxdomain-ttl")
+                .takes_value(true)
+                .help("TTL for NXDOMAIN responses without a SOA record, in seconds (defaults to --err-ttl)"),
+        )
+        .arg(
+            Arg::with_name("servfail_ttl")
+                .long("servfail-ttl")
+                .takes_value(true)
+                .help("TTL for SERVFAIL responses, in seconds (defaults to --err-ttl)"),
+        )
+        .arg(
+            Arg::with_name("refused_ttl")
+                .long("refused-ttl")
+                .takes_value(true)
+                .help("TTL for REFUSED responses, in seconds (defaults to --err-ttl)"),
+        )
+        .arg(
+            Arg::with_name("unreachable_ede_code")
+                .long("unreachable-ede-code")
+                .takes_value(true)
+                .default_value(&unreachable_ede_code)
+                .help("Extended DNS error code sent with the SERVFAIL response when no upstream server can be reached"),
+        )
         .arg(
             Arg::with_name("disable_keepalive")
                 .short("K")
@@ -105,6 +383,319 @@ pub fn parse_opts(globals: &mut Globals) {
                 .short("P")
                 .long("disable-post")
                 .help("Disable POST queries"),
+        )
+        .arg(
+            Arg::with_name("padding_block_size")
+                .long("padding-block-size")
+                .takes_value(true)
+                .default_value("0")
+                .help("Pad responses to a multiple of this size (0 for default boundaries)"),
+        )
+        .arg(
+            Arg::with_name("randomize_case")
+                .long("randomize-case")
+                .help("Randomize the case of query names sent to the server over UDP (DNS 0x20)"),
+        )
+        .arg(
+            Arg::with_name("refuse_any")
+                .long("refuse-any")
+                .help("Refuse queries for the ANY type"),
+        )
+        .arg(
+            Arg::with_name("allow_ping")
+                .long("allow-ping")
+                .help("Answer GET requests without parameters with a 204 status, so that clients can check that the server is reachable"),
+        )
+        .arg(
+            Arg::with_name("enable_odoh")
+                .long("enable-odoh")
+                .help("Act as an Oblivious DoH target: decrypt application/oblivious-dns-message queries, and publish the public key on /odoh-config"),
+        )
+        .arg(
+            Arg::with_name("odoh_key_seed_path")
+                .long("odoh-key-seed-path")
+                .takes_value(true)
+                .requires("enable_odoh")
+                .help("File of at least 32 random bytes to derive the Oblivious DoH key pair from, instead of a new key pair on every start"),
+        )
+        .arg(
+            Arg::with_name("allow_all_opcodes")
+                .long("allow-all-opcodes")
+                .help("Forward queries with any opcode; by default, only standard queries are handled, and other opcodes get a NOTIMP response"),
+        )
+        .arg(
+            Arg::with_name("minimize_responses")
+                .long("minimize-responses")
+                .help("Remove additional records, except the OPT record, from responses sent to clients"),
+        )
+        .arg(
+            Arg::with_name("max_response_size")
+                .long("max-response-size")
+                .takes_value(true)
+                .default_value("0")
+                .help("Truncate responses sent to clients above this size, in bytes, and set the TC bit; lowered to the EDNS payload size advertised by clients. Upstream responses and the cache are not affected (0 for no limit)"),
+        )
+        .arg(
+            Arg::with_name("debug_packets")
+                .long("debug-packets")
+                .help("Print a description of every query and response to stderr"),
+        )
+        .arg(
+            Arg::with_name("cache_size")
+                .long("cache-size")
+                .takes_value(true)
+                .default_value(&cache_size)
+                .help("Maximum number of cached responses (0 to disable the cache)"),
+        )
+        .arg(
+            Arg::with_name("max_cache_ttl")
+                .long("max-cache-ttl")
+                .takes_value(true)
+                .default_value("0")
+                .help("Maximum time to keep a response in the cache, in seconds, without changing the TTLs sent to clients (0 for no limit)"),
+        )
+        .arg(
+            Arg::with_name("ttl_jitter_pct")
+                .long("ttl-jitter-pct")
+                .takes_value(true)
+                .default_value("0")
+                .help("Randomly lower the TTLs of cached responses by up to this percentage, but not below --min-ttl"),
+        )
+        .arg(
+            Arg::with_name("serve_stale")
+                .long("serve-stale")
+                .takes_value(true)
+                .default_value("0")
+                .help("Serve expired cached responses for up to this many seconds if the server fails"),
+        )
+        .arg(
+            Arg::with_name("prefetch_threshold")
+                .long("prefetch-threshold")
+                .takes_value(true)
+                .default_value("0")
+                .help("Refresh cached responses once their remaining TTL drops below this percentage (0 to disable)"),
+        )
+        .arg(
+            Arg::with_name("prefetch_min_hits")
+                .long("prefetch-min-hits")
+                .takes_value(true)
+                .default_value(&prefetch_min_hits)
+                .help("Minimum number of cache hits for a response to be prefetched"),
+        )
+        .arg(
+            Arg::with_name("cache_persist_path")
+                .long("cache-persist-path")
+                .takes_value(true)
+                .help("File to save the cache to on shutdown, and to load it from on startup"),
+        )
+        .arg(
+            Arg::with_name("max_qps_per_client")
+                .long("max-qps-per-client")
+                .takes_value(true)
+                .default_value("0")
+                .help("Maximum number of queries per second from a single client IP (0 for no limit)"),
+        )
+        .arg(
+            Arg::with_name("client_ip_header")
+                .long("client-ip-header")
+                .takes_value(true)
+                .help("Trusted HTTP header to read the client IP from (e.g. X-Forwarded-For)"),
+        )
+        .arg(
+            Arg::with_name("trusted_proxies")
+                .long("trusted-proxies")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(verify_network)
+                .help("Read the client IP from Forwarded/X-Forwarded-For when the peer is in this network (can be repeated)"),
+        )
+        .arg(
+            Arg::with_name("append_client_subnet")
+                .long("append-client-subnet")
+                .help("Send the network of the client IP to upstream servers (EDNS client subnet); responses are then not cached"),
+        )
+        .arg(
+            Arg::with_name("client_subnet_prefix_v4")
+                .long("client-subnet-prefix-v4")
+                .takes_value(true)
+                .default_value(&client_subnet_prefix_v4)
+                .validator(verify_ipv4_prefix_len)
+                .help("Prefix length of the client subnet sent for IPv4 clients"),
+        )
+        .arg(
+            Arg::with_name("client_subnet_prefix_v6")
+                .long("client-subnet-prefix-v6")
+                .takes_value(true)
+                .default_value(&client_subnet_prefix_v6)
+                .validator(verify_ipv6_prefix_len)
+                .help("Prefix length of the client subnet sent for IPv6 clients"),
+        )
+        .arg(
+            Arg::with_name("allowed_networks")
+                .long("allowed-networks")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(verify_network)
+                .help("Only accept queries from this network, in CIDR notation (can be repeated)"),
+        )
+        .arg(
+            Arg::with_name("blocklist")
+                .long("blocklist")
+                .takes_value(true)
+                .help("File of blocked domains, one per line (reloaded on SIGHUP)"),
+        )
+        .arg(
+            Arg::with_name("blocklist_response")
+                .long("blocklist-response")
+                .takes_value(true)
+                .possible_values(&["nxdomain", "nodata"])
+                .default_value("nxdomain")
+                .help("Response sent for blocked names"),
+        )
+        .arg(
+            Arg::with_name("allowlist")
+                .long("allowlist")
+                .takes_value(true)
+                .help("File of domains that are never blocked, one per line (reloaded on SIGHUP)"),
+        )
+        .arg(
+            Arg::with_name("default_deny")
+                .long("default-deny")
+                .requires("allowlist")
+                .help("Refuse queries for names that are not in the allowlist"),
+        )
+        .arg(
+            Arg::with_name("deny_private_answers")
+                .long("deny-private-answers")
+                .help("Answer NXDOMAIN when a name resolves to a private, loopback or link-local address"),
+        )
+        .arg(
+            Arg::with_name("private_answers_allowed")
+                .long("private-answers-allowed")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .requires("deny_private_answers")
+                .help("Name, including its subdomains, allowed to resolve to private addresses (can be repeated)"),
+        )
+        .arg(
+            Arg::with_name("poison_ips")
+                .long("poison-ip")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(verify_ip_addr)
+                .help("Address injected by networks tampering with DNS; poisoned responses are retried over TCP, and rejected if still poisoned (can be repeated)"),
+        )
+        .arg(
+            Arg::with_name("strip_poisoned")
+                .long("strip-poisoned")
+                .requires("poison_ips")
+                .help("Remove the records pointing to poisoned addresses instead of rejecting the responses"),
+        )
+        .arg(
+            Arg::with_name("overrides")
+                .long("overrides")
+                .takes_value(true)
+                .help("File of local answers, one \"<name> <A|AAAA> <address>\" entry per line"),
+        )
+        .arg(
+            Arg::with_name("overrides_ttl")
+                .long("overrides-ttl")
+                .takes_value(true)
+                .default_value(&overrides_ttl)
+                .help("TTL of local answers"),
+        )
+        .arg(
+            Arg::with_name("local_zone")
+                .long("local-zone")
+                .takes_value(true)
+                .help("Zone file (RFC 1035 format) to answer matching queries from; other queries are forwarded"),
+        )
+        .arg(
+            Arg::with_name("synthesize_aaaa")
+                .long("synthesize-aaaa")
+                .help("DNS64: synthesize AAAA records from the A records of names that have no AAAA records"),
+        )
+        .arg(
+            Arg::with_name("dns64_prefix")
+                .long("dns64-prefix")
+                .takes_value(true)
+                .default_value(&dns64_prefix)
+                .validator(verify_dns64_prefix)
+                .help("NAT64 prefix of synthesized AAAA records (must be a /96)"),
+        )
+        .arg(
+            Arg::with_name("chaos_version")
+                .long("chaos-version")
+                .takes_value(true)
+                .help("Answer to version.bind CH TXT queries (refused if not set)"),
+        )
+        .arg(
+            Arg::with_name("chaos_hostname")
+                .long("chaos-hostname")
+                .takes_value(true)
+                .help("Answer to hostname.bind CH TXT queries (refused if not set)"),
+        )
+        .arg(
+            Arg::with_name("nsid")
+                .long("nsid")
+                .takes_value(true)
+                .help("Name server identifier returned to clients sending an NSID option (defaults to --hostname)"),
+        )
+        .arg(
+            Arg::with_name("enable_metrics")
+                .long("enable-metrics")
+                .help("Expose Prometheus metrics on the /metrics path"),
+        )
+        .arg(
+            Arg::with_name("metrics_address")
+                .long("metrics-address")
+                .takes_value(true)
+                .validator(verify_sock_addr)
+                .help("Serve metrics on a separate address instead of the main listener"),
+        )
+        .arg(
+            Arg::with_name("query_log")
+                .long("query-log")
+                .takes_value(true)
+                .help("Log queries to this file (- for stderr)"),
+        )
+        .arg(
+            Arg::with_name("query_log_format")
+                .long("query-log-format")
+                .takes_value(true)
+                .possible_values(&["plain", "json"])
+                .default_value("plain")
+                .help("Query log format"),
+        )
+        .arg(
+            Arg::with_name("log_malformed_packets")
+                .long("log-malformed-packets")
+                .help("Log packets rejected as malformed, from clients and upstream servers, to stderr"),
+        )
+        .arg(
+            Arg::with_name("malformed_log_interval_ms")
+                .long("malformed-log-interval-ms")
+                .takes_value(true)
+                .default_value(&malformed_log_interval_ms)
+                .validator(verify_nonzero)
+                .help("Minimum time between two malformed packet reports, in milliseconds; the packets in between are only counted"),
+        )
+        .arg(
+            Arg::with_name("malformed_log_hexdump_len")
+                .long("malformed-log-hexdump-len")
+                .takes_value(true)
+                .default_value(&malformed_log_hexdump_len)
+                .help("Number of bytes of malformed packets to include in reports, as a hex dump"),
+        )
+        .arg(
+            Arg::with_name("health_check_name")
+                .long("health-check-name")
+                .takes_value(true)
+                .help("Name to resolve for /health checks (default: root NS query)"),
         );
 
     #[cfg(feature = "tls")]
@@ -122,21 +713,81 @@ pub fn parse_opts(globals: &mut Globals) {
                 .long("tls-cert-key-path")
                 .takes_value(true)
                 .help("Path to the PEM-encoded secret keys (only required for built-in TLS)"),
+        )
+        .arg(
+            Arg::with_name("enable_dot")
+                .long("enable-dot")
+                .requires_all(&["tls_cert_path", "tls_cert_key_path"])
+                .help("Also accept DNS over TLS connections, using the same certificate as DoH"),
+        )
+        .arg(
+            Arg::with_name("dot_listen_address")
+                .long("dot-listen-address")
+                .takes_value(true)
+                .default_value(DOT_LISTEN_ADDRESS)
+                .validator(verify_sock_addr)
+                .help("Address to accept DNS over TLS connections on"),
         );
 
-    let matches = options.get_matches();
+    #[cfg(feature = "http3")]
+    let options = options.arg(
+        Arg::with_name("enable_http3")
+            .long("enable-http3")
+            .requires_all(&["tls_cert_path", "tls_cert_key_path"])
+            .help("Also accept HTTP/3 connections over UDP, on the same address and port"),
+    );
+
+    f(&options.get_matches())
+}
+
+pub fn configure_runtime(runtime_builder: &mut runtime::Builder, matches: &ArgMatches) {
+    if matches.is_present("current_thread_runtime") {
+        runtime_builder.basic_scheduler();
+        return;
+    }
+    runtime_builder.threaded_scheduler();
+    let runtime_threads = match matches.value_of("runtime_threads") {
+        Some(runtime_threads) => runtime_threads.parse().unwrap(),
+        None => thread::available_parallelism().map_or(1, |n| n.get()),
+    };
+    runtime_builder.core_threads(runtime_threads);
+    if let Some(blocking_threads) = matches.value_of("blocking_threads") {
+        let blocking_threads: usize = blocking_threads.parse().unwrap();
+        runtime_builder.max_threads(runtime_threads + blocking_threads);
+    }
+}
+
+pub fn parse_opts(globals: &mut Globals, matches: &ArgMatches) {
     globals.listen_address = matches.value_of("listen_address").unwrap().parse().unwrap();
+    globals.reuse_port = matches.is_present("reuse_port");
 
-    globals.server_address = matches
-        .value_of("server_address")
-        .unwrap()
-        .to_socket_addrs()
+    // The default server address is only used if no SRV record is given
+    let upstream_srvs = matches.values_of("upstream_srv");
+    if upstream_srvs.is_none() || matches.occurrences_of("server_address") > 0 {
+        globals.upstream_sources = matches
+            .values_of("server_address")
+            .unwrap()
+            .map(|server_address| UpstreamSource::Host(server_address.to_string()))
+            .collect();
+    }
+    if let Some(upstream_srvs) = upstream_srvs {
+        globals
+            .upstream_sources
+            .extend(upstream_srvs.map(|name| UpstreamSource::Srv(name.to_string())));
+    }
+    let upstream_addresses = resolve_upstreams(&globals.upstream_sources).unwrap();
+    globals.upstreams = Upstreams::new(&upstream_addresses);
+    let upstream_refresh_secs: u64 = matches
+        .value_of("upstream_refresh_secs")
         .unwrap()
-        .next()
+        .parse()
         .unwrap();
+    if upstream_refresh_secs > 0 {
+        globals.upstream_refresh_interval = Some(Duration::from_secs(upstream_refresh_secs));
+    }
     globals.local_bind_address = match matches.value_of("local_bind_address") {
         Some(address) => address.parse().unwrap(),
-        None => match globals.server_address {
+        None => match upstream_addresses[0] {
             SocketAddr::V4(_) => SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)),
             SocketAddr::V6(s) => SocketAddr::V6(SocketAddrV6::new(
                 Ipv6Addr::UNSPECIFIED,
@@ -146,21 +797,284 @@ pub fn parse_opts(globals: &mut Globals) {
             )),
         },
     };
+    globals.local_bind_port_range = matches
+        .value_of("local_bind_port_range")
+        .and_then(parse_port_range);
+    globals.tcp_pool_size = matches.value_of("tcp_pool_size").unwrap().parse().unwrap();
+    globals.tcp_idle_timeout = Duration::from_secs(
+        matches
+            .value_of("tcp_idle_timeout")
+            .unwrap()
+            .parse()
+            .unwrap(),
+    );
+    globals.tcp_keepalive = matches.is_present("tcp_keepalive");
+    globals.upstream_tcp_fastopen = matches.is_present("upstream_tcp_fastopen");
+    globals.udp_edns_size = matches.value_of("udp_edns_size").unwrap().parse().unwrap();
+    globals.adaptive_edns_size = matches.is_present("adaptive_edns_size");
+    globals.tcp_edns_size = matches.value_of("tcp_edns_size").unwrap().parse().unwrap();
+    globals.force_tcp = matches.is_present("force_tcp");
+    globals.qname_minimization = matches.is_present("qname_minimization");
+    globals.hostname = matches.value_of("hostname").map(String::from);
+    if let Some(response_headers) = matches.values_of("response_headers") {
+        globals.response_headers = response_headers
+            .map(|response_header| response_header.parse().unwrap())
+            .collect();
+    }
+    if matches.is_present("enable_hsts") {
+        globals.response_headers.push(ResponseHeader::hsts());
+    }
+    globals.server_timing = matches.is_present("server_timing");
     globals.path = matches.value_of("path").unwrap().to_string();
+    if let Some(path_aliases) = matches.values_of("path_aliases") {
+        globals.path_aliases = path_aliases.map(|alias| alias.to_string()).collect();
+    }
     if !globals.path.starts_with('/') {
         globals.path = format!("/{}", globals.path);
     }
     globals.max_clients = matches.value_of("max_clients").unwrap().parse().unwrap();
+    globals.max_concurrent_streams = matches
+        .value_of("max_concurrent_streams")
+        .unwrap()
+        .parse()
+        .unwrap();
+    globals.overload_servfail = matches.value_of("overload_response") == Some("servfail");
+    globals.max_request_size = matches
+        .value_of("max_request_size")
+        .unwrap()
+        .parse()
+        .unwrap();
+    globals.max_client_packet_size = matches
+        .value_of("max_client_packet_size")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap()
+        .min(dns::DNS_MAX_PACKET_SIZE);
+    globals.body_read_timeout = Duration::from_millis(
+        matches
+            .value_of("body_read_timeout_ms")
+            .unwrap()
+            .parse()
+            .unwrap(),
+    );
+    globals.body_min_rate = matches.value_of("body_min_rate").unwrap().parse().unwrap();
     globals.timeout = Duration::from_secs(matches.value_of("timeout").unwrap().parse().unwrap());
+    globals.upstream_timeout = match matches.value_of("upstream_timeout_ms") {
+        Some(upstream_timeout_ms) => Duration::from_millis(upstream_timeout_ms.parse().unwrap()),
+        None => globals.timeout,
+    };
+    globals.upstream_retries = matches
+        .value_of("upstream_retries")
+        .unwrap()
+        .parse()
+        .unwrap();
+    globals.upstream_failure_threshold = matches
+        .value_of("upstream_failure_threshold")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap()
+        .max(1);
+    globals.upstream_cooldown = Duration::from_secs(
+        matches
+            .value_of("upstream_cooldown_secs")
+            .unwrap()
+            .parse()
+            .unwrap(),
+    );
+    globals.race_upstreams = matches.is_present("race_upstreams");
+    globals.max_inflight_per_upstream = matches
+        .value_of("max_inflight_per_upstream")
+        .unwrap()
+        .parse()
+        .unwrap();
+    globals.shutdown_grace_period = Duration::from_secs(
+        matches
+            .value_of("shutdown_grace_secs")
+            .unwrap()
+            .parse()
+            .unwrap(),
+    );
+    if globals.upstream_timeout > globals.timeout {
+        clap::Error::with_description(
+            "--upstream-timeout-ms must not exceed --timeout",
+            clap::ErrorKind::ValueValidation,
+        )
+        .exit();
+    }
     globals.min_ttl = matches.value_of("min_ttl").unwrap().parse().unwrap();
     globals.max_ttl = matches.value_of("max_ttl").unwrap().parse().unwrap();
     globals.err_ttl = matches.value_of("err_ttl").unwrap().parse().unwrap();
+    let failure_ttl = |name| match matches.value_of(name) {
+        Some(ttl) => ttl.parse().unwrap(),
+        None => globals.err_ttl,
+    };
+    globals.failure_ttls = dns::FailureTtls {
+        nxdomain: failure_ttl("nxdomain_ttl"),
+        servfail: failure_ttl("servfail_ttl"),
+        refused: failure_ttl("refused_ttl"),
+        other: globals.err_ttl,
+    };
+    globals.unreachable_ede_code = matches
+        .value_of("unreachable_ede_code")
+        .unwrap()
+        .parse()
+        .unwrap();
     globals.keepalive = !matches.is_present("disable_keepalive");
     globals.disable_post = matches.is_present("disable_post");
+    globals.padding_block_size = matches
+        .value_of("padding_block_size")
+        .unwrap()
+        .parse()
+        .unwrap();
+    globals.randomize_case = matches.is_present("randomize_case");
+    globals.refuse_any = matches.is_present("refuse_any");
+    globals.allow_all_opcodes = matches.is_present("allow_all_opcodes");
+    globals.allow_ping = matches.is_present("allow_ping");
+    if matches.is_present("enable_odoh") {
+        let odoh_key_pair = match matches.value_of("odoh_key_seed_path") {
+            Some(odoh_key_seed_path) => ODoHKeyPair::load(odoh_key_seed_path),
+            None => ODoHKeyPair::generate(),
+        };
+        globals.odoh_key_pair = Some(odoh_key_pair.unwrap());
+    }
+    globals.minimize_responses = matches.is_present("minimize_responses");
+    globals.max_response_size = matches
+        .value_of("max_response_size")
+        .unwrap()
+        .parse()
+        .unwrap();
+    globals.debug_packets = matches.is_present("debug_packets");
+    globals.max_cache_ttl = matches.value_of("max_cache_ttl").unwrap().parse().unwrap();
+    globals.ttl_jitter_pct = matches
+        .value_of("ttl_jitter_pct")
+        .unwrap()
+        .parse::<u32>()
+        .unwrap()
+        .min(100);
+    globals.cache = Cache::new(
+        matches.value_of("cache_size").unwrap().parse().unwrap(),
+        matches.value_of("serve_stale").unwrap().parse().unwrap(),
+        matches
+            .value_of("prefetch_threshold")
+            .unwrap()
+            .parse()
+            .unwrap(),
+        matches
+            .value_of("prefetch_min_hits")
+            .unwrap()
+            .parse()
+            .unwrap(),
+    );
+    globals.cache_persist_path = matches.value_of("cache_persist_path").map(PathBuf::from);
+    globals.rate_limiter = RateLimiter::new(
+        matches
+            .value_of("max_qps_per_client")
+            .unwrap()
+            .parse()
+            .unwrap(),
+    );
+    globals.client_ip_header = matches.value_of("client_ip_header").map(String::from);
+    if let Some(allowed_networks) = matches.values_of("allowed_networks") {
+        let networks: Vec<Network> = allowed_networks
+            .map(|network| network.parse().unwrap())
+            .collect();
+        globals.access_list = AccessList::new(&networks);
+    }
+    globals.append_client_subnet = matches.is_present("append_client_subnet");
+    globals.client_subnet_prefix_v4 = matches
+        .value_of("client_subnet_prefix_v4")
+        .unwrap()
+        .parse()
+        .unwrap();
+    globals.client_subnet_prefix_v6 = matches
+        .value_of("client_subnet_prefix_v6")
+        .unwrap()
+        .parse()
+        .unwrap();
+    if let Some(trusted_proxies) = matches.values_of("trusted_proxies") {
+        let networks: Vec<Network> = trusted_proxies
+            .map(|network| network.parse().unwrap())
+            .collect();
+        globals.trusted_proxies = AccessList::new(&networks);
+    }
+    if let Some(blocklist) = matches.value_of("blocklist") {
+        let nxdomain = matches.value_of("blocklist_response") == Some("nxdomain");
+        globals.blocklist = Blocklist::new(PathBuf::from(blocklist), nxdomain).unwrap();
+    }
+    if let Some(allowlist) = matches.value_of("allowlist") {
+        globals.allowlist = Blocklist::allowlist(PathBuf::from(allowlist)).unwrap();
+    }
+    globals.default_deny = matches.is_present("default_deny");
+    if matches.is_present("deny_private_answers") {
+        let allowed_names: Vec<String> = matches
+            .values_of("private_answers_allowed")
+            .map(|names| names.map(String::from).collect())
+            .unwrap_or_default();
+        globals.rebinding_protection = RebindingProtection::new(&allowed_names);
+    }
+    if let Some(poison_ips) = matches.values_of("poison_ips") {
+        let addresses: Vec<IpAddr> = poison_ips.map(|ip| ip.parse().unwrap()).collect();
+        globals.poison_filter = PoisonFilter::new(&addresses, matches.is_present("strip_poisoned"));
+    }
+    if let Some(overrides) = matches.value_of("overrides") {
+        let ttl = matches.value_of("overrides_ttl").unwrap().parse().unwrap();
+        globals.overrides = Overrides::load(overrides, ttl).unwrap();
+    }
+    if let Some(local_zone) = matches.value_of("local_zone") {
+        globals.local_zone = LocalZone::load(local_zone).unwrap();
+    }
+    globals.synthesize_aaaa = matches.is_present("synthesize_aaaa");
+    globals.dns64_prefix = parse_dns64_prefix(matches.value_of("dns64_prefix").unwrap()).unwrap();
+    globals.chaos_version = matches.value_of("chaos_version").map(String::from);
+    globals.chaos_hostname = matches.value_of("chaos_hostname").map(String::from);
+    globals.nsid = matches
+        .value_of("nsid")
+        .map(String::from)
+        .or_else(|| globals.hostname.clone());
+    globals.metrics_address = matches
+        .value_of("metrics_address")
+        .map(|address| address.parse().unwrap());
+    globals.enable_metrics =
+        matches.is_present("enable_metrics") || globals.metrics_address.is_some();
+    globals.health_check_name = matches.value_of("health_check_name").map(String::from);
+    if let Some(query_log) = matches.value_of("query_log") {
+        let format = matches
+            .value_of("query_log_format")
+            .unwrap()
+            .parse()
+            .unwrap();
+        globals.query_log = QueryLog::new(query_log, format).unwrap();
+    }
+    if matches.is_present("log_malformed_packets") {
+        let interval = Duration::from_millis(
+            matches
+                .value_of("malformed_log_interval_ms")
+                .unwrap()
+                .parse()
+                .unwrap(),
+        );
+        let hexdump_len = matches
+            .value_of("malformed_log_hexdump_len")
+            .unwrap()
+            .parse()
+            .unwrap();
+        globals.malformed_packet_log = MalformedPacketLog::new(interval, hexdump_len);
+    }
 
     #[cfg(feature = "tls")]
     {
         globals.tls_cert_path = matches.value_of("tls_cert_key_path").map(PathBuf::from);
         globals.tls_cert_key_path = matches.value_of("tls_cert_key_path").map(PathBuf::from);
+        globals.enable_dot = matches.is_present("enable_dot");
+        globals.dot_listen_address = matches
+            .value_of("dot_listen_address")
+            .unwrap()
+            .parse()
+            .unwrap();
+    }
+
+    #[cfg(feature = "http3")]
+    {
+        globals.enable_http3 = matches.is_present("enable_http3");
     }
 }
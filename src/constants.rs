@@ -1,8 +1,31 @@
 pub const LISTEN_ADDRESS: &str = "127.0.0.1:3000";
+#[cfg(feature = "tls")]
+pub const DOT_LISTEN_ADDRESS: &str = "127.0.0.1:853";
 pub const MAX_CLIENTS: usize = 512;
+pub const MAX_REQUEST_SIZE: usize = 65535;
+pub const MAX_CLIENT_PACKET_SIZE: usize = 4096;
+pub const BODY_READ_TIMEOUT_MS: u64 = 5000;
 pub const PATH: &str = "/dns-query";
 pub const SERVER_ADDRESS: &str = "9.9.9.9:53";
 pub const TIMEOUT_SEC: u64 = 10;
 pub const MAX_TTL: u32 = 86400 * 7;
 pub const MIN_TTL: u32 = 10;
 pub const ERR_TTL: u32 = 2;
+pub const CACHE_SIZE: usize = 0;
+pub const PREFETCH_MIN_HITS: u64 = 10;
+pub const MAX_CONCURRENT_STREAMS: u32 = 16;
+pub const TCP_POOL_SIZE: usize = 4;
+pub const TCP_IDLE_TIMEOUT_SEC: u64 = 10;
+pub const UPSTREAM_RETRIES: u32 = 2;
+pub const UPSTREAM_FAILURE_THRESHOLD: usize = 3;
+pub const UPSTREAM_COOLDOWN_SECS: u64 = 30;
+pub const UDP_EDNS_SIZE: u16 = 1232;
+pub const TCP_EDNS_SIZE: u16 = 4096;
+pub const OVERRIDES_TTL: u32 = 60;
+pub const DNS64_PREFIX: &str = "64:ff9b::";
+pub const CLIENT_SUBNET_PREFIX_V4: u8 = 24;
+pub const CLIENT_SUBNET_PREFIX_V6: u8 = 56;
+pub const MALFORMED_LOG_INTERVAL_MS: u64 = 1000;
+pub const MALFORMED_LOG_HEXDUMP_LEN: usize = 64;
+pub const UNREACHABLE_EDE_CODE: u16 = 22;
+pub const SHUTDOWN_GRACE_SECS: u64 = 10;
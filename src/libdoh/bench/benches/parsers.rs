@@ -0,0 +1,54 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use libdoh::dns;
+
+// Offset of the question name, right after the header
+const QUESTION_OFFSET: usize = 12;
+
+const FIXTURES: [(&str, &[u8]); 3] = [
+    (
+        "small_a_response",
+        include_bytes!("fixtures/small_a_response.bin"),
+    ),
+    ("dnssec_response", include_bytes!("fixtures/dnssec_response.bin")),
+    (
+        "many_records_response",
+        include_bytes!("fixtures/many_records_response.bin"),
+    ),
+];
+
+fn skip_name(c: &mut Criterion) {
+    let mut group = c.benchmark_group("skip_name");
+    for (name, packet) in FIXTURES.iter() {
+        group.bench_function(*name, |b| {
+            b.iter(|| dns::skip_name(black_box(packet), QUESTION_OFFSET))
+        });
+    }
+    group.finish();
+}
+
+fn min_ttl(c: &mut Criterion) {
+    let mut group = c.benchmark_group("min_ttl");
+    for (name, packet) in FIXTURES.iter() {
+        group.bench_function(*name, |b| {
+            b.iter(|| dns::min_ttl(black_box(packet), 1, 86400, 2))
+        });
+    }
+    group.finish();
+}
+
+fn set_edns_max_payload_size(c: &mut Criterion) {
+    let mut group = c.benchmark_group("set_edns_max_payload_size");
+    for (name, packet) in FIXTURES.iter() {
+        group.bench_function(*name, |b| {
+            b.iter_batched(
+                || packet.to_vec(),
+                |mut packet| dns::set_edns_max_payload_size(&mut packet, 1232),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, skip_name, min_ttl, set_edns_max_payload_size);
+criterion_main!(benches);
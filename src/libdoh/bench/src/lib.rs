@@ -0,0 +1 @@
+//! Benchmarks only; see `benches/`. Run with `cargo bench` from this directory.
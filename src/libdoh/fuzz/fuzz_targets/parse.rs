@@ -0,0 +1,24 @@
+//! Run with `cargo fuzz run parse` from the `libdoh` directory.
+
+#![no_main]
+
+use libdoh::dns;
+use libfuzzer_sys::fuzz_target;
+
+// Whatever the input, the parsers have to return an error rather than panic
+fuzz_target!(|packet: &[u8]| {
+    let _ = dns::describe(packet);
+    let _ = dns::qname(packet);
+    let _ = dns::skip_name(packet, dns::DNS_HEADER_SIZE);
+    if dns::validate_packet(packet).is_err() {
+        return;
+    }
+    // The other functions only get packets that passed validation
+    let _ = dns::answer_addresses(packet);
+    let _ = dns::min_ttl(packet, 0, u32::MAX, 0);
+    let _ = dns::extended_error(packet);
+    let _ = dns::cache_key(packet);
+    let mut packet = packet.to_vec();
+    let _ = dns::decrement_ttls(&mut packet, 1);
+    let _ = dns::clamp_ttls(&mut packet, 0);
+});
@@ -0,0 +1,198 @@
+use std::collections::{BTreeMap, HashSet};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Network {
+    address: IpAddr,
+    prefix_len: u8,
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '/');
+        let address: IpAddr = parts
+            .next()
+            .unwrap_or("")
+            .parse()
+            .map_err(|_| format!("Invalid network address: {}", s))?;
+        let max_prefix_len = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match parts.next() {
+            None => max_prefix_len,
+            Some(prefix_len) => prefix_len
+                .parse()
+                .ok()
+                .filter(|&prefix_len| prefix_len <= max_prefix_len)
+                .ok_or_else(|| format!("Invalid network prefix length: {}", s))?,
+        };
+        Ok(Network {
+            address,
+            prefix_len,
+        })
+    }
+}
+
+impl Network {
+    pub fn new(address: IpAddr, prefix_len: u8) -> Self {
+        Network {
+            address,
+            prefix_len,
+        }
+    }
+
+    #[inline]
+    pub fn address(&self) -> IpAddr {
+        self.address
+    }
+
+    #[inline]
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+}
+
+#[inline]
+fn mask_v4(address: u32, prefix_len: u8) -> u32 {
+    address & (!0u64 << (32 - prefix_len as u32)) as u32
+}
+
+#[inline]
+fn mask_v6(address: u128, prefix_len: u8) -> u128 {
+    match prefix_len {
+        0 => 0,
+        _ => address & (!0u128 << (128 - prefix_len as u32)),
+    }
+}
+
+// Networks are grouped by prefix length, so that a lookup is one hash probe per distinct length
+#[derive(Debug, Default)]
+pub struct AccessList {
+    v4: BTreeMap<u8, HashSet<u32>>,
+    v6: BTreeMap<u8, HashSet<u128>>,
+}
+
+impl AccessList {
+    pub fn new(networks: &[Network]) -> Self {
+        let mut access_list = AccessList::default();
+        for network in networks {
+            match network.address {
+                IpAddr::V4(address) => {
+                    access_list
+                        .v4
+                        .entry(network.prefix_len)
+                        .or_default()
+                        .insert(mask_v4(address.into(), network.prefix_len));
+                }
+                IpAddr::V6(address) => {
+                    access_list
+                        .v6
+                        .entry(network.prefix_len)
+                        .or_default()
+                        .insert(mask_v6(address.into(), network.prefix_len));
+                }
+            }
+        }
+        access_list
+    }
+
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        !self.v4.is_empty() || !self.v6.is_empty()
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(ip) => ip
+                .to_ipv4_mapped()
+                .map(IpAddr::V4)
+                .unwrap_or(IpAddr::V6(ip)),
+            ip => ip,
+        };
+        match ip {
+            IpAddr::V4(ip) => {
+                let ip = u32::from(ip);
+                self.v4
+                    .iter()
+                    .any(|(&prefix_len, networks)| networks.contains(&mask_v4(ip, prefix_len)))
+            }
+            IpAddr::V6(ip) => {
+                let ip = u128::from(ip);
+                self.v6
+                    .iter()
+                    .any(|(&prefix_len, networks)| networks.contains(&mask_v6(ip, prefix_len)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn access_list(networks: &[&str]) -> AccessList {
+        let networks: Vec<Network> = networks
+            .iter()
+            .map(|network| network.parse().unwrap())
+            .collect();
+        AccessList::new(&networks)
+    }
+
+    fn contains(access_list: &AccessList, ip: &str) -> bool {
+        access_list.contains(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn ipv4_network_boundaries() {
+        let access_list = access_list(&["192.0.2.0/25", "198.51.100.7"]);
+        assert!(!contains(&access_list, "192.0.1.255"));
+        assert!(contains(&access_list, "192.0.2.0"));
+        assert!(contains(&access_list, "192.0.2.127"));
+        assert!(!contains(&access_list, "192.0.2.128"));
+        assert!(contains(&access_list, "198.51.100.7"));
+        assert!(!contains(&access_list, "198.51.100.8"));
+        // IPv4-mapped IPv6 addresses are matched against IPv4 networks
+        assert!(contains(&access_list, "::ffff:192.0.2.1"));
+    }
+
+    #[test]
+    fn ipv6_network_boundaries() {
+        let access_list = access_list(&["2001:db8::/33"]);
+        assert!(!contains(
+            &access_list,
+            "2001:db7:ffff:ffff:ffff:ffff:ffff:ffff"
+        ));
+        assert!(contains(&access_list, "2001:db8::"));
+        assert!(contains(
+            &access_list,
+            "2001:db8:7fff:ffff:ffff:ffff:ffff:ffff"
+        ));
+        assert!(!contains(&access_list, "2001:db8:8000::"));
+        assert!(!contains(&access_list, "192.0.2.1"));
+    }
+
+    #[test]
+    fn zero_length_prefixes_match_everything() {
+        let access_list = access_list(&["0.0.0.0/0", "::/0"]);
+        assert!(contains(&access_list, "0.0.0.0"));
+        assert!(contains(&access_list, "255.255.255.255"));
+        assert!(contains(
+            &access_list,
+            "ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff"
+        ));
+    }
+
+    #[test]
+    fn empty_list_is_disabled() {
+        assert!(!AccessList::default().is_enabled());
+        assert!(access_list(&["192.0.2.0/24"]).is_enabled());
+    }
+
+    #[test]
+    fn invalid_networks_are_rejected() {
+        assert!("192.0.2.0/33".parse::<Network>().is_err());
+        assert!("2001:db8::/129".parse::<Network>().is_err());
+        assert!("192.0.2/24".parse::<Network>().is_err());
+    }
+}
@@ -0,0 +1,126 @@
+use crate::shutdown;
+
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+#[derive(Debug, Default)]
+struct BlocklistNames {
+    // Blocks the name itself and all its subdomains
+    domains: HashSet<String>,
+    // `*.name` entries block subdomains only
+    wildcards: HashSet<String>,
+}
+
+impl BlocklistNames {
+    fn load(path: &Path) -> io::Result<Self> {
+        let mut names = BlocklistNames::default();
+        for line in fs::read_to_string(path)?.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let name = line.trim_end_matches('.').to_ascii_lowercase();
+            match name.strip_prefix("*.") {
+                Some(parent) => names.wildcards.insert(parent.to_string()),
+                None => names.domains.insert(name),
+            };
+        }
+        Ok(names)
+    }
+}
+
+// Also used for the allowlist, which has the same format and matching rules
+#[derive(Debug, Default)]
+pub struct Blocklist {
+    path: Option<PathBuf>,
+    description: &'static str,
+    nxdomain: bool,
+    names: RwLock<BlocklistNames>,
+}
+
+impl Blocklist {
+    pub fn new(path: PathBuf, nxdomain: bool) -> io::Result<Self> {
+        let names = BlocklistNames::load(&path)?;
+        Ok(Blocklist {
+            path: Some(path),
+            description: "blocklist",
+            nxdomain,
+            names: RwLock::new(names),
+        })
+    }
+
+    pub fn allowlist(path: PathBuf) -> io::Result<Self> {
+        let names = BlocklistNames::load(&path)?;
+        Ok(Blocklist {
+            path: Some(path),
+            description: "allowlist",
+            nxdomain: false,
+            names: RwLock::new(names),
+        })
+    }
+
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.path.is_some()
+    }
+
+    #[inline]
+    pub fn nxdomain(&self) -> bool {
+        self.nxdomain
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        let names = self.names.read().unwrap();
+        if names.domains.contains(&name) {
+            return true;
+        }
+        let mut suffix = name.as_str();
+        while let Some(dot) = suffix.find('.') {
+            suffix = &suffix[dot + 1..];
+            if names.domains.contains(suffix) || names.wildcards.contains(suffix) {
+                return true;
+            }
+        }
+        false
+    }
+
+    pub(crate) async fn reload_on_signal(&self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        let mut seen_requests = 0;
+        loop {
+            shutdown::reload_requested(&mut seen_requests).await;
+            match BlocklistNames::load(path) {
+                Ok(names) => {
+                    *self.names.write().unwrap() = names;
+                    println!("The {} was reloaded", self.description);
+                }
+                Err(e) => eprintln!("Unable to reload the {}: {}", self.description, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing;
+
+    #[test]
+    fn names_match_with_their_subdomains() {
+        let allowlist = testing::allowlist("example.com # comment\n\n*.example.net\n");
+        assert!(allowlist.is_enabled());
+        assert!(allowlist.matches("example.com"));
+        assert!(allowlist.matches("www.Example.com."));
+        assert!(!allowlist.matches("notexample.com"));
+        assert!(!allowlist.matches("com"));
+        // Wildcards only match subdomains
+        assert!(!allowlist.matches("example.net"));
+        assert!(allowlist.matches("a.b.example.net"));
+    }
+}
@@ -0,0 +1,291 @@
+use crate::constants::*;
+use crate::dns;
+
+use byteorder::{BigEndian, ByteOrder};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+// Persisted entries: key length (u16), key, packet length (u16), packet, TTL when
+// saved (u32) and expiration as a UNIX timestamp (u64), all big-endian
+const PERSISTED_CACHE_MAGIC: &[u8] = b"DoHcache1";
+
+#[derive(Debug)]
+struct CacheEntry {
+    packet: Vec<u8>,
+    inserted: Instant,
+    ttl: u32,
+    tick: u64,
+    hits: u64,
+    prefetching: bool,
+}
+
+#[derive(Debug, Default)]
+struct CacheInner {
+    entries: HashMap<Vec<u8>, CacheEntry>,
+    lru: BTreeMap<u64, Vec<u8>>,
+    tick: u64,
+}
+
+#[derive(Debug)]
+pub struct CachedResponse {
+    pub packet: Vec<u8>,
+    pub ttl: u32,
+    pub prefetch: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct Cache {
+    capacity: usize,
+    serve_stale_secs: u32,
+    prefetch_threshold: u32,
+    prefetch_min_hits: u64,
+    inner: Mutex<CacheInner>,
+}
+
+impl Cache {
+    pub fn new(
+        capacity: usize,
+        serve_stale_secs: u32,
+        prefetch_threshold: u32,
+        prefetch_min_hits: u64,
+    ) -> Self {
+        Cache {
+            capacity,
+            serve_stale_secs,
+            prefetch_threshold,
+            prefetch_min_hits,
+            inner: Mutex::new(CacheInner::default()),
+        }
+    }
+
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    #[inline]
+    pub fn serves_stale(&self) -> bool {
+        self.is_enabled() && self.serve_stale_secs > 0
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<CachedResponse> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+        let CacheInner { entries, lru, .. } = &mut *inner;
+        let entry = entries.get_mut(key)?;
+        let elapsed = entry.inserted.elapsed().as_secs();
+        if elapsed >= entry.ttl as u64 + self.serve_stale_secs as u64 {
+            lru.remove(&entry.tick);
+            entries.remove(key);
+            return None;
+        }
+        if elapsed >= entry.ttl as u64 {
+            return None;
+        }
+        let elapsed = elapsed as u32;
+        let mut packet = entry.packet.clone();
+        dns::decrement_ttls(&mut packet, elapsed).ok()?;
+        let key = lru.remove(&entry.tick)?;
+        lru.insert(tick, key);
+        entry.tick = tick;
+        entry.hits += 1;
+        let ttl = entry.ttl - elapsed;
+        let prefetch = self.prefetch_threshold > 0
+            && !entry.prefetching
+            && entry.hits >= self.prefetch_min_hits
+            && (ttl as u64) * 100 < entry.ttl as u64 * self.prefetch_threshold as u64;
+        if prefetch {
+            entry.prefetching = true;
+        }
+        Some(CachedResponse {
+            packet,
+            ttl,
+            prefetch,
+        })
+    }
+
+    /// Clears the prefetching flag of an entry whose prefetch failed
+    pub fn cancel_prefetch(&self, key: &[u8]) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(entry) = inner.entries.get_mut(key) {
+            entry.prefetching = false;
+        }
+    }
+
+    pub fn get_stale(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let inner = self.inner.lock().unwrap();
+        let entry = inner.entries.get(key)?;
+        let elapsed = entry.inserted.elapsed().as_secs();
+        if elapsed >= entry.ttl as u64 + self.serve_stale_secs as u64 {
+            return None;
+        }
+        let mut packet = entry.packet.clone();
+        dns::clamp_ttls(&mut packet, STALE_RESPONSE_TTL).ok()?;
+        Some(packet)
+    }
+
+    /// Writes the unexpired entries to a file, least recently used first
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<usize> {
+        let path = path.as_ref();
+        let now = unix_time();
+        let mut data = PERSISTED_CACHE_MAGIC.to_vec();
+        let mut count = 0;
+        {
+            let inner = self.inner.lock().unwrap();
+            for key in inner.lru.values() {
+                let entry = match inner.entries.get(key) {
+                    Some(entry) => entry,
+                    None => continue,
+                };
+                let elapsed = entry.inserted.elapsed().as_secs();
+                if elapsed >= entry.ttl as u64 {
+                    continue;
+                }
+                let elapsed = elapsed as u32;
+                let mut packet = entry.packet.clone();
+                if dns::decrement_ttls(&mut packet, elapsed).is_err() {
+                    continue;
+                }
+                let ttl = entry.ttl - elapsed;
+                let mut header = [0u8; 2];
+                BigEndian::write_u16(&mut header, key.len() as u16);
+                data.extend_from_slice(&header);
+                data.extend_from_slice(key);
+                BigEndian::write_u16(&mut header, packet.len() as u16);
+                data.extend_from_slice(&header);
+                data.extend_from_slice(&packet);
+                let mut expiration = [0u8; 12];
+                BigEndian::write_u32(&mut expiration[0..], ttl);
+                BigEndian::write_u64(&mut expiration[4..], now + ttl as u64);
+                data.extend_from_slice(&expiration);
+                count += 1;
+            }
+        }
+        // The file is replaced atomically, so that an interrupted save doesn't
+        // leave a truncated file behind
+        let tmp_path = path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        Ok(count)
+    }
+
+    /// Loads entries saved by `save()`. Expired and invalid entries are skipped.
+    pub fn load<P: AsRef<Path>>(&self, path: P) -> io::Result<usize> {
+        let invalid_data = || io::Error::new(io::ErrorKind::InvalidData, "Corrupted cache file");
+        let data = fs::read(path)?;
+        if !data.starts_with(PERSISTED_CACHE_MAGIC) {
+            return Err(invalid_data());
+        }
+        let now = unix_time();
+        let mut data = &data[PERSISTED_CACHE_MAGIC.len()..];
+        let mut count = 0;
+        while !data.is_empty() {
+            let mut next = |len: usize| {
+                if data.len() < len {
+                    return Err(invalid_data());
+                }
+                let (chunk, rest) = data.split_at(len);
+                data = rest;
+                Ok(chunk)
+            };
+            let key_len = BigEndian::read_u16(next(2)?) as usize;
+            let key = next(key_len)?.to_vec();
+            let packet_len = BigEndian::read_u16(next(2)?) as usize;
+            let mut packet = next(packet_len)?.to_vec();
+            let expiration = next(12)?;
+            let saved_ttl = BigEndian::read_u32(&expiration[0..]);
+            let expires_at = BigEndian::read_u64(&expiration[4..]);
+            if expires_at <= now || dns::validate_packet(&packet).is_err() {
+                continue;
+            }
+            let ttl = (expires_at - now).min(saved_ttl as u64) as u32;
+            if dns::decrement_ttls(&mut packet, saved_ttl - ttl).is_err() {
+                continue;
+            }
+            self.insert(key, packet, ttl);
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    pub fn insert(&self, key: Vec<u8>, mut packet: Vec<u8>, ttl: u32) {
+        if !self.is_enabled() || ttl == 0 {
+            return;
+        }
+        // Queries that only differ by case share the entry; the case of each
+        // query is restored when the response is served
+        let _ = dns::lowercase_qname(&mut packet);
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+        let CacheInner { entries, lru, .. } = &mut *inner;
+        if let Some(previous) = entries.remove(&key) {
+            lru.remove(&previous.tick);
+        }
+        while entries.len() >= self.capacity {
+            let oldest_tick = match lru.keys().next() {
+                Some(&oldest_tick) => oldest_tick,
+                None => break,
+            };
+            if let Some(oldest_key) = lru.remove(&oldest_tick) {
+                entries.remove(&oldest_key);
+            }
+        }
+        lru.insert(tick, key.clone());
+        entries.insert(
+            key,
+            CacheEntry {
+                packet,
+                inserted: Instant::now(),
+                ttl,
+                tick,
+                hits: 0,
+                prefetching: false,
+            },
+        );
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response() -> Vec<u8> {
+        let query = dns::build_query("example.com", dns::DNS_TYPE_A, false, false).unwrap();
+        let mut packet = dns::response_from_query(&query, dns::DNS_RCODE_NOERROR).unwrap();
+        dns::add_answer_rr(&mut packet, dns::DNS_TYPE_A, 300, &[192, 0, 2, 1]).unwrap();
+        packet
+    }
+
+    #[test]
+    fn prefetch_is_only_requested_once() {
+        // With a 200% threshold, entries are always due for a prefetch
+        let cache = Cache::new(10, 0, 200, 0);
+        cache.insert(b"key".to_vec(), response(), 300);
+        assert!(cache.get(b"key").unwrap().prefetch);
+        assert!(!cache.get(b"key").unwrap().prefetch);
+    }
+
+    #[test]
+    fn failed_prefetch_can_be_retried() {
+        let cache = Cache::new(10, 0, 200, 0);
+        cache.insert(b"key".to_vec(), response(), 300);
+        assert!(cache.get(b"key").unwrap().prefetch);
+        cache.cancel_prefetch(b"key");
+        assert!(cache.get(b"key").unwrap().prefetch);
+    }
+}
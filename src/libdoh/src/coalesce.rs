@@ -0,0 +1,143 @@
+use crate::dns;
+use crate::errors::*;
+use crate::globals::Globals;
+use crate::metrics::Metrics;
+use crate::DoH;
+
+use futures::future::{BoxFuture, FutureExt, Shared, WeakShared};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+type QueryFuture = BoxFuture<'static, Option<Vec<u8>>>;
+type SharedQuery = Shared<QueryFuture>;
+// Query key -> (registration ID, query)
+type InflightMap = HashMap<Vec<u8>, (u64, WeakShared<QueryFuture>)>;
+
+// Entries only keep weak references: the upstream query is dropped, and thus
+// cancelled, as soon as no client is waiting for its response anymore
+#[derive(Default)]
+pub struct InflightQueries {
+    queries: Mutex<InflightMap>,
+    last_id: AtomicU64,
+}
+
+impl fmt::Debug for InflightQueries {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InflightQueries")
+            .field("queries", &self.queries.lock().unwrap().len())
+            .finish()
+    }
+}
+
+// Removes the entry once the upstream query completes, fails or is cancelled, so
+// that later queries start afresh
+struct Registration {
+    globals: Arc<Globals>,
+    key: Vec<u8>,
+    id: u64,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut queries = self.globals.inflight_upstream_queries.queries.lock().unwrap();
+        if matches!(queries.get(&self.key), Some((id, _)) if *id == self.id) {
+            queries.remove(&self.key);
+        }
+    }
+}
+
+// The shared response carries the leader's transaction ID and question case
+fn adapt_response(query: &[u8], mut packet: Vec<u8>) -> Result<Vec<u8>, DoHError> {
+    dns::question_type_offset(query).map_err(|_| DoHError::InvalidData)?;
+    dns::set_qname_case(&mut packet, query).map_err(|_| DoHError::UpstreamIssue)?;
+    dns::set_tid(&mut packet, dns::tid(query));
+    Ok(packet)
+}
+
+impl DoH {
+    fn join_inflight_query(&self, key: Vec<u8>, query: &[u8]) -> SharedQuery {
+        let globals = &self.globals;
+        let inflight_queries = &globals.inflight_upstream_queries;
+        let mut queries = inflight_queries.queries.lock().unwrap();
+        if let Some(shared_query) = queries.get(&key).and_then(|(_, weak)| weak.upgrade()) {
+            Metrics::inc(&globals.metrics.counters.coalesced_queries_total);
+            return shared_query;
+        }
+        let id = inflight_queries.last_id.fetch_add(1, Ordering::Relaxed);
+        let registration = Registration {
+            globals: globals.clone(),
+            key: key.clone(),
+            id,
+        };
+        let self_inner = self.clone();
+        let query = query.to_vec();
+        let shared_query = async move {
+            let _registration = registration;
+            self_inner.resolve_upstream(&query).await.ok()
+        }
+        .boxed()
+        .shared();
+        if let Some(weak) = shared_query.downgrade() {
+            queries.insert(key, (id, weak));
+        }
+        shared_query
+    }
+
+    // Identical queries share a single upstream query, which keeps running as long
+    // as at least one of the clients is waiting for it
+    pub(crate) async fn resolve_coalesced(&self, query: &[u8]) -> Result<Vec<u8>, DoHError> {
+        let key = match dns::cache_key(query) {
+            Ok(key) => key,
+            Err(_) => return self.resolve_upstream(query).await,
+        };
+        match self.join_inflight_query(key, query).await {
+            Some(packet) => adapt_response(query, packet),
+            None => Err(DoHError::UpstreamIssue),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn response_takes_the_query_id_and_case() {
+        let mut query = dns::build_query("example.com", dns::DNS_TYPE_A, false, false).unwrap();
+        dns::set_tid(&mut query, 0x1234);
+        let leader_query = dns::build_query("EXAMPLE.com", dns::DNS_TYPE_A, false, false).unwrap();
+        let packet = dns::response_from_query(&leader_query, dns::DNS_RCODE_NOERROR).unwrap();
+        let packet = adapt_response(&query, packet).unwrap();
+        assert_eq!(dns::tid(&packet), 0x1234);
+        assert_eq!(
+            dns::expanded_qname(&packet).unwrap(),
+            dns::qname(&query).unwrap()
+        );
+    }
+
+    #[test]
+    fn compressed_response_question_is_kept() {
+        let query = dns::build_query("www", dns::DNS_TYPE_A, false, false).unwrap();
+        let mut packet = dns::response_from_query(&query, dns::DNS_RCODE_NOERROR).unwrap();
+        // "www" followed by a pointer to the high byte of QDCOUNT, which reads as the root
+        packet.truncate(dns::DNS_OFFSET_QUESTION);
+        packet.extend_from_slice(&[3, b'W', b'W', b'W', 0xc0, 4, 0, 1, 0, 1]);
+        let adapted = adapt_response(&query, packet.clone()).unwrap();
+        assert_eq!(
+            adapted[dns::DNS_OFFSET_QUESTION..],
+            packet[dns::DNS_OFFSET_QUESTION..]
+        );
+    }
+
+    #[test]
+    fn truncated_response_is_rejected() {
+        let query = dns::build_query("www.example.com", dns::DNS_TYPE_A, false, false).unwrap();
+        let packet = query[..dns::DNS_OFFSET_QUESTION].to_vec();
+        let mut compressed = packet.clone();
+        compressed.extend_from_slice(&[3, b'w', b'w', b'w', 0xc0]);
+        assert!(adapt_response(&query, packet).is_err());
+        assert!(adapt_response(&query, compressed).is_err());
+    }
+}
@@ -1,6 +1,40 @@
 pub const DNS_QUERY_PARAM: &str = "dns";
-pub const MAX_DNS_QUESTION_LEN: usize = 512;
+pub const DNS_CONTENT_TYPE_PARAM: &str = "ct";
+pub const DNS_MESSAGE_CONTENT_TYPE: &str = "application/dns-message";
+pub const DNS_UDPWIREFORMAT_CONTENT_TYPE: &str = "application/dns-udpwireformat";
+pub const ODOH_CONTENT_TYPE: &str = "application/oblivious-dns-message";
 pub const MAX_DNS_RESPONSE_LEN: usize = 4096;
 pub const MIN_DNS_PACKET_LEN: usize = 17;
 pub const STALE_IF_ERROR_SECS: u32 = 86400;
+pub const NO_STORE_CACHE_CONTROL: &str = "max-age=0, no-store";
 pub const STALE_WHILE_REVALIDATE_SECS: u32 = 60;
+pub const STALE_RESPONSE_TTL: u32 = 30;
+pub const UPSTREAM_FAILURE_WINDOW_SECS: u64 = 60;
+pub const ADAPTIVE_EDNS_SIZE_TIMEOUTS: usize = 3;
+pub const ADAPTIVE_EDNS_SIZE_RESET_SECS: u64 = 3600;
+pub const LOCAL_PORT_BIND_ATTEMPTS: usize = 16;
+#[cfg(feature = "tls")]
+pub const TLS_CERTS_RELOAD_CHECK_SECS: u64 = 30;
+#[cfg(feature = "tls")]
+pub const DOT_HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+#[cfg(feature = "http3")]
+pub const HTTP3_ALPN: &[u8] = b"h3";
+#[cfg(feature = "http3")]
+pub const HTTP3_ALT_SVC_MAX_AGE_SECS: u64 = 86400;
+pub const HEALTH_PATH: &str = "/health";
+pub const ODOH_CONFIGS_PATH: &str = "/odoh-config";
+pub const ODOH_CONFIGS_MAX_AGE_SECS: u64 = 86400;
+pub const HEALTH_CHECK_TIMEOUT_SECS: u64 = 2;
+pub const HEALTH_CHECK_CACHE_SECS: u64 = 2;
+pub const TCP_POOL_MAX_PIPELINED_QUERIES: usize = 32;
+pub const QNAME_MINIMIZATION_MAX_STEPS: usize = 10;
+pub const SHUTDOWN_SIGNAL_POLL_MS: u64 = 100;
+pub const LISTEN_BACKLOG: i32 = 1024;
+pub const BODY_MIN_RATE_GRACE_MS: u64 = 1000;
+pub const HSTS_MAX_AGE_SECS: u64 = 31_536_000;
+pub const SERVER_TIMING_HEADER: &str = "server-timing";
+pub const RESOLV_CONF_PATH: &str = "/etc/resolv.conf";
+pub const SRV_LOOKUP_TIMEOUT_SECS: u64 = 5;
+pub const LOCAL_ZONE_DEFAULT_TTL: u32 = 3600;
+pub const LOCAL_ZONE_MAX_CNAME_CHAIN: usize = 8;
+pub const JSON_PADDING_BLOCK_SIZE: usize = 468;
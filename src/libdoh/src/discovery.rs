@@ -0,0 +1,115 @@
+use crate::constants::*;
+use crate::dns;
+use crate::globals::Globals;
+
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Where the addresses of upstream servers come from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UpstreamSource {
+    /// An IP address or a host name, with a port
+    Host(String),
+    /// The name of a SRV record, whose targets are tried by priority
+    Srv(String),
+}
+
+impl UpstreamSource {
+    fn resolve(&self) -> io::Result<Vec<SocketAddr>> {
+        match self {
+            UpstreamSource::Host(host) => Ok(host.to_socket_addrs()?.collect()),
+            UpstreamSource::Srv(name) => lookup_srv(name),
+        }
+    }
+}
+
+/// Resolves all the sources, in order. Addresses returned by several sources are only kept once.
+pub fn resolve_upstreams(sources: &[UpstreamSource]) -> io::Result<Vec<SocketAddr>> {
+    let mut addresses = vec![];
+    for source in sources {
+        for address in source.resolve()? {
+            if !addresses.contains(&address) {
+                addresses.push(address);
+            }
+        }
+    }
+    if addresses.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "No upstream server addresses found",
+        ));
+    }
+    Ok(addresses)
+}
+
+// The first name server listed in /etc/resolv.conf
+fn system_name_server() -> SocketAddr {
+    let ip = fs::read_to_string(RESOLV_CONF_PATH)
+        .ok()
+        .and_then(|resolv_conf| {
+            resolv_conf.lines().find_map(|line| {
+                let mut tokens = line.split_whitespace();
+                match tokens.next() {
+                    Some("nameserver") => tokens.next()?.parse::<IpAddr>().ok(),
+                    _ => None,
+                }
+            })
+        })
+        .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    SocketAddr::new(ip, 53)
+}
+
+// SRV records can't be looked up with the system resolver, so the query is sent
+// to the system name server directly
+fn lookup_srv(name: &str) -> io::Result<Vec<SocketAddr>> {
+    let invalid_data = |e| io::Error::new(io::ErrorKind::InvalidData, e);
+    let name_server = system_name_server();
+    let query = dns::build_query(name, dns::DNS_TYPE_SRV, false, false).map_err(invalid_data)?;
+    let local_address = match name_server {
+        SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+    };
+    let socket = UdpSocket::bind(local_address)?;
+    socket.set_read_timeout(Some(Duration::from_secs(SRV_LOOKUP_TIMEOUT_SECS)))?;
+    socket.connect(name_server)?;
+    socket.send(&query)?;
+    let mut packet = vec![0u8; MAX_DNS_RESPONSE_LEN];
+    let packet = loop {
+        let len = socket.recv(&mut packet)?;
+        if dns::question_matches(&query, &packet[..len]).unwrap_or(false) {
+            break &packet[..len];
+        }
+    };
+    let mut records = dns::srv_records(packet).map_err(invalid_data)?;
+    records.sort_by(|a, b| (a.priority, b.weight).cmp(&(b.priority, a.weight)));
+    let mut addresses = vec![];
+    for record in records {
+        let target = dns::name_to_string(&record.target);
+        let target = target.trim_end_matches('.');
+        addresses.extend((target, record.port).to_socket_addrs()?);
+    }
+    Ok(addresses)
+}
+
+// Re-resolves the upstream servers in the background. If resolution fails, the
+// last known addresses are kept.
+pub(crate) fn spawn_upstream_refresh(globals: Arc<Globals>, interval: Duration) -> io::Result<()> {
+    thread::Builder::new()
+        .name("upstream-refresh".to_string())
+        .spawn(move || loop {
+            thread::sleep(interval);
+            match resolve_upstreams(&globals.upstream_sources) {
+                Ok(addresses) => {
+                    if globals.upstreams.update(&addresses) {
+                        println!("Upstream servers updated: {:?}", addresses);
+                    }
+                }
+                Err(e) => eprintln!("Unable to resolve the upstream servers, keeping the previous addresses: {}", e),
+            }
+        })?;
+    Ok(())
+}
@@ -1,47 +1,186 @@
-use anyhow::{ensure, Error};
+use crate::errors::DnsError;
 use byteorder::{BigEndian, ByteOrder};
+use std::fmt::Write;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
 
-const DNS_HEADER_SIZE: usize = 12;
+macro_rules! ensure {
+    ($cond:expr, $err:expr $(,)?) => {
+        if !$cond {
+            return Err($err);
+        }
+    };
+}
+pub(crate) use ensure;
+
+pub const DNS_HEADER_SIZE: usize = 12;
 const DNS_MAX_HOSTNAME_SIZE: usize = 256;
-const DNS_MAX_PACKET_SIZE: usize = 4096;
-const DNS_OFFSET_QUESTION: usize = DNS_HEADER_SIZE;
-const DNS_TYPE_OPT: u16 = 41;
+pub const DNS_MAX_PACKET_SIZE: usize = 4096;
+pub(crate) const DNS_MIN_EDNS_PAYLOAD_SIZE: u16 = 512;
+const DNS_MAX_COMPRESSION_JUMPS: usize = 128;
+pub(crate) const DNS_OFFSET_QUESTION: usize = DNS_HEADER_SIZE;
+const DNS_CLASS_INET: u16 = 1;
+pub(crate) const DNS_CLASS_CHAOS: u16 = 3;
 
+pub(crate) const DNS_TYPE_A: u16 = 1;
+pub(crate) const DNS_TYPE_NS: u16 = 2;
+pub(crate) const DNS_TYPE_CNAME: u16 = 5;
+pub(crate) const DNS_TYPE_PTR: u16 = 12;
+pub(crate) const DNS_TYPE_MX: u16 = 15;
+pub(crate) const DNS_TYPE_AAAA: u16 = 28;
+const DNS_TYPE_SOA: u16 = 6;
+pub(crate) const DNS_TYPE_TXT: u16 = 16;
+pub(crate) const DNS_TYPE_SRV: u16 = 33;
+pub(crate) const DNS_TYPE_OPT: u16 = 41;
+const DNS_TYPE_RRSIG: u16 = 46;
+const DNS_TYPE_NSEC: u16 = 47;
+const DNS_TYPE_DNSKEY: u16 = 48;
+const DNS_TYPE_NSEC3: u16 = 50;
+pub(crate) const DNS_TYPE_ANY: u16 = 255;
+
+const DNS_PTYPE_NSID: u16 = 3;
+const DNS_PTYPE_CLIENT_SUBNET: u16 = 8;
+const DNS_PTYPE_COOKIE: u16 = 10;
+const DNS_PTYPE_TCP_KEEPALIVE: u16 = 11;
 const DNS_PTYPE_PADDING: u16 = 12;
+const DNS_PTYPE_EXTENDED_ERROR: u16 = 15;
+
+pub(crate) const DNS_EDE_BLOCKED: u16 = 15;
+pub(crate) const DNS_EDE_FILTERED: u16 = 17;
+pub(crate) const DNS_EDE_PROHIBITED: u16 = 18;
+
+pub(crate) const DNS_OPCODE_QUERY: u8 = 0;
+
+pub(crate) const DNS_RCODE_NOERROR: u8 = 0;
+pub(crate) const DNS_RCODE_SERVFAIL: u8 = 2;
+pub(crate) const DNS_RCODE_NXDOMAIN: u8 = 3;
+pub(crate) const DNS_RCODE_NOTIMP: u8 = 4;
+pub(crate) const DNS_RCODE_REFUSED: u8 = 5;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Flags {
+    pub qr: bool,
+    pub opcode: u8,
+    pub aa: bool,
+    pub tc: bool,
+    pub rd: bool,
+    pub ra: bool,
+    pub z: bool,
+    pub ad: bool,
+    pub cd: bool,
+    pub rcode: u8,
+}
+
+// Header accessors expect at least `DNS_HEADER_SIZE` bytes; functions returning a
+// `Result` must never panic, whatever the content of the packet is.
+#[inline]
+pub fn header_flags(packet: &[u8]) -> Flags {
+    debug_assert!(
+        packet.len() >= DNS_HEADER_SIZE,
+        "Packet shorter than a DNS header"
+    );
+    let (b2, b3) = (packet[2], packet[3]);
+    Flags {
+        qr: b2 & 0x80 != 0,
+        opcode: (b2 >> 3) & 0x0f,
+        aa: b2 & 0x04 != 0,
+        tc: b2 & 0x02 != 0,
+        rd: b2 & 0x01 != 0,
+        ra: b3 & 0x80 != 0,
+        z: b3 & 0x40 != 0,
+        ad: b3 & 0x20 != 0,
+        cd: b3 & 0x10 != 0,
+        rcode: b3 & 0x0f,
+    }
+}
+
+#[inline]
+pub fn tid(packet: &[u8]) -> u16 {
+    debug_assert!(
+        packet.len() >= DNS_HEADER_SIZE,
+        "Packet shorter than a DNS header"
+    );
+    BigEndian::read_u16(&packet[0..])
+}
+
+#[inline]
+pub fn set_tid(packet: &mut [u8], tid: u16) {
+    debug_assert!(
+        packet.len() >= DNS_HEADER_SIZE,
+        "Packet shorter than a DNS header"
+    );
+    BigEndian::write_u16(&mut packet[0..], tid);
+}
 
-const DNS_RCODE_SERVFAIL: u8 = 2;
-const DNS_RCODE_REFUSED: u8 = 5;
+#[inline]
+pub fn opcode(packet: &[u8]) -> u8 {
+    header_flags(packet).opcode
+}
 
 #[inline]
 pub fn rcode(packet: &[u8]) -> u8 {
-    packet[3] & 0x0f
+    header_flags(packet).rcode
+}
+
+#[inline]
+pub fn set_rcode(packet: &mut [u8], rcode: u8) {
+    packet[3] = (packet[3] & 0xf0) | (rcode & 0x0f);
 }
 
 #[inline]
 pub fn qdcount(packet: &[u8]) -> u16 {
+    debug_assert!(
+        packet.len() >= DNS_HEADER_SIZE,
+        "Packet shorter than a DNS header"
+    );
     BigEndian::read_u16(&packet[4..])
 }
 
 #[inline]
 pub fn ancount(packet: &[u8]) -> u16 {
+    debug_assert!(
+        packet.len() >= DNS_HEADER_SIZE,
+        "Packet shorter than a DNS header"
+    );
     BigEndian::read_u16(&packet[6..])
 }
 
 #[inline]
 pub fn arcount(packet: &[u8]) -> u16 {
+    debug_assert!(
+        packet.len() >= DNS_HEADER_SIZE,
+        "Packet shorter than a DNS header"
+    );
     BigEndian::read_u16(&packet[10..])
 }
 
-fn arcount_inc(packet: &mut [u8]) -> Result<(), Error> {
+fn arcount_inc(packet: &mut [u8]) -> Result<(), DnsError> {
     let mut arcount = arcount(packet);
-    ensure!(arcount < 0xffff, "Too many additional records");
+    ensure!(arcount < 0xffff, DnsError::TooManyAdditionalRecords);
     arcount += 1;
     BigEndian::write_u16(&mut packet[10..], arcount);
     Ok(())
 }
 
+fn arcount_dec(packet: &mut [u8]) -> Result<(), DnsError> {
+    let mut arcount = arcount(packet);
+    ensure!(arcount > 0, DnsError::NoAdditionalRecords);
+    arcount -= 1;
+    BigEndian::write_u16(&mut packet[10..], arcount);
+    Ok(())
+}
+
 #[inline]
-fn nscount(packet: &[u8]) -> u16 {
+pub fn is_truncated(packet: &[u8]) -> bool {
+    header_flags(packet).tc
+}
+
+#[inline]
+pub fn nscount(packet: &[u8]) -> u16 {
+    debug_assert!(
+        packet.len() >= DNS_HEADER_SIZE,
+        "Packet shorter than a DNS header"
+    );
     BigEndian::read_u16(&packet[8..])
 }
 
@@ -51,91 +190,710 @@ pub fn is_recoverable_error(packet: &[u8]) -> bool {
     rcode == DNS_RCODE_SERVFAIL || rcode == DNS_RCODE_REFUSED
 }
 
-fn skip_name(packet: &[u8], offset: usize) -> Result<usize, Error> {
+/// Returns the offset following a name. Compression pointers are followed, so that
+/// the whole name is checked, but they have to point backwards, and at most
+/// `DNS_MAX_COMPRESSION_JUMPS` of them are followed.
+pub fn skip_name(packet: &[u8], offset: usize) -> Result<usize, DnsError> {
     let packet_len = packet.len();
-    ensure!(offset < packet_len - 1, "Short packet");
+    ensure!(offset + 1 < packet_len, DnsError::ShortPacket);
     let mut qname_len: usize = 0;
     let mut offset = offset;
+    let mut next_offset = None;
+    let mut jumps = 0;
     loop {
         let label_len = match packet[offset] as usize {
             label_len if label_len & 0xc0 == 0xc0 => {
-                ensure!(packet_len - offset >= 2, "Incomplete offset");
-                offset += 2;
-                break;
+                ensure!(packet_len - offset >= 2, DnsError::IncompleteOffset);
+                let target = (BigEndian::read_u16(&packet[offset..]) & 0x3fff) as usize;
+                ensure!(target < offset, DnsError::ForwardCompressionPointer);
+                jumps += 1;
+                ensure!(
+                    jumps <= DNS_MAX_COMPRESSION_JUMPS,
+                    DnsError::TooManyCompressionPointers
+                );
+                if next_offset.is_none() {
+                    next_offset = Some(offset + 2);
+                }
+                offset = target;
+                continue;
             }
             label_len => label_len,
-        } as usize;
-        ensure!(label_len < 0x40, "Long label");
+        };
+        ensure!(label_len < 0x40, DnsError::LongLabel);
         ensure!(
             packet_len - offset - 1 > label_len,
-            "Malformed packet with an out-of-bounds name"
+            DnsError::OutOfBoundsName
         );
         qname_len += label_len + 1;
-        ensure!(qname_len <= DNS_MAX_HOSTNAME_SIZE, "Name too long");
+        ensure!(qname_len <= DNS_MAX_HOSTNAME_SIZE, DnsError::NameTooLong);
+        offset += label_len + 1;
+        if label_len == 0 {
+            break;
+        }
+    }
+    Ok(next_offset.unwrap_or(offset))
+}
+
+pub fn expand_name(packet: &[u8], offset: usize) -> Result<(Vec<u8>, usize), DnsError> {
+    let packet_len = packet.len();
+    ensure!(offset < packet_len, DnsError::ShortPacket);
+    let mut name = Vec::new();
+    let mut offset = offset;
+    let mut next_offset = None;
+    let mut jumps = 0;
+    loop {
+        let label_len = packet[offset] as usize;
+        if label_len & 0xc0 == 0xc0 {
+            ensure!(packet_len - offset >= 2, DnsError::IncompleteOffset);
+            let target = (BigEndian::read_u16(&packet[offset..]) & 0x3fff) as usize;
+            ensure!(target < offset, DnsError::ForwardCompressionPointer);
+            jumps += 1;
+            ensure!(
+                jumps <= DNS_MAX_COMPRESSION_JUMPS,
+                DnsError::TooManyCompressionPointers
+            );
+            if next_offset.is_none() {
+                next_offset = Some(offset + 2);
+            }
+            offset = target;
+            continue;
+        }
+        ensure!(label_len < 0x40, DnsError::LongLabel);
+        ensure!(
+            label_len == 0 || packet_len - offset - 1 > label_len,
+            DnsError::OutOfBoundsName
+        );
+        ensure!(
+            name.len() + label_len < DNS_MAX_HOSTNAME_SIZE,
+            DnsError::NameTooLong
+        );
+        name.extend_from_slice(&packet[offset..offset + label_len + 1]);
+        offset += label_len + 1;
+        if label_len == 0 {
+            break;
+        }
+    }
+    Ok((name, next_offset.unwrap_or(offset)))
+}
+
+/// Compares two names case-insensitively, after expanding them if they are compressed.
+/// Compression pointers can only point backwards and their number is bounded, so
+/// malformed names can't cause loops.
+pub fn names_match(
+    packet_a: &[u8],
+    offset_a: usize,
+    packet_b: &[u8],
+    offset_b: usize,
+) -> Result<bool, DnsError> {
+    let (name_a, _) = expand_name(packet_a, offset_a)?;
+    let (name_b, _) = expand_name(packet_b, offset_b)?;
+    Ok(name_a.eq_ignore_ascii_case(&name_b))
+}
+
+/// The question name, with compression pointers expanded
+pub fn expanded_qname(packet: &[u8]) -> Result<Vec<u8>, DnsError> {
+    question_type_offset(packet)?;
+    let (name, _) = expand_name(packet, DNS_OFFSET_QUESTION)?;
+    Ok(name)
+}
+
+/// The question name, expanded and lowercased, so that equivalent names are always
+/// matched the same way
+pub fn normalized_qname(packet: &[u8]) -> Result<Vec<u8>, DnsError> {
+    let mut name = expanded_qname(packet)?;
+    name.make_ascii_lowercase();
+    Ok(name)
+}
+
+pub fn qname(packet: &[u8]) -> Result<Vec<u8>, DnsError> {
+    let packet_len = packet.len();
+    ensure!(packet_len > DNS_OFFSET_QUESTION, DnsError::ShortPacket);
+    ensure!(packet_len <= DNS_MAX_PACKET_SIZE, DnsError::LargePacket);
+    ensure!(qdcount(packet) == 1, DnsError::NoQuestion);
+    let end = skip_name(packet, DNS_OFFSET_QUESTION)?;
+    let mut offset = DNS_OFFSET_QUESTION;
+    loop {
+        let label_len = packet[offset] as usize;
+        if label_len & 0xc0 == 0xc0 {
+            return Err(DnsError::CompressedQuestionName);
+        }
         offset += label_len + 1;
         if label_len == 0 {
             break;
         }
     }
+    assert_eq!(offset, end);
+    Ok(packet[DNS_OFFSET_QUESTION..offset].to_vec())
+}
+
+pub(crate) fn question_type_offset(packet: &[u8]) -> Result<usize, DnsError> {
+    let packet_len = packet.len();
+    ensure!(packet_len > DNS_OFFSET_QUESTION, DnsError::ShortPacket);
+    ensure!(packet_len <= DNS_MAX_PACKET_SIZE, DnsError::LargePacket);
+    ensure!(qdcount(packet) == 1, DnsError::NoQuestion);
+    let offset = skip_name(packet, DNS_OFFSET_QUESTION)?;
+    assert!(offset > DNS_OFFSET_QUESTION);
+    ensure!(packet_len - offset >= 4, DnsError::ShortPacket);
     Ok(offset)
 }
 
-fn traverse_rrs<F: FnMut(usize) -> Result<(), Error>>(
+pub fn qtype(packet: &[u8]) -> Result<u16, DnsError> {
+    let offset = question_type_offset(packet)?;
+    Ok(BigEndian::read_u16(&packet[offset..]))
+}
+
+pub fn qclass(packet: &[u8]) -> Result<u16, DnsError> {
+    let offset = question_type_offset(packet)?;
+    Ok(BigEndian::read_u16(&packet[offset + 2..]))
+}
+
+pub fn question_matches(request: &[u8], response: &[u8]) -> Result<bool, DnsError> {
+    ensure!(
+        request.len() > DNS_OFFSET_QUESTION && response.len() > DNS_OFFSET_QUESTION,
+        DnsError::ShortPacket
+    );
+    if tid(request) != tid(response) || qdcount(request) != qdcount(response) {
+        return Ok(false);
+    }
+    let request_offset = question_type_offset(request)?;
+    let response_offset = question_type_offset(response)?;
+    let names_match = names_match(request, DNS_OFFSET_QUESTION, response, DNS_OFFSET_QUESTION)?;
+    Ok(names_match && request[request_offset..][..4] == response[response_offset..][..4])
+}
+
+pub fn randomize_case(packet: &mut [u8], rng_bytes: &[u8]) -> Result<(), DnsError> {
+    let end = question_type_offset(packet)?;
+    if rng_bytes.is_empty() {
+        return Ok(());
+    }
+    let mut offset = DNS_OFFSET_QUESTION;
+    let mut bit = 0;
+    while offset < end {
+        let label_len = packet[offset] as usize;
+        if label_len == 0 || label_len & 0xc0 == 0xc0 {
+            break;
+        }
+        for c in &mut packet[offset + 1..offset + 1 + label_len] {
+            if !c.is_ascii_alphabetic() {
+                continue;
+            }
+            if rng_bytes[(bit / 8) % rng_bytes.len()] & (1 << (bit % 8)) != 0 {
+                *c ^= 0x20;
+            }
+            bit += 1;
+        }
+        offset += label_len + 1;
+    }
+    Ok(())
+}
+
+/// Lowercases the letters of the question name, leaving the length octets untouched
+pub fn lowercase_qname(packet: &mut [u8]) -> Result<(), DnsError> {
+    let end = question_type_offset(packet)?;
+    let mut offset = DNS_OFFSET_QUESTION;
+    while offset < end {
+        let label_len = packet[offset] as usize;
+        if label_len == 0 || label_len & 0xc0 == 0xc0 {
+            break;
+        }
+        packet[offset + 1..offset + 1 + label_len].make_ascii_lowercase();
+        offset += label_len + 1;
+    }
+    Ok(())
+}
+
+fn is_qname_compressed(packet: &[u8], end: usize) -> bool {
+    let mut offset = DNS_OFFSET_QUESTION;
+    while offset < end {
+        let label_len = packet[offset] as usize;
+        if label_len & 0xc0 == 0xc0 {
+            return true;
+        }
+        offset += label_len + 1;
+    }
+    false
+}
+
+/// Copies the case of the question name of `query` to the response, if both
+/// names only differ by case. The offsets of each name are computed from its own
+/// packet; compressed names are left untouched, as rewriting them would move
+/// the data following them.
+pub(crate) fn set_qname_case(packet: &mut [u8], query: &[u8]) -> Result<(), DnsError> {
+    let query_end = question_type_offset(query)?;
+    let end = question_type_offset(packet)?;
+    if is_qname_compressed(query, query_end) || is_qname_compressed(packet, end) {
+        return Ok(());
+    }
+    let qname = &query[DNS_OFFSET_QUESTION..query_end];
+    if packet[DNS_OFFSET_QUESTION..end].eq_ignore_ascii_case(qname) {
+        packet[DNS_OFFSET_QUESTION..end].copy_from_slice(qname);
+    }
+    Ok(())
+}
+
+pub fn response_from_query(query: &[u8], rcode: u8) -> Result<Vec<u8>, DnsError> {
+    let offset = question_type_offset(query)? + 4;
+    let mut packet = query[..offset].to_vec();
+    packet[2] = (packet[2] & 0x79) | 0x80;
+    packet[3] = (packet[3] & 0x10) | 0x80;
+    BigEndian::write_u16(&mut packet[6..], 0);
+    BigEndian::write_u16(&mut packet[8..], 0);
+    BigEndian::write_u16(&mut packet[10..], 0);
+    set_rcode(&mut packet, rcode);
+    Ok(packet)
+}
+
+/// Returns a copy of the query for a different type
+pub(crate) fn with_qtype(query: &[u8], qtype: u16) -> Result<Vec<u8>, DnsError> {
+    let offset = question_type_offset(query)?;
+    let mut query = query.to_vec();
+    BigEndian::write_u16(&mut query[offset..], qtype);
+    Ok(query)
+}
+
+// Encodes an owner name, replacing its longest suffix shared with the question name
+// by a compression pointer to the question; this is the inverse of `skip_name()`
+fn compress_name(packet: &[u8], name: &[u8]) -> Result<Vec<u8>, DnsError> {
+    let question_end = question_type_offset(packet)?;
+    let qname = &packet[DNS_OFFSET_QUESTION..question_end];
+    let mut offset = 0;
+    loop {
+        ensure!(offset < name.len(), DnsError::MalformedName);
+        let label_len = name[offset] as usize;
+        ensure!(label_len < 0x40, DnsError::LongLabel);
+        if label_len == 0 {
+            return Ok(name[..=offset].to_vec());
+        }
+        let suffix = &name[offset..];
+        let mut qname_offset = 0;
+        while qname_offset < qname.len() && (1..0x40).contains(&qname[qname_offset]) {
+            if qname[qname_offset..].eq_ignore_ascii_case(suffix) {
+                let pointer = 0xc000 | (DNS_OFFSET_QUESTION + qname_offset) as u16;
+                let mut compressed = name[..offset].to_vec();
+                compressed.extend_from_slice(&pointer.to_be_bytes());
+                return Ok(compressed);
+            }
+            qname_offset += qname[qname_offset] as usize + 1;
+        }
+        offset += label_len + 1;
+    }
+}
+
+pub(crate) fn add_answer_rr(
+    packet: &mut Vec<u8>,
+    rtype: u16,
+    ttl: u32,
+    rdata: &[u8],
+) -> Result<(), DnsError> {
+    let qname = question_name(packet)?;
+    add_answer_rr_with_class(packet, &qname, rtype, DNS_CLASS_INET, ttl, rdata)
+}
+
+// For records whose owner is not the name in the question, such as CNAME targets
+pub(crate) fn add_answer_rr_for_owner(
+    packet: &mut Vec<u8>,
+    owner: &[u8],
+    rtype: u16,
+    ttl: u32,
+    rdata: &[u8],
+) -> Result<(), DnsError> {
+    add_answer_rr_with_class(packet, owner, rtype, DNS_CLASS_INET, ttl, rdata)
+}
+
+// Adds a TXT record, split into character-strings of at most 255 bytes
+pub(crate) fn add_txt_answer(
+    packet: &mut Vec<u8>,
+    rclass: u16,
+    ttl: u32,
+    text: &str,
+) -> Result<(), DnsError> {
+    let mut rdata = vec![];
+    for chunk in text.as_bytes().chunks(255) {
+        rdata.push(chunk.len() as u8);
+        rdata.extend_from_slice(chunk);
+    }
+    if rdata.is_empty() {
+        rdata.push(0);
+    }
+    ensure!(rdata.len() <= 0xffff, DnsError::TxtRecordTooLarge);
+    let qname = question_name(packet)?;
+    add_answer_rr_with_class(packet, &qname, DNS_TYPE_TXT, rclass, ttl, &rdata)
+}
+
+fn question_name(packet: &[u8]) -> Result<Vec<u8>, DnsError> {
+    let question_end = question_type_offset(packet)?;
+    Ok(packet[DNS_OFFSET_QUESTION..question_end].to_vec())
+}
+
+fn add_answer_rr_with_class(
+    packet: &mut Vec<u8>,
+    owner: &[u8],
+    rtype: u16,
+    rclass: u16,
+    ttl: u32,
+    rdata: &[u8],
+) -> Result<(), DnsError> {
+    let ancount = ancount(packet);
+    ensure!(ancount < 0xffff, DnsError::TooManyAnswerRecords);
+    let answer_offset = question_type_offset(packet)? + 4;
+    let offset = traverse_rrs(packet, answer_offset, ancount as _, |_offset| Ok(()))?;
+    let mut rr = compress_name(packet, owner)?;
+    let name_len = rr.len();
+    ensure!(
+        DNS_MAX_PACKET_SIZE.saturating_sub(packet.len()) >= name_len + 10 + rdata.len(),
+        DnsError::LargePacket
+    );
+    rr.resize(name_len + 10, 0);
+    BigEndian::write_u16(&mut rr[name_len..], rtype);
+    BigEndian::write_u16(&mut rr[name_len + 2..], rclass);
+    BigEndian::write_u32(&mut rr[name_len + 4..], ttl);
+    BigEndian::write_u16(&mut rr[name_len + 8..], rdata.len() as u16);
+    rr.extend_from_slice(rdata);
+    packet.splice(offset..offset, rr);
+    BigEndian::write_u16(&mut packet[6..], ancount + 1);
+    debug_assert!(
+        traverse_rrs(packet, answer_offset, ancount as usize + 1, |_offset| Ok(())).is_ok(),
+        "Synthesized answer doesn't parse"
+    );
+    Ok(())
+}
+
+pub(crate) fn traverse_rrs<F: FnMut(usize) -> Result<(), DnsError>>(
     packet: &[u8],
     mut offset: usize,
     rrcount: usize,
     mut cb: F,
-) -> Result<usize, Error> {
+) -> Result<usize, DnsError> {
     let packet_len = packet.len();
     for _ in 0..rrcount {
         offset = skip_name(packet, offset)?;
-        ensure!(packet_len - offset >= 10, "Short packet");
+        ensure!(packet_len - offset >= 10, DnsError::ShortPacket);
         cb(offset)?;
         let rdlen = BigEndian::read_u16(&packet[offset + 8..]) as usize;
         offset += 10;
         ensure!(
             packet_len - offset >= rdlen,
-            "Record length would exceed packet length"
+            DnsError::RecordExceedsPacketLength
         );
         offset += rdlen;
     }
     Ok(offset)
 }
 
-fn traverse_rrs_mut<F: FnMut(&mut [u8], usize) -> Result<(), Error>>(
+fn traverse_rrs_mut<F: FnMut(&mut [u8], usize) -> Result<(), DnsError>>(
     packet: &mut [u8],
     mut offset: usize,
     rrcount: usize,
     mut cb: F,
-) -> Result<usize, Error> {
+) -> Result<usize, DnsError> {
     let packet_len = packet.len();
     for _ in 0..rrcount {
         offset = skip_name(packet, offset)?;
-        ensure!(packet_len - offset >= 10, "Short packet");
+        ensure!(packet_len - offset >= 10, DnsError::ShortPacket);
         cb(packet, offset)?;
         let rdlen = BigEndian::read_u16(&packet[offset + 8..]) as usize;
         offset += 10;
         ensure!(
             packet_len - offset >= rdlen,
-            "Record length would exceed packet length"
+            DnsError::RecordExceedsPacketLength
         );
         offset += rdlen;
     }
     Ok(offset)
 }
 
-pub fn min_ttl(packet: &[u8], min_ttl: u32, max_ttl: u32, failure_ttl: u32) -> Result<u32, Error> {
+fn describe_records(
+    packet: &[u8],
+    offset: usize,
+    sections: &[(&str, u16)],
+    out: &mut String,
+) -> Result<(), (usize, DnsError)> {
+    let mut rr_start = offset;
+    for &(section, rrcount) in sections {
+        if rrcount == 0 {
+            continue;
+        }
+        let _ = write!(out, "\n;; {}", section);
+        traverse_rrs(packet, rr_start, rrcount as _, |offset| {
+            let (name, _) = expand_name(packet, rr_start)?;
+            let rdlen = BigEndian::read_u16(&packet[offset + 8..]);
+            let _ = write!(
+                out,
+                "\n{} type={} class={} ttl={} rdlen={}",
+                name_to_string(&name),
+                BigEndian::read_u16(&packet[offset..]),
+                BigEndian::read_u16(&packet[offset + 2..]),
+                BigEndian::read_u32(&packet[offset + 4..]),
+                rdlen
+            );
+            rr_start = offset + 10 + rdlen as usize;
+            Ok(())
+        })
+        .map_err(|e| (rr_start, e))?;
+    }
+    if rr_start != packet.len() {
+        return Err((rr_start, DnsError::GarbageAfterPacket));
+    }
+    Ok(())
+}
+
+/// Renders the header, the question and the records of a packet for debugging.
+/// Parsing stops at the first error, which is reported along with its offset.
+pub fn describe(packet: &[u8]) -> String {
+    if packet.len() < DNS_HEADER_SIZE {
+        return format!("Short packet ({} bytes)", packet.len());
+    }
+    let flags = header_flags(packet);
+    let mut out = format!(
+        ";; id={} qr={} opcode={} aa={} tc={} rd={} ra={} z={} ad={} cd={} rcode={} qd={} an={} ns={} ar={}",
+        tid(packet),
+        flags.qr as u8,
+        flags.opcode,
+        flags.aa as u8,
+        flags.tc as u8,
+        flags.rd as u8,
+        flags.ra as u8,
+        flags.z as u8,
+        flags.ad as u8,
+        flags.cd as u8,
+        flags.rcode,
+        qdcount(packet),
+        ancount(packet),
+        nscount(packet),
+        arcount(packet)
+    );
+    let offset = match question_type_offset(packet) {
+        Ok(offset) => offset,
+        Err(e) => {
+            let _ = write!(out, "\n;; stopped at offset {}: {}", DNS_OFFSET_QUESTION, e);
+            return out;
+        }
+    };
+    let qname = expand_name(packet, DNS_OFFSET_QUESTION)
+        .map(|(qname, _)| name_to_string(&qname))
+        .unwrap_or_default();
+    let _ = write!(
+        out,
+        "\n;; Question\n{} type={} class={}",
+        qname,
+        BigEndian::read_u16(&packet[offset..]),
+        BigEndian::read_u16(&packet[offset + 2..])
+    );
+    let sections = [
+        ("Answer", ancount(packet)),
+        ("Authority", nscount(packet)),
+        ("Additional", arcount(packet)),
+    ];
+    if let Err((offset, e)) = describe_records(packet, offset + 4, &sections, &mut out) {
+        let _ = write!(out, "\n;; stopped at offset {}: {}", offset, e);
+    }
+    out
+}
+
+/// Parses the whole packet once, so that malformed or implausible packets can be
+/// rejected before any other function has to deal with them.
+pub fn validate_packet(packet: &[u8]) -> Result<(), DnsError> {
     let packet_len = packet.len();
-    ensure!(packet_len > DNS_OFFSET_QUESTION, "Short packet");
-    ensure!(packet_len <= DNS_MAX_PACKET_SIZE, "Large packet");
-    ensure!(qdcount(packet) == 1, "No question");
-    let mut offset = skip_name(packet, DNS_OFFSET_QUESTION)?;
-    assert!(offset > DNS_OFFSET_QUESTION);
-    ensure!(packet_len - offset > 4, "Short packet");
-    offset += 4;
+    let offset = question_type_offset(packet)? + 4;
+    let (ancount, nscount, arcount) = (ancount(packet), nscount(packet), arcount(packet));
+    let rrcount = ancount as usize + nscount as usize + arcount as usize;
+    // The smallest record has a root name, a type, a class, a TTL and an empty rdata
+    ensure!(
+        rrcount * (1 + 10) <= packet_len - offset,
+        DnsError::RecordCountsExceedPacketLength
+    );
+    let mut opt_count = 0;
+    let mut index = 0;
+    let mut rr_start = offset;
+    let offset = traverse_rrs(packet, offset, rrcount, |offset| {
+        if BigEndian::read_u16(&packet[offset..]) == DNS_TYPE_OPT {
+            ensure!(
+                index >= ancount as usize + nscount as usize,
+                DnsError::OptRrOutsideAdditionalSection
+            );
+            ensure!(
+                offset - rr_start == 1 && packet[rr_start] == 0,
+                DnsError::OptRrWithNonRootName
+            );
+            opt_count += 1;
+            ensure!(opt_count == 1, DnsError::DuplicateOptRr);
+        }
+        index += 1;
+        rr_start = offset + 10 + BigEndian::read_u16(&packet[offset + 8..]) as usize;
+        Ok(())
+    })?;
+    ensure!(offset == packet_len, DnsError::GarbageAfterPacket);
+    Ok(())
+}
+
+pub fn answer_addresses(packet: &[u8]) -> Result<Vec<IpAddr>, DnsError> {
+    let packet_len = packet.len();
+    let offset = question_type_offset(packet)? + 4;
+    let mut addresses = vec![];
+    traverse_rrs(packet, offset, ancount(packet) as _, |offset| {
+        let rtype = BigEndian::read_u16(&packet[offset..]);
+        let rclass = BigEndian::read_u16(&packet[offset + 2..]);
+        if rclass != DNS_CLASS_INET || (rtype != DNS_TYPE_A && rtype != DNS_TYPE_AAAA) {
+            return Ok(());
+        }
+        let rdlen = BigEndian::read_u16(&packet[offset + 8..]) as usize;
+        ensure!(
+            packet_len - offset - 10 >= rdlen,
+            DnsError::RecordExceedsPacketLength
+        );
+        let rdata = &packet[offset + 10..offset + 10 + rdlen];
+        match rtype {
+            DNS_TYPE_A => {
+                ensure!(rdlen == 4, DnsError::InvalidARecord);
+                let mut octets = [0u8; 4];
+                octets.copy_from_slice(rdata);
+                addresses.push(IpAddr::V4(Ipv4Addr::from(octets)));
+            }
+            _ => {
+                ensure!(rdlen == 16, DnsError::InvalidAaaaRecord);
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(rdata);
+                addresses.push(IpAddr::V6(Ipv6Addr::from(octets)));
+            }
+        }
+        Ok(())
+    })?;
+    Ok(addresses)
+}
+
+/// Removes the A and AAAA records of the answer section whose address matches
+pub(crate) fn remove_answer_addresses<F: Fn(&IpAddr) -> bool>(
+    packet: &mut Vec<u8>,
+    matches: F,
+) -> Result<bool, DnsError> {
+    let offset = question_type_offset(packet)? + 4;
+    let mut removed_ranges = vec![];
+    let mut rr_start = offset;
+    let mut kept_count = 0;
+    traverse_rrs(packet, offset, ancount(packet) as _, |offset| {
+        let rtype = BigEndian::read_u16(&packet[offset..]);
+        let rclass = BigEndian::read_u16(&packet[offset + 2..]);
+        let rdlen = BigEndian::read_u16(&packet[offset + 8..]) as usize;
+        let rr_end = offset + 10 + rdlen;
+        ensure!(rr_end <= packet.len(), DnsError::RecordExceedsPacketLength);
+        let rdata = &packet[offset + 10..rr_end];
+        let address = match (rclass, rtype, rdlen) {
+            (DNS_CLASS_INET, DNS_TYPE_A, 4) => {
+                let mut octets = [0u8; 4];
+                octets.copy_from_slice(rdata);
+                Some(IpAddr::V4(Ipv4Addr::from(octets)))
+            }
+            (DNS_CLASS_INET, DNS_TYPE_AAAA, 16) => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(rdata);
+                Some(IpAddr::V6(Ipv6Addr::from(octets)))
+            }
+            _ => None,
+        };
+        if address.is_some_and(|address| matches(&address)) {
+            removed_ranges.push(rr_start..rr_end);
+        } else {
+            kept_count += 1;
+        }
+        rr_start = rr_end;
+        Ok(())
+    })?;
+    if removed_ranges.is_empty() {
+        return Ok(false);
+    }
+    for range in removed_ranges.into_iter().rev() {
+        packet.drain(range);
+    }
+    BigEndian::write_u16(&mut packet[6..], kept_count);
+    Ok(true)
+}
+
+/// A SRV record from the answer section of a response
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SrvRecord {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: Vec<u8>,
+}
+
+pub fn srv_records(packet: &[u8]) -> Result<Vec<SrvRecord>, DnsError> {
+    let packet_len = packet.len();
+    let offset = question_type_offset(packet)? + 4;
+    let mut records = vec![];
+    traverse_rrs(packet, offset, ancount(packet) as _, |offset| {
+        let rtype = BigEndian::read_u16(&packet[offset..]);
+        let rclass = BigEndian::read_u16(&packet[offset + 2..]);
+        if rclass != DNS_CLASS_INET || rtype != DNS_TYPE_SRV {
+            return Ok(());
+        }
+        let rdlen = BigEndian::read_u16(&packet[offset + 8..]) as usize;
+        ensure!(
+            packet_len - offset - 10 >= rdlen,
+            DnsError::RecordExceedsPacketLength
+        );
+        ensure!(rdlen > 6, DnsError::InvalidSrvRecord);
+        let rdata = &packet[offset + 10..];
+        records.push(SrvRecord {
+            priority: BigEndian::read_u16(rdata),
+            weight: BigEndian::read_u16(&rdata[2..]),
+            port: BigEndian::read_u16(&rdata[4..]),
+            target: expand_name(packet, offset + 16)?.0,
+        });
+        Ok(())
+    })?;
+    Ok(records)
+}
+
+/// TTLs of responses without any records, by rcode
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FailureTtls {
+    pub nxdomain: u32,
+    pub servfail: u32,
+    pub refused: u32,
+    pub other: u32,
+}
+
+impl FailureTtls {
+    pub fn uniform(ttl: u32) -> Self {
+        FailureTtls {
+            nxdomain: ttl,
+            servfail: ttl,
+            refused: ttl,
+            other: ttl,
+        }
+    }
+
+    pub fn for_rcode(&self, rcode: u8) -> u32 {
+        match rcode {
+            DNS_RCODE_NXDOMAIN => self.nxdomain,
+            DNS_RCODE_SERVFAIL => self.servfail,
+            DNS_RCODE_REFUSED => self.refused,
+            _ => self.other,
+        }
+    }
+}
+
+pub fn min_ttl(packet: &[u8], min_ttl: u32, max_ttl: u32, failure_ttl: u32) -> Result<u32, DnsError> {
+    min_ttl_by_rcode(packet, min_ttl, max_ttl, &FailureTtls::uniform(failure_ttl))
+}
+
+pub fn min_ttl_by_rcode(
+    packet: &[u8],
+    min_ttl: u32,
+    max_ttl: u32,
+    failure_ttls: &FailureTtls,
+) -> Result<u32, DnsError> {
+    let packet_len = packet.len();
+    let mut offset = question_type_offset(packet)? + 4;
     let (ancount, nscount, arcount) = (ancount(packet), nscount(packet), arcount(packet));
     let rrcount = ancount as usize + nscount as usize + arcount as usize;
-    let mut found_min_ttl = if rrcount > 0 { max_ttl } else { failure_ttl };
+    let mut found_min_ttl = if rrcount > 0 {
+        max_ttl
+    } else {
+        failure_ttls.for_rcode(rcode(packet))
+    };
+    let is_negative = ancount == 0;
 
     offset = traverse_rrs(packet, offset, rrcount, |offset| {
         let qtype = BigEndian::read_u16(&packet[offset..]);
@@ -143,61 +901,186 @@ pub fn min_ttl(packet: &[u8], min_ttl: u32, max_ttl: u32, failure_ttl: u32) -> R
         if qtype != DNS_TYPE_OPT && ttl < found_min_ttl {
             found_min_ttl = ttl;
         }
+        if is_negative && qtype == DNS_TYPE_SOA {
+            let rdlen = BigEndian::read_u16(&packet[offset + 8..]) as usize;
+            ensure!(rdlen >= 22, DnsError::ShortSoaRecord);
+            ensure!(
+                packet_len - offset - 10 >= rdlen,
+                DnsError::RecordExceedsPacketLength
+            );
+            let soa_minimum = BigEndian::read_u32(&packet[offset + 10 + rdlen - 4..]);
+            if soa_minimum < found_min_ttl {
+                found_min_ttl = soa_minimum;
+            }
+        }
         Ok(())
     })?;
     if found_min_ttl < min_ttl {
         found_min_ttl = min_ttl;
     }
-    ensure!(packet_len == offset, "Garbage after packet");
+    ensure!(packet_len == offset, DnsError::GarbageAfterPacket);
     Ok(found_min_ttl)
 }
 
-fn add_edns_section(packet: &mut Vec<u8>, max_payload_size: u16) -> Result<(), Error> {
-    let opt_rr: [u8; 11] = [
-        0,
-        (DNS_TYPE_OPT >> 8) as u8,
-        DNS_TYPE_OPT as u8,
-        (max_payload_size >> 8) as u8,
-        max_payload_size as u8,
-        0,
-        0,
-        0,
-        0,
-        0,
-        0,
-    ];
-    ensure!(
-        DNS_MAX_PACKET_SIZE - packet.len() >= opt_rr.len(),
-        "Packet would be too large to add a new record"
-    );
-    arcount_inc(packet)?;
-    packet.extend(&opt_rr);
-    Ok(())
-}
-
-pub fn set_edns_max_payload_size(packet: &mut Vec<u8>, max_payload_size: u16) -> Result<(), Error> {
-    let packet_len = packet.len();
-    ensure!(packet_len > DNS_OFFSET_QUESTION, "Short packet");
-    ensure!(packet_len <= DNS_MAX_PACKET_SIZE, "Large packet");
-    ensure!(qdcount(packet) == 1, "No question");
-    let mut offset = skip_name(packet, DNS_OFFSET_QUESTION)?;
-    assert!(offset > DNS_OFFSET_QUESTION);
-    ensure!(packet_len - offset >= 4, "Short packet");
+fn update_ttls<F: FnMut(u32) -> u32>(packet: &mut [u8], mut f: F) -> Result<(), DnsError> {
+    let mut offset = question_type_offset(packet)?;
     offset += 4;
     let (ancount, nscount, arcount) = (ancount(packet), nscount(packet), arcount(packet));
-    offset = traverse_rrs(
-        packet,
-        offset,
-        ancount as usize + nscount as usize,
-        |_offset| Ok(()),
-    )?;
-    let mut edns_payload_set = false;
-    traverse_rrs_mut(packet, offset, arcount as _, |packet, offset| {
+    let rrcount = ancount as usize + nscount as usize + arcount as usize;
+    traverse_rrs_mut(packet, offset, rrcount, |packet, offset| {
         let qtype = BigEndian::read_u16(&packet[offset..]);
-        if qtype == DNS_TYPE_OPT {
-            ensure!(!edns_payload_set, "Duplicate OPT RR found");
-            BigEndian::write_u16(&mut packet[offset + 2..], max_payload_size);
-            edns_payload_set = true;
+        if qtype != DNS_TYPE_OPT {
+            let ttl = BigEndian::read_u32(&packet[offset + 4..]);
+            BigEndian::write_u32(&mut packet[offset + 4..], f(ttl));
+        }
+        Ok(())
+    })?;
+    Ok(())
+}
+
+pub fn decrement_ttls(packet: &mut [u8], elapsed_secs: u32) -> Result<(), DnsError> {
+    update_ttls(packet, |ttl| ttl.saturating_sub(elapsed_secs))
+}
+
+#[inline]
+pub fn reduce_ttl(ttl: u32, reduction: u32, min_ttl: u32) -> u32 {
+    ttl.saturating_sub(reduction).max(ttl.min(min_ttl))
+}
+
+/// Lowers the TTLs by `reduction` seconds, but not below `min_ttl`.
+/// TTLs that are already lower than `min_ttl` are left untouched.
+pub fn reduce_ttls(packet: &mut [u8], reduction: u32, min_ttl: u32) -> Result<(), DnsError> {
+    update_ttls(packet, |ttl| reduce_ttl(ttl, reduction, min_ttl))
+}
+
+/// Caps the TTL computed by `min_ttl()` for cache bookkeeping only (0 for no cap).
+/// Unlike `clamp_ttls()`, the TTLs of the records in the packet are left untouched,
+/// so clients still see the real TTLs while the cache forgets the entry sooner.
+#[inline]
+pub fn cache_ttl(ttl: u32, max_cache_ttl: u32) -> u32 {
+    match max_cache_ttl {
+        0 => ttl,
+        max_cache_ttl => ttl.min(max_cache_ttl),
+    }
+}
+
+pub fn clamp_ttls(packet: &mut [u8], max_ttl: u32) -> Result<(), DnsError> {
+    update_ttls(packet, |ttl| ttl.min(max_ttl))
+}
+
+pub fn build_query(
+    name: &str,
+    qtype: u16,
+    dnssec_ok: bool,
+    checking_disabled: bool,
+) -> Result<Vec<u8>, DnsError> {
+    let qname = encode_name(name)?;
+    let mut packet = build_query_for_qname(&qname, qtype)?;
+    if checking_disabled {
+        packet[3] |= 0x10;
+    }
+    if dnssec_ok {
+        add_edns_section(&mut packet, DNS_MAX_PACKET_SIZE as _)?;
+        let packet_len = packet.len();
+        packet[packet_len - 4] |= 0x80;
+    }
+    Ok(packet)
+}
+
+/// Converts an uncompressed name to text, escaping dots, backslashes and non-printable
+/// characters
+pub(crate) fn name_to_string(name: &[u8]) -> String {
+    let mut s = String::new();
+    let mut offset = 0;
+    while offset < name.len() {
+        let label_len = name[offset] as usize;
+        if label_len == 0 {
+            break;
+        }
+        for &c in &name[offset + 1..offset + 1 + label_len] {
+            match c {
+                b'.' | b'\\' => {
+                    s.push('\\');
+                    s.push(c as char);
+                }
+                0x21..=0x7e => s.push(c as char),
+                c => {
+                    let _ = write!(s, "\\{:03}", c);
+                }
+            }
+        }
+        s.push('.');
+        offset += label_len + 1;
+    }
+    if s.is_empty() {
+        s.push('.');
+    }
+    s
+}
+
+/// Converts a name to the uncompressed wire format
+pub(crate) fn encode_name(name: &str) -> Result<Vec<u8>, DnsError> {
+    let mut encoded = vec![];
+    let name = name.trim_end_matches('.');
+    if !name.is_empty() {
+        for label in name.split('.') {
+            ensure!(!label.is_empty(), DnsError::EmptyLabel);
+            ensure!(label.len() < 0x40, DnsError::LongLabel);
+            encoded.push(label.len() as u8);
+            encoded.extend_from_slice(label.as_bytes());
+        }
+    }
+    encoded.push(0);
+    ensure!(encoded.len() <= DNS_MAX_HOSTNAME_SIZE, DnsError::NameTooLong);
+    Ok(encoded)
+}
+
+pub(crate) fn build_query_for_qname(qname: &[u8], qtype: u16) -> Result<Vec<u8>, DnsError> {
+    ensure!(qname.len() <= DNS_MAX_HOSTNAME_SIZE, DnsError::NameTooLong);
+    let mut packet = vec![0u8; DNS_HEADER_SIZE];
+    packet[2] = 0x01;
+    BigEndian::write_u16(&mut packet[4..], 1);
+    packet.extend_from_slice(qname);
+    let mut qtype_qclass = [0u8; 4];
+    BigEndian::write_u16(&mut qtype_qclass[0..], qtype);
+    BigEndian::write_u16(&mut qtype_qclass[2..], DNS_CLASS_INET);
+    packet.extend_from_slice(&qtype_qclass);
+    Ok(packet)
+}
+
+fn add_edns_section(packet: &mut Vec<u8>, max_payload_size: u16) -> Result<(), DnsError> {
+    let opt_rr: [u8; 11] = [
+        0,
+        (DNS_TYPE_OPT >> 8) as u8,
+        DNS_TYPE_OPT as u8,
+        (max_payload_size >> 8) as u8,
+        max_payload_size as u8,
+        0,
+        0,
+        0,
+        0,
+        0,
+        0,
+    ];
+    ensure!(
+        DNS_MAX_PACKET_SIZE - packet.len() >= opt_rr.len(),
+        DnsError::NoRoomForRecord
+    );
+    arcount_inc(packet)?;
+    packet.extend(&opt_rr);
+    Ok(())
+}
+
+pub fn set_edns_max_payload_size(packet: &mut Vec<u8>, max_payload_size: u16) -> Result<(), DnsError> {
+    let offset = additional_section_offset(packet)?;
+    let arcount = arcount(packet);
+    let mut edns_payload_set = false;
+    traverse_rrs_mut(packet, offset, arcount as _, |packet, offset| {
+        let qtype = BigEndian::read_u16(&packet[offset..]);
+        if qtype == DNS_TYPE_OPT {
+            ensure!(!edns_payload_set, DnsError::DuplicateOptRr);
+            BigEndian::write_u16(&mut packet[offset + 2..], max_payload_size);
+            edns_payload_set = true;
         }
         Ok(())
     })?;
@@ -208,6 +1091,434 @@ pub fn set_edns_max_payload_size(packet: &mut Vec<u8>, max_payload_size: u16) ->
     Ok(())
 }
 
+// Checks the header and the question, then skips the answer and authority sections
+fn additional_section_offset(packet: &[u8]) -> Result<usize, DnsError> {
+    let mut offset = question_type_offset(packet)?;
+    offset += 4;
+    let (ancount, nscount) = (ancount(packet), nscount(packet));
+    offset = traverse_rrs(
+        packet,
+        offset,
+        ancount as usize + nscount as usize,
+        |_offset| Ok(()),
+    )?;
+    Ok(offset)
+}
+
+/// The payload size advertised in the OPT record of a query. Values below
+/// 512 are treated as 512 (RFC 6891).
+pub fn client_edns_size(packet: &[u8]) -> Option<u16> {
+    let edns_offset = find_edns_section(packet).ok()??;
+    let max_payload_size = BigEndian::read_u16(&packet[edns_offset + 2..]);
+    Some(max_payload_size.max(DNS_MIN_EDNS_PAYLOAD_SIZE))
+}
+
+pub(crate) fn find_edns_section(packet: &[u8]) -> Result<Option<usize>, DnsError> {
+    let offset = additional_section_offset(packet)?;
+    let mut edns_offset = None;
+    traverse_rrs(packet, offset, arcount(packet) as _, |offset| {
+        let qtype = BigEndian::read_u16(&packet[offset..]);
+        if qtype == DNS_TYPE_OPT {
+            ensure!(edns_offset.is_none(), DnsError::DuplicateOptRr);
+            edns_offset = Some(offset)
+        }
+        Ok(())
+    })?;
+    Ok(edns_offset)
+}
+
+pub(crate) fn remove_opt_rr(packet: &mut Vec<u8>) -> Result<bool, DnsError> {
+    let offset = additional_section_offset(packet)?;
+    let mut rr_start = offset;
+    let mut opt_rr_range = None;
+    traverse_rrs(packet, offset, arcount(packet) as _, |offset| {
+        let qtype = BigEndian::read_u16(&packet[offset..]);
+        let rdlen = BigEndian::read_u16(&packet[offset + 8..]) as usize;
+        let rr_end = offset + 10 + rdlen;
+        if qtype == DNS_TYPE_OPT {
+            ensure!(opt_rr_range.is_none(), DnsError::DuplicateOptRr);
+            opt_rr_range = Some(rr_start..rr_end);
+        }
+        rr_start = rr_end;
+        Ok(())
+    })?;
+    let opt_rr_range = match opt_rr_range {
+        Some(opt_rr_range) => opt_rr_range,
+        None => return Ok(false),
+    };
+    arcount_dec(packet)?;
+    packet.drain(opt_rr_range);
+    Ok(true)
+}
+
+/// Only keeps the header, the question and the OPT RR, and sets the TC bit so that
+/// the client knows that the response is incomplete.
+pub(crate) fn truncate(packet: &mut Vec<u8>) -> Result<(), DnsError> {
+    strip_additional_records(packet)?;
+    let question_end = question_type_offset(packet)? + 4;
+    let additional_offset = additional_section_offset(packet)?;
+    packet.drain(question_end..additional_offset);
+    BigEndian::write_u16(&mut packet[6..], 0);
+    BigEndian::write_u16(&mut packet[8..], 0);
+    packet[2] |= 0x02;
+    Ok(())
+}
+
+// Removes every additional record but the OPT RR
+pub(crate) fn strip_additional_records(packet: &mut Vec<u8>) -> Result<bool, DnsError> {
+    let offset = additional_section_offset(packet)?;
+    let arcount = arcount(packet);
+    let mut rr_start = offset;
+    let mut opt_rr_range = None;
+    let end = traverse_rrs(packet, offset, arcount as _, |offset| {
+        let qtype = BigEndian::read_u16(&packet[offset..]);
+        let rdlen = BigEndian::read_u16(&packet[offset + 8..]) as usize;
+        let rr_end = offset + 10 + rdlen;
+        if qtype == DNS_TYPE_OPT {
+            ensure!(opt_rr_range.is_none(), DnsError::DuplicateOptRr);
+            opt_rr_range = Some(rr_start..rr_end);
+        }
+        rr_start = rr_end;
+        Ok(())
+    })?;
+    let kept_count = opt_rr_range.is_some() as u16;
+    if arcount == kept_count {
+        return Ok(false);
+    }
+    let opt_rr = opt_rr_range.map(|opt_rr_range| packet[opt_rr_range].to_vec());
+    packet.splice(offset..end, opt_rr.unwrap_or_default());
+    BigEndian::write_u16(&mut packet[10..], kept_count);
+    Ok(true)
+}
+
+fn traverse_edns_options<F: FnMut(u16, usize, usize) -> Result<(), DnsError>>(
+    packet: &[u8],
+    edns_offset: usize,
+    mut cb: F,
+) -> Result<(), DnsError> {
+    let edns_rdlen = BigEndian::read_u16(&packet[edns_offset + 8..]) as usize;
+    let mut offset = edns_offset + 10;
+    let edns_end = offset + edns_rdlen;
+    while offset < edns_end {
+        ensure!(edns_end - offset >= 4, DnsError::TruncatedEdnsOption);
+        let option_code = BigEndian::read_u16(&packet[offset..]);
+        let option_len = BigEndian::read_u16(&packet[offset + 2..]) as usize;
+        ensure!(edns_end - offset - 4 >= option_len, DnsError::TruncatedEdnsOption);
+        cb(option_code, offset + 4, option_len)?;
+        offset += 4 + option_len;
+    }
+    Ok(())
+}
+
+// Returns the data of the first option with the given code
+fn edns_option(packet: &[u8], code: u16) -> Result<Option<Vec<u8>>, DnsError> {
+    let edns_offset = match find_edns_section(packet)? {
+        Some(edns_offset) => edns_offset,
+        None => return Ok(None),
+    };
+    let mut data = None;
+    traverse_edns_options(packet, edns_offset, |option_code, offset, option_len| {
+        if option_code == code && data.is_none() {
+            data = Some(packet[offset..offset + option_len].to_vec());
+        }
+        Ok(())
+    })?;
+    Ok(data)
+}
+
+fn remove_edns_options(packet: &mut Vec<u8>, code: u16) -> Result<bool, DnsError> {
+    let edns_offset = match find_edns_section(packet)? {
+        Some(edns_offset) => edns_offset,
+        None => return Ok(false),
+    };
+    let edns_rdlen_offset = edns_offset + 8;
+    let edns_rdlen = BigEndian::read_u16(&packet[edns_rdlen_offset..]) as usize;
+    let mut offset = edns_offset + 10;
+    let mut edns_end = offset + edns_rdlen;
+    let mut removed_len = 0;
+    while offset < edns_end {
+        ensure!(edns_end - offset >= 4, DnsError::TruncatedEdnsOption);
+        let option_code = BigEndian::read_u16(&packet[offset..]);
+        let option_len = 4 + BigEndian::read_u16(&packet[offset + 2..]) as usize;
+        ensure!(edns_end - offset >= option_len, DnsError::TruncatedEdnsOption);
+        if option_code == code {
+            packet.drain(offset..offset + option_len);
+            edns_end -= option_len;
+            removed_len += option_len;
+        } else {
+            offset += option_len;
+        }
+    }
+    if removed_len == 0 {
+        return Ok(false);
+    }
+    BigEndian::write_u16(
+        &mut packet[edns_rdlen_offset..],
+        (edns_rdlen - removed_len) as u16,
+    );
+    Ok(true)
+}
+
+pub(crate) fn has_edns_client_subnet(packet: &[u8]) -> Result<bool, DnsError> {
+    Ok(edns_option(packet, DNS_PTYPE_CLIENT_SUBNET)?.is_some())
+}
+
+pub(crate) fn strip_edns_client_subnet(packet: &mut Vec<u8>) -> Result<bool, DnsError> {
+    remove_edns_options(packet, DNS_PTYPE_CLIENT_SUBNET)
+}
+
+pub(crate) fn add_edns_client_subnet(
+    packet: &mut Vec<u8>,
+    address: IpAddr,
+    prefix_len: u8,
+) -> Result<(), DnsError> {
+    let (family, octets) = match address {
+        IpAddr::V4(address) => (1u16, address.octets().to_vec()),
+        IpAddr::V6(address) => (2u16, address.octets().to_vec()),
+    };
+    ensure!(
+        prefix_len as usize <= octets.len() * 8,
+        DnsError::InvalidSourcePrefixLength
+    );
+    // Only the bytes covered by the prefix are sent, with the remaining bits zeroed
+    let address_len = (prefix_len as usize).div_ceil(8);
+    let mut address = octets[..address_len].to_vec();
+    if !prefix_len.is_multiple_of(8) {
+        address[address_len - 1] &= 0xff << (8 - prefix_len % 8);
+    }
+    let mut data = vec![0u8; 4];
+    BigEndian::write_u16(&mut data[0..], family);
+    data[2] = prefix_len;
+    data[3] = 0;
+    data.extend_from_slice(&address);
+    add_edns_option(packet, DNS_PTYPE_CLIENT_SUBNET, &data)
+}
+
+fn ensure_edns_section(packet: &mut Vec<u8>) -> Result<usize, DnsError> {
+    match find_edns_section(packet)? {
+        Some(edns_offset) => Ok(edns_offset),
+        None => {
+            let edns_offset = packet.len() + 1;
+            add_edns_section(packet, DNS_MAX_PACKET_SIZE as _)?;
+            Ok(edns_offset)
+        }
+    }
+}
+
+fn add_edns_option(packet: &mut Vec<u8>, code: u16, data: &[u8]) -> Result<(), DnsError> {
+    ensure!(data.len() <= 0xffff - 4, DnsError::EdnsOptionTooLarge);
+    let edns_offset = ensure_edns_section(packet)?;
+    let packet_len = packet.len();
+    let mut edns_prr = vec![0u8; 4];
+    BigEndian::write_u16(&mut edns_prr[0..], code);
+    BigEndian::write_u16(&mut edns_prr[2..], data.len() as u16);
+    edns_prr.extend_from_slice(data);
+    let edns_prr_len = edns_prr.len();
+    let edns_rdlen_offset = edns_offset + 8;
+    let edns_rdlen = BigEndian::read_u16(&packet[edns_rdlen_offset..]);
+    ensure!(
+        0xffff - edns_rdlen as usize >= edns_prr_len,
+        DnsError::EdnsSectionTooLarge
+    );
+    ensure!(
+        DNS_MAX_PACKET_SIZE.saturating_sub(packet_len) >= edns_prr_len,
+        DnsError::LargePacket
+    );
+    BigEndian::write_u16(
+        &mut packet[edns_rdlen_offset..],
+        edns_rdlen + edns_prr_len as u16,
+    );
+    let edns_end = edns_offset + 10 + edns_rdlen as usize;
+    packet.splice(edns_end..edns_end, edns_prr);
+    Ok(())
+}
+
+// Adds an RFC 8914 extended error, creating the OPT record if needed
+pub(crate) fn set_extended_error(
+    packet: &mut Vec<u8>,
+    info_code: u16,
+    extra_text: &str,
+) -> Result<(), DnsError> {
+    let mut data = vec![0u8; 2];
+    BigEndian::write_u16(&mut data, info_code);
+    data.extend_from_slice(extra_text.as_bytes());
+    add_edns_option(packet, DNS_PTYPE_EXTENDED_ERROR, &data)
+}
+
+pub(crate) fn set_edns_padding(packet: &mut Vec<u8>, block_size: u16) -> Result<(), DnsError> {
+    if block_size == 0 {
+        return Ok(());
+    }
+    remove_edns_options(packet, DNS_PTYPE_PADDING)?;
+    ensure_edns_section(packet)?;
+    let block_size = block_size as usize;
+    let padding_len = (block_size - (packet.len() + 4) % block_size) % block_size;
+    add_edns_option(packet, DNS_PTYPE_PADDING, &vec![0u8; padding_len])
+}
+
+/// Adds a client cookie (RFC 7873) to a query, replacing any cookie it already had.
+pub(crate) fn set_edns_cookie(packet: &mut Vec<u8>, client_cookie: [u8; 8]) -> Result<(), DnsError> {
+    set_edns_cookies(packet, client_cookie, &[])
+}
+
+// The same as `set_edns_cookie()`, followed by the cookie the server previously returned
+pub(crate) fn set_edns_cookies(
+    packet: &mut Vec<u8>,
+    client_cookie: [u8; 8],
+    server_cookie: &[u8],
+) -> Result<(), DnsError> {
+    ensure!(
+        server_cookie.is_empty() || (8..=32).contains(&server_cookie.len()),
+        DnsError::InvalidServerCookie
+    );
+    remove_edns_options(packet, DNS_PTYPE_COOKIE)?;
+    let mut cookie = client_cookie.to_vec();
+    cookie.extend_from_slice(server_cookie);
+    add_edns_option(packet, DNS_PTYPE_COOKIE, &cookie)
+}
+
+/// Returns `true` if the query carries an NSID option (RFC 5001), which is always empty.
+pub fn requests_nsid(packet: &[u8]) -> Result<bool, DnsError> {
+    Ok(edns_option(packet, DNS_PTYPE_NSID)?.is_some())
+}
+
+// Replaces the NSID an upstream server may have sent with our own
+pub(crate) fn set_edns_nsid(packet: &mut Vec<u8>, nsid: &[u8]) -> Result<(), DnsError> {
+    remove_edns_options(packet, DNS_PTYPE_NSID)?;
+    add_edns_option(packet, DNS_PTYPE_NSID, nsid)
+}
+
+/// Asks the server to advertise its TCP idle timeout (RFC 7828). The option is only
+/// added to queries that already have an OPT record. Returns `true` if it was added.
+pub(crate) fn add_edns_tcp_keepalive(packet: &mut Vec<u8>) -> Result<bool, DnsError> {
+    if find_edns_section(packet)?.is_none() {
+        return Ok(false);
+    }
+    remove_edns_options(packet, DNS_PTYPE_TCP_KEEPALIVE)?;
+    add_edns_option(packet, DNS_PTYPE_TCP_KEEPALIVE, &[])?;
+    Ok(true)
+}
+
+/// Returns the TCP idle timeout advertised by a server, if any.
+pub fn edns_tcp_keepalive(packet: &[u8]) -> Result<Option<Duration>, DnsError> {
+    match edns_option(packet, DNS_PTYPE_TCP_KEEPALIVE)? {
+        Some(data) if data.is_empty() => Ok(None),
+        Some(data) => {
+            ensure!(data.len() == 2, DnsError::InvalidTcpKeepalive);
+            let timeout = BigEndian::read_u16(&data) as u64 * 100;
+            Ok(Some(Duration::from_millis(timeout)))
+        }
+        None => Ok(None),
+    }
+}
+
+pub(crate) fn strip_edns_tcp_keepalive(packet: &mut Vec<u8>) -> Result<bool, DnsError> {
+    remove_edns_options(packet, DNS_PTYPE_TCP_KEEPALIVE)
+}
+
+pub(crate) fn edns_cookie(packet: &[u8]) -> Result<Option<Vec<u8>>, DnsError> {
+    let edns_offset = match find_edns_section(packet)? {
+        Some(edns_offset) => edns_offset,
+        None => return Ok(None),
+    };
+    let mut cookie = None;
+    traverse_edns_options(packet, edns_offset, |option_code, offset, option_len| {
+        if option_code == DNS_PTYPE_COOKIE {
+            ensure!(cookie.is_none(), DnsError::DuplicateCookie);
+            ensure!(
+                option_len == 8 || (16..=40).contains(&option_len),
+                DnsError::InvalidCookieLength
+            );
+            cookie = Some(packet[offset..offset + option_len].to_vec());
+        }
+        Ok(())
+    })?;
+    Ok(cookie)
+}
+
+pub(crate) fn strip_edns_cookie(packet: &mut Vec<u8>) -> Result<bool, DnsError> {
+    remove_edns_options(packet, DNS_PTYPE_COOKIE)
+}
+
+pub fn extended_error(packet: &[u8]) -> Result<Option<(u16, String)>, DnsError> {
+    let edns_offset = match find_edns_section(packet)? {
+        Some(edns_offset) => edns_offset,
+        None => return Ok(None),
+    };
+    let mut extended_error = None;
+    traverse_edns_options(packet, edns_offset, |option_code, offset, option_len| {
+        if option_code == DNS_PTYPE_EXTENDED_ERROR && extended_error.is_none() {
+            ensure!(option_len >= 2, DnsError::TruncatedExtendedError);
+            let info_code = BigEndian::read_u16(&packet[offset..]);
+            let extra_text =
+                String::from_utf8_lossy(&packet[offset + 2..offset + option_len]).into_owned();
+            extended_error = Some((info_code, extra_text));
+        }
+        Ok(())
+    })?;
+    Ok(extended_error)
+}
+
+/// Returns the DO bit from the flags of the OPT record, `false` if there is no OPT record.
+pub fn dnssec_ok(packet: &[u8]) -> Result<bool, DnsError> {
+    let edns_offset = match find_edns_section(packet)? {
+        Some(edns_offset) => edns_offset,
+        None => return Ok(false),
+    };
+    Ok(BigEndian::read_u16(&packet[edns_offset + 6..]) & 0x8000 != 0)
+}
+
+pub(crate) fn strip_dnssec_records(packet: &mut Vec<u8>) -> Result<bool, DnsError> {
+    let qtype = qtype(packet)?;
+    let mut offset = question_type_offset(packet)? + 4;
+    let mut removed_ranges = vec![];
+    let mut section_counts = [(6, 0u16), (8, 0u16), (10, 0u16)];
+    for (count_offset, rrcount) in section_counts.iter_mut() {
+        *rrcount = BigEndian::read_u16(&packet[*count_offset..]);
+        let mut rr_start = offset;
+        let mut kept_count = 0;
+        offset = traverse_rrs(packet, offset, *rrcount as _, |offset| {
+            let rrtype = BigEndian::read_u16(&packet[offset..]);
+            let rdlen = BigEndian::read_u16(&packet[offset + 8..]) as usize;
+            let rr_end = offset + 10 + rdlen;
+            let is_dnssec_record = matches!(
+                rrtype,
+                DNS_TYPE_RRSIG | DNS_TYPE_NSEC | DNS_TYPE_DNSKEY | DNS_TYPE_NSEC3
+            );
+            if is_dnssec_record && rrtype != qtype && qtype != DNS_TYPE_ANY {
+                removed_ranges.push(rr_start..rr_end);
+            } else {
+                kept_count += 1;
+            }
+            rr_start = rr_end;
+            Ok(())
+        })?;
+        *rrcount = kept_count;
+    }
+    if removed_ranges.is_empty() {
+        return Ok(false);
+    }
+    for range in removed_ranges.into_iter().rev() {
+        packet.drain(range);
+    }
+    for &(count_offset, rrcount) in section_counts.iter() {
+        BigEndian::write_u16(&mut packet[count_offset..], rrcount);
+    }
+    Ok(true)
+}
+
+/// Key layout: the lowercased wire-format qname, followed by the qtype and
+/// qclass (big-endian u16 each), followed by a single byte whose bit 0 is set if
+/// the DO bit was set in the query, and bit 1 if the CD bit was set.
+pub fn cache_key(packet: &[u8]) -> Result<Vec<u8>, DnsError> {
+    let mut key = normalized_qname(packet)?;
+    let offset = question_type_offset(packet)?;
+    key.extend_from_slice(&packet[offset..offset + 4]);
+    let checking_disabled = header_flags(packet).cd;
+    key.push(dnssec_ok(packet)? as u8 | (checking_disabled as u8) << 1);
+    Ok(key)
+}
+
 fn padded_len(unpadded_len: usize) -> usize {
     const BOUNDARIES: [usize; 16] = [
         64, 128, 192, 256, 320, 384, 512, 704, 768, 896, 960, 1024, 1088, 1152, 2688, 4080,
@@ -219,27 +1530,15 @@ fn padded_len(unpadded_len: usize) -> usize {
         .unwrap_or(DNS_MAX_PACKET_SIZE)
 }
 
-pub fn add_edns_padding(packet: &mut Vec<u8>) -> Result<(), Error> {
+pub fn add_edns_padding(packet: &mut Vec<u8>) -> Result<(), DnsError> {
     let mut packet_len = packet.len();
-    ensure!(packet_len > DNS_OFFSET_QUESTION, "Short packet");
-    ensure!(packet_len <= DNS_MAX_PACKET_SIZE, "Large packet");
-    ensure!(qdcount(packet) == 1, "No question");
-    let mut offset = skip_name(packet, DNS_OFFSET_QUESTION)?;
-    assert!(offset > DNS_OFFSET_QUESTION);
-    ensure!(packet_len - offset >= 4, "Short packet");
-    offset += 4;
-    let (ancount, nscount, arcount) = (ancount(packet), nscount(packet), arcount(packet));
-    offset = traverse_rrs(
-        packet,
-        offset,
-        ancount as usize + nscount as usize,
-        |_offset| Ok(()),
-    )?;
+    let offset = additional_section_offset(packet)?;
+    let arcount = arcount(packet);
     let mut edns_offset = None;
     traverse_rrs_mut(packet, offset, arcount as _, |packet, offset| {
         let qtype = BigEndian::read_u16(&packet[offset..]);
         if qtype == DNS_TYPE_OPT {
-            ensure!(edns_offset.is_none(), "Duplicate OPT RR found");
+            ensure!(edns_offset.is_none(), DnsError::DuplicateOptRr);
             edns_offset = Some(offset)
         }
         Ok(())
@@ -259,19 +1558,19 @@ pub fn add_edns_padding(packet: &mut Vec<u8>) -> Result<(), Error> {
     BigEndian::write_u16(&mut edns_padding_prr[2..], padding_len as u16);
     let edns_padding_prr_len = edns_padding_prr.len();
     let edns_rdlen_offset: usize = edns_offset + 8;
-    ensure!(packet_len - edns_rdlen_offset >= 2, "Short packet");
+    ensure!(packet_len - edns_rdlen_offset >= 2, DnsError::ShortPacket);
     let edns_rdlen = BigEndian::read_u16(&packet[edns_rdlen_offset..]);
     ensure!(
         edns_offset + edns_rdlen as usize <= packet_len,
-        "Out of range EDNS size"
+        DnsError::OutOfRangeEdnsSize
     );
     ensure!(
         0xffff - edns_rdlen as usize >= edns_padding_prr_len,
-        "EDNS section too large for padding"
+        DnsError::EdnsSectionTooLargeForPadding
     );
     ensure!(
         DNS_MAX_PACKET_SIZE - packet_len >= edns_padding_prr_len,
-        "Large packet"
+        DnsError::LargePacket
     );
     BigEndian::write_u16(
         &mut packet[edns_rdlen_offset..],
@@ -280,3 +1579,718 @@ pub fn add_edns_padding(packet: &mut Vec<u8>) -> Result<(), Error> {
     packet.extend(&edns_padding_prr);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // "www" followed by a pointer to the high byte of QDCOUNT, which reads as the root
+    const COMPRESSED_WWW: [u8; 6] = [3, b'W', b'w', b'W', 0xc0, 4];
+
+    fn compressed_response(question: &[u8]) -> Vec<u8> {
+        let query = build_query("www", DNS_TYPE_A, false, false).unwrap();
+        let mut packet = query[..DNS_HEADER_SIZE].to_vec();
+        packet[2] |= 0x80;
+        packet.extend_from_slice(question);
+        packet
+    }
+
+    const NSID: (u16, &[u8]) = (DNS_PTYPE_NSID, b"ns1");
+    const COOKIE: (u16, &[u8]) = (DNS_PTYPE_COOKIE, &[1, 2, 3, 4, 5, 6, 7, 8]);
+    // 192.0.2.0/24
+    const CLIENT_SUBNET: (u16, &[u8]) = (DNS_PTYPE_CLIENT_SUBNET, &[0, 1, 24, 0, 192, 0, 2]);
+
+    // "ns" followed by a pointer to "example.com" in the question of `response_with_records()`
+    const COMPRESSED_NS: [u8; 5] = [2, b'n', b's', 0xc0, 16];
+
+    // Appends a record to the last section, whose count is at `count_offset`
+    fn push_rr(
+        packet: &mut Vec<u8>,
+        count_offset: usize,
+        owner: &[u8],
+        rtype: u16,
+        ttl: u32,
+        rdata: &[u8],
+    ) {
+        packet.extend_from_slice(owner);
+        packet.extend_from_slice(&rtype.to_be_bytes());
+        packet.extend_from_slice(&DNS_CLASS_INET.to_be_bytes());
+        packet.extend_from_slice(&ttl.to_be_bytes());
+        packet.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        packet.extend_from_slice(rdata);
+        let count = BigEndian::read_u16(&packet[count_offset..]);
+        BigEndian::write_u16(&mut packet[count_offset..], count + 1);
+    }
+
+    // A response for www.example.com with two A records and an NS record in the
+    // authority section, all of them with compressed owner names
+    fn response_with_records(ttls: [u32; 3]) -> Vec<u8> {
+        let query = build_query("www.example.com", DNS_TYPE_A, false, false).unwrap();
+        let mut packet = response_from_query(&query, DNS_RCODE_NOERROR).unwrap();
+        add_answer_rr(&mut packet, DNS_TYPE_A, ttls[0], &[192, 0, 2, 1]).unwrap();
+        add_answer_rr(&mut packet, DNS_TYPE_A, ttls[1], &[192, 0, 2, 2]).unwrap();
+        push_rr(
+            &mut packet,
+            8,
+            &[0xc0, 16],
+            DNS_TYPE_NS,
+            ttls[2],
+            &COMPRESSED_NS,
+        );
+        packet
+    }
+
+    // Adds an OPT record with the DO bit set, and the given options
+    fn add_opt_rr(packet: &mut Vec<u8>, options: &[(u16, &[u8])]) {
+        add_edns_section(packet, 1232).unwrap();
+        let packet_len = packet.len();
+        packet[packet_len - 4] |= 0x80;
+        for &(code, data) in options {
+            add_edns_option(packet, code, data).unwrap();
+        }
+    }
+
+    // Adds the address of ns.example.com to the additional section
+    fn add_glue_rr(packet: &mut Vec<u8>, ttl: u32) {
+        push_rr(
+            packet,
+            10,
+            &COMPRESSED_NS,
+            DNS_TYPE_A,
+            ttl,
+            &[192, 0, 2, 53],
+        );
+    }
+
+    // `response_with_records()`, followed by an OPT record and a glue record
+    fn response_with_opt(options: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut packet = response_with_records([300, 600, 3600]);
+        add_opt_rr(&mut packet, options);
+        add_glue_rr(&mut packet, 3600);
+        packet
+    }
+
+    #[test]
+    fn client_subnet_is_stripped() {
+        let mut packet = response_with_records([300, 600, 3600]);
+        add_glue_rr(&mut packet, 3600);
+        let original = packet.clone();
+        assert_eq!(strip_edns_client_subnet(&mut packet), Ok(false));
+        assert_eq!(packet, original);
+
+        let mut packet = response_with_opt(&[NSID, CLIENT_SUBNET, COOKIE, CLIENT_SUBNET]);
+        assert_eq!(strip_edns_client_subnet(&mut packet), Ok(true));
+        assert_eq!(packet, response_with_opt(&[NSID, COOKIE]));
+        assert_eq!(validate_packet(&packet), Ok(()));
+        assert_eq!(strip_edns_client_subnet(&mut packet), Ok(false));
+
+        add_edns_client_subnet(&mut packet, "192.0.2.1".parse().unwrap(), 24).unwrap();
+        assert_eq!(packet, response_with_opt(&[NSID, COOKIE, CLIENT_SUBNET]));
+        assert_eq!(strip_edns_client_subnet(&mut packet), Ok(true));
+        assert_eq!(packet, response_with_opt(&[NSID, COOKIE]));
+    }
+
+    #[test]
+    fn padding_is_added_to_block_boundaries() {
+        let mut packet = response_with_records([300, 600, 3600]);
+        let original = packet.clone();
+        set_edns_padding(&mut packet, 0).unwrap();
+        assert_eq!(packet, original);
+
+        // Without an OPT record, one is created
+        set_edns_padding(&mut packet, 128).unwrap();
+        assert_eq!(packet.len() % 128, 0);
+        assert_eq!(arcount(&packet), 1);
+        assert_eq!(validate_packet(&packet), Ok(()));
+
+        // The padding option is replaced, and the other options are kept
+        let mut packet = response_with_opt(&[NSID, COOKIE]);
+        for &block_size in &[128, 64, 468] {
+            set_edns_padding(&mut packet, block_size).unwrap();
+            assert_eq!(packet.len() % block_size as usize, 0);
+            assert_eq!(validate_packet(&packet), Ok(()));
+            assert_eq!(
+                edns_option(&packet, DNS_PTYPE_NSID),
+                Ok(Some(NSID.1.to_vec()))
+            );
+            assert_eq!(
+                edns_option(&packet, DNS_PTYPE_COOKIE),
+                Ok(Some(COOKIE.1.to_vec()))
+            );
+        }
+        assert_eq!(
+            remove_edns_options(&mut packet, DNS_PTYPE_PADDING),
+            Ok(true)
+        );
+        assert_eq!(packet, response_with_opt(&[NSID, COOKIE]));
+
+        assert_eq!(
+            set_edns_padding(&mut packet, DNS_MAX_PACKET_SIZE as u16 * 2),
+            Err(DnsError::LargePacket)
+        );
+        assert_eq!(packet, response_with_opt(&[NSID, COOKIE]));
+    }
+
+    #[test]
+    fn extended_errors_are_parsed() {
+        let packet = response_with_records([300, 600, 3600]);
+        assert_eq!(extended_error(&packet), Ok(None));
+        let packet = response_with_opt(&[NSID, COOKIE]);
+        assert_eq!(extended_error(&packet), Ok(None));
+
+        let filtered = (DNS_PTYPE_EXTENDED_ERROR, &b"\x00\x11Filtered"[..]);
+        let blocked = (DNS_PTYPE_EXTENDED_ERROR, &b"\x00\x0f"[..]);
+        let packet = response_with_opt(&[NSID, filtered, COOKIE, blocked]);
+        assert_eq!(
+            extended_error(&packet),
+            Ok(Some((DNS_EDE_FILTERED, "Filtered".to_string())))
+        );
+        let packet = response_with_opt(&[blocked, NSID]);
+        assert_eq!(
+            extended_error(&packet),
+            Ok(Some((DNS_EDE_BLOCKED, String::new())))
+        );
+
+        let invalid_utf8 = (DNS_PTYPE_EXTENDED_ERROR, &b"\x00\x12a\xffb"[..]);
+        let packet = response_with_opt(&[invalid_utf8]);
+        assert_eq!(
+            extended_error(&packet),
+            Ok(Some((DNS_EDE_PROHIBITED, "a\u{fffd}b".to_string())))
+        );
+
+        let truncated = (DNS_PTYPE_EXTENDED_ERROR, &b"\x00"[..]);
+        let packet = response_with_opt(&[COOKIE, truncated]);
+        assert_eq!(
+            extended_error(&packet),
+            Err(DnsError::TruncatedExtendedError)
+        );
+    }
+
+    // A negative response with a SOA record in the authority section, whose names
+    // are compressed
+    fn negative_response(rcode: u8, soa_ttl: u32, soa_minimum: u32) -> Vec<u8> {
+        let query = build_query("www.example.com", DNS_TYPE_A, false, false).unwrap();
+        let mut packet = response_from_query(&query, rcode).unwrap();
+        let mut rdata = COMPRESSED_NS.to_vec();
+        rdata.extend_from_slice(&[0xc0, 16]);
+        for value in &[2024010101, 7200, 3600, 1209600, soa_minimum] {
+            rdata.extend_from_slice(&u32::to_be_bytes(*value));
+        }
+        push_rr(&mut packet, 8, &[0xc0, 16], DNS_TYPE_SOA, soa_ttl, &rdata);
+        packet
+    }
+
+    #[test]
+    fn negative_ttl_is_the_soa_minimum() {
+        let packet = negative_response(DNS_RCODE_NXDOMAIN, 3600, 300);
+        assert_eq!(min_ttl(&packet, 1, 86400, 2), Ok(300));
+        assert_eq!(min_ttl(&packet, 600, 86400, 2), Ok(600));
+        let packet = negative_response(DNS_RCODE_NOERROR, 60, 300);
+        assert_eq!(min_ttl(&packet, 1, 86400, 2), Ok(60));
+
+        // The TTL field of the OPT record holds flags, and is ignored even when it's 0
+        let mut packet = negative_response(DNS_RCODE_NXDOMAIN, 3600, 300);
+        add_opt_rr(&mut packet, &[NSID, COOKIE]);
+        let edns_offset = find_edns_section(&packet).unwrap().unwrap();
+        packet[edns_offset + 6] = 0;
+        add_glue_rr(&mut packet, 3600);
+        assert_eq!(min_ttl(&packet, 1, 86400, 2), Ok(300));
+
+        // Only responses without answers are negative
+        let mut packet = negative_response(DNS_RCODE_NOERROR, 3600, 300);
+        add_answer_rr(&mut packet, DNS_TYPE_A, 600, &[192, 0, 2, 1]).unwrap();
+        assert_eq!(min_ttl(&packet, 1, 86400, 2), Ok(600));
+
+        // The SOA record is too short to have a minimum
+        let mut packet = negative_response(DNS_RCODE_NXDOMAIN, 3600, 300);
+        let rdlen_offset = packet.len() - 27 - 2;
+        BigEndian::write_u16(&mut packet[rdlen_offset..], 21);
+        packet.truncate(packet.len() - 6);
+        assert_eq!(min_ttl(&packet, 1, 86400, 2), Err(DnsError::ShortSoaRecord));
+    }
+
+    // `response_with_records()`, with an optional OPT record before a glue record
+    fn response_with_ttls(ttls: [u32; 4], options: Option<&[(u16, &[u8])]>) -> Vec<u8> {
+        let mut packet = response_with_records([ttls[0], ttls[1], ttls[2]]);
+        if let Some(options) = options {
+            add_opt_rr(&mut packet, options);
+        }
+        add_glue_rr(&mut packet, ttls[3]);
+        packet
+    }
+
+    #[test]
+    fn ttls_are_decremented() {
+        for &options in &[None, Some(&[NSID, COOKIE][..])] {
+            let mut packet = response_with_ttls([300, 600, 3600, 50], options);
+            decrement_ttls(&mut packet, 100).unwrap();
+            assert_eq!(packet, response_with_ttls([200, 500, 3500, 0], options));
+        }
+
+        let mut packet = response_with_ttls([300, 600, 3600, 50], None);
+        packet.pop();
+        assert_eq!(
+            decrement_ttls(&mut packet, 100),
+            Err(DnsError::RecordExceedsPacketLength)
+        );
+    }
+
+    #[test]
+    fn ttls_are_clamped() {
+        for &options in &[None, Some(&[NSID, COOKIE][..])] {
+            let mut packet = response_with_ttls([300, 600, 3600, 50], options);
+            clamp_ttls(&mut packet, 500).unwrap();
+            assert_eq!(packet, response_with_ttls([300, 500, 500, 50], options));
+        }
+
+        let mut packet = response_with_ttls([300, 600, 3600, 50], None);
+        packet.pop();
+        assert_eq!(
+            clamp_ttls(&mut packet, 500),
+            Err(DnsError::RecordExceedsPacketLength)
+        );
+    }
+
+    #[test]
+    fn opt_rr_is_removed() {
+        let mut packet = response_with_ttls([300, 600, 3600, 3600], None);
+        let original = packet.clone();
+        assert_eq!(remove_opt_rr(&mut packet), Ok(false));
+        assert_eq!(packet, original);
+
+        // Followed by another additional record
+        let mut packet = response_with_ttls([300, 600, 3600, 3600], Some(&[NSID, COOKIE]));
+        assert_eq!(remove_opt_rr(&mut packet), Ok(true));
+        assert_eq!(packet, original);
+        assert_eq!(validate_packet(&packet), Ok(()));
+
+        // As the last record
+        let mut packet = response_with_records([300, 600, 3600]);
+        add_opt_rr(&mut packet, &[NSID]);
+        assert_eq!(remove_opt_rr(&mut packet), Ok(true));
+        assert_eq!(packet, response_with_records([300, 600, 3600]));
+        assert_eq!(arcount(&packet), 0);
+    }
+
+    #[test]
+    fn cache_keys_only_depend_on_the_question_and_the_do_and_cd_bits() {
+        let key = |name, qtype, dnssec_ok, checking_disabled| {
+            let query = build_query(name, qtype, dnssec_ok, checking_disabled).unwrap();
+            cache_key(&query).unwrap()
+        };
+        let base_key = key("www.example.com", DNS_TYPE_A, false, false);
+        assert_eq!(&base_key[base_key.len() - 5..], &[0, 1, 0, 1, 0]);
+        assert_eq!(key("WwW.ExamPle.COM.", DNS_TYPE_A, false, false), base_key);
+        assert_ne!(
+            key("www.example.com", DNS_TYPE_AAAA, false, false),
+            base_key
+        );
+        assert_ne!(key("www.example.org", DNS_TYPE_A, false, false), base_key);
+
+        let keys = [
+            base_key,
+            key("www.example.com", DNS_TYPE_A, true, false),
+            key("www.example.com", DNS_TYPE_A, false, true),
+            key("www.example.com", DNS_TYPE_A, true, true),
+        ];
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(key[key.len() - 1], i as u8);
+        }
+
+        // The transaction ID, the EDNS payload size and the options don't matter
+        let mut query = build_query("WWW.example.com", DNS_TYPE_A, true, true).unwrap();
+        set_tid(&mut query, 0x1234);
+        set_edns_max_payload_size(&mut query, 1232).unwrap();
+        add_edns_padding(&mut query).unwrap();
+        add_edns_option(&mut query, DNS_PTYPE_COOKIE, COOKIE.1).unwrap();
+        assert_eq!(cache_key(&query).unwrap(), keys[3]);
+    }
+
+    // A response with the AD and CD bits set, an OPT record with other options, and
+    // optionally an RRSIG or NSEC record in every section
+    fn signed_response(qtype: u16, with_dnssec_records: bool) -> Vec<u8> {
+        let query = build_query("www.example.com", qtype, false, true).unwrap();
+        let mut packet = response_from_query(&query, DNS_RCODE_NOERROR).unwrap();
+        packet[3] |= 0x20;
+        let signature = [0u8; 20];
+        add_answer_rr(&mut packet, DNS_TYPE_A, 300, &[192, 0, 2, 1]).unwrap();
+        if with_dnssec_records {
+            add_answer_rr(&mut packet, DNS_TYPE_RRSIG, 300, &signature).unwrap();
+        }
+        push_rr(
+            &mut packet,
+            8,
+            &[0xc0, 16],
+            DNS_TYPE_NS,
+            3600,
+            &COMPRESSED_NS,
+        );
+        if with_dnssec_records {
+            let mut nsec = COMPRESSED_NS.to_vec();
+            nsec.extend_from_slice(&[0, 1, 0x40]);
+            push_rr(&mut packet, 8, &[0xc0, 16], DNS_TYPE_NSEC, 3600, &nsec);
+        }
+        add_opt_rr(&mut packet, &[NSID, COOKIE]);
+        add_glue_rr(&mut packet, 3600);
+        if with_dnssec_records {
+            push_rr(
+                &mut packet,
+                10,
+                &COMPRESSED_NS,
+                DNS_TYPE_RRSIG,
+                3600,
+                &signature,
+            );
+        }
+        packet
+    }
+
+    #[test]
+    fn dnssec_records_are_stripped() {
+        let mut packet = signed_response(DNS_TYPE_A, false);
+        assert_eq!(strip_dnssec_records(&mut packet), Ok(false));
+        assert_eq!(packet, signed_response(DNS_TYPE_A, false));
+
+        let mut packet = signed_response(DNS_TYPE_A, true);
+        assert_eq!(strip_dnssec_records(&mut packet), Ok(true));
+        assert_eq!(packet, signed_response(DNS_TYPE_A, false));
+        let flags = header_flags(&packet);
+        assert!(flags.ad && flags.cd);
+        assert_eq!(validate_packet(&packet), Ok(()));
+
+        // Unless they were explicitly asked for
+        let mut packet = signed_response(DNS_TYPE_ANY, true);
+        assert_eq!(strip_dnssec_records(&mut packet), Ok(false));
+        assert_eq!(packet, signed_response(DNS_TYPE_ANY, true));
+        let mut packet = signed_response(DNS_TYPE_RRSIG, true);
+        assert_eq!(strip_dnssec_records(&mut packet), Ok(true));
+        assert_eq!(
+            (ancount(&packet), nscount(&packet), arcount(&packet)),
+            (2, 1, 3)
+        );
+        assert_eq!(validate_packet(&packet), Ok(()));
+    }
+
+    #[test]
+    fn extended_errors_are_added() {
+        let mut packet = response_with_records([300, 600, 3600]);
+        set_extended_error(&mut packet, DNS_EDE_FILTERED, "Filtered").unwrap();
+        assert_eq!(arcount(&packet), 1);
+        assert_eq!(validate_packet(&packet), Ok(()));
+        assert_eq!(
+            extended_error(&packet),
+            Ok(Some((DNS_EDE_FILTERED, "Filtered".to_string())))
+        );
+
+        // The length covers the info code and the UTF-8 encoded text
+        let mut packet = response_with_opt(&[NSID, COOKIE]);
+        set_extended_error(&mut packet, DNS_EDE_BLOCKED, "Bloqué").unwrap();
+        let data = edns_option(&packet, DNS_PTYPE_EXTENDED_ERROR)
+            .unwrap()
+            .unwrap();
+        assert_eq!(data, b"\x00\x0fBloqu\xc3\xa9");
+        assert_eq!(
+            extended_error(&packet),
+            Ok(Some((DNS_EDE_BLOCKED, "Bloqué".to_string())))
+        );
+        assert_eq!(validate_packet(&packet), Ok(()));
+        assert_eq!(
+            remove_edns_options(&mut packet, DNS_PTYPE_EXTENDED_ERROR),
+            Ok(true)
+        );
+        assert_eq!(packet, response_with_opt(&[NSID, COOKIE]));
+
+        let extra_text = "x".repeat(DNS_MAX_PACKET_SIZE);
+        assert_eq!(
+            set_extended_error(&mut packet, DNS_EDE_BLOCKED, &extra_text),
+            Err(DnsError::LargePacket)
+        );
+        assert_eq!(packet, response_with_opt(&[NSID, COOKIE]));
+    }
+
+    #[test]
+    fn answer_addresses_are_parsed() {
+        let query = build_query("www.example.com", DNS_TYPE_A, false, false).unwrap();
+        let packet = response_from_query(&query, DNS_RCODE_NOERROR).unwrap();
+        assert_eq!(answer_addresses(&packet), Ok(vec![]));
+
+        let mut packet = response_with_records([300, 600, 3600]);
+        let owner = b"\x02ns\x07example\x03com\x00";
+        let ipv6 = [0x20, 1, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+        add_answer_rr(&mut packet, DNS_TYPE_CNAME, 300, &COMPRESSED_NS).unwrap();
+        add_answer_rr_for_owner(&mut packet, owner, DNS_TYPE_AAAA, 300, &ipv6).unwrap();
+        add_txt_answer(&mut packet, DNS_CLASS_CHAOS, 300, "not an address").unwrap();
+        // The glue address in the additional section is ignored
+        add_opt_rr(&mut packet, &[NSID, COOKIE]);
+        add_glue_rr(&mut packet, 3600);
+        let addresses = ["192.0.2.1", "192.0.2.2", "2001:db8::1"]
+            .iter()
+            .map(|address| address.parse().unwrap())
+            .collect();
+        assert_eq!(answer_addresses(&packet), Ok(addresses));
+
+        let mut packet = response_from_query(&query, DNS_RCODE_NOERROR).unwrap();
+        add_answer_rr(&mut packet, DNS_TYPE_A, 300, &[192, 0, 2]).unwrap();
+        assert_eq!(answer_addresses(&packet), Err(DnsError::InvalidARecord));
+        let mut packet = response_from_query(&query, DNS_RCODE_NOERROR).unwrap();
+        add_answer_rr(&mut packet, DNS_TYPE_AAAA, 300, &[192, 0, 2, 1]).unwrap();
+        assert_eq!(answer_addresses(&packet), Err(DnsError::InvalidAaaaRecord));
+    }
+
+    #[test]
+    fn cookies_are_set_and_stripped() {
+        let client_cookie = [8, 7, 6, 5, 4, 3, 2, 1];
+        let server_cookie = [9u8; 16];
+
+        // Without an OPT record
+        let mut packet = response_with_records([300, 600, 3600]);
+        assert_eq!(set_edns_cookie(&mut packet, client_cookie), Ok(()));
+        assert_eq!(edns_cookie(&packet), Ok(Some(client_cookie.to_vec())));
+        assert_eq!(arcount(&packet), 1);
+        assert_eq!(validate_packet(&packet), Ok(()));
+
+        // With an OPT record carrying other options, followed by another record
+        let original = response_with_opt(&[NSID, CLIENT_SUBNET]);
+        let mut packet = original.clone();
+        assert_eq!(
+            set_edns_cookies(&mut packet, client_cookie, &server_cookie),
+            Ok(())
+        );
+        let mut cookie = client_cookie.to_vec();
+        cookie.extend_from_slice(&server_cookie);
+        assert_eq!(edns_cookie(&packet), Ok(Some(cookie)));
+        assert_eq!(edns_option(&packet, NSID.0), Ok(Some(NSID.1.to_vec())));
+        assert_eq!(
+            edns_option(&packet, CLIENT_SUBNET.0),
+            Ok(Some(CLIENT_SUBNET.1.to_vec()))
+        );
+        assert_eq!(validate_packet(&packet), Ok(()));
+        // The previous cookie is replaced
+        assert_eq!(set_edns_cookie(&mut packet, client_cookie), Ok(()));
+        assert_eq!(edns_cookie(&packet), Ok(Some(client_cookie.to_vec())));
+        assert_eq!(strip_edns_cookie(&mut packet), Ok(true));
+        assert_eq!(packet, original);
+        assert_eq!(edns_cookie(&packet), Ok(None));
+
+        assert_eq!(
+            set_edns_cookies(&mut packet, client_cookie, &[9; 4]),
+            Err(DnsError::InvalidServerCookie)
+        );
+        let packet = response_with_opt(&[(DNS_PTYPE_COOKIE, &[1; 9])]);
+        assert_eq!(edns_cookie(&packet), Err(DnsError::InvalidCookieLength));
+        let packet = response_with_opt(&[COOKIE, COOKIE]);
+        assert_eq!(edns_cookie(&packet), Err(DnsError::DuplicateCookie));
+    }
+
+    #[test]
+    fn truncated_flag_is_detected() {
+        let mut packet = [0u8; DNS_HEADER_SIZE];
+        assert!(!is_truncated(&packet));
+        // QR and TC
+        packet[2] = 0x82;
+        assert!(is_truncated(&packet));
+        assert!(header_flags(&packet).tc);
+    }
+
+    #[test]
+    fn opcode_is_extracted_from_the_flags() {
+        let mut packet = build_query("example.com", DNS_TYPE_A, false, false).unwrap();
+        assert_eq!(opcode(&packet), DNS_OPCODE_QUERY);
+        // UPDATE, with the RD flag kept
+        packet[2] |= 5 << 3;
+        assert_eq!(opcode(&packet), 5);
+        assert!(header_flags(&packet).rd);
+    }
+
+    #[test]
+    fn compressed_question_is_expanded() {
+        let mut packet = compressed_response(&COMPRESSED_WWW);
+        packet.extend_from_slice(&[0, 1, 0, 1]);
+        assert_eq!(expanded_qname(&packet).unwrap(), b"\x03WwW\x00");
+        assert_eq!(normalized_qname(&packet).unwrap(), b"\x03www\x00");
+        assert_eq!(qtype(&packet).unwrap(), DNS_TYPE_A);
+        assert!(matches!(
+            qname(&packet),
+            Err(DnsError::CompressedQuestionName)
+        ));
+    }
+
+    // A root name followed by `count` pointers, each one to the previous one
+    fn pointer_chain(count: usize) -> Vec<u8> {
+        let mut packet = vec![0; DNS_HEADER_SIZE + 1];
+        for i in 0..count {
+            let target = if i == 0 {
+                DNS_HEADER_SIZE
+            } else {
+                packet.len() - 2
+            };
+            packet.extend_from_slice(&(0xc000 | target as u16).to_be_bytes());
+        }
+        packet.extend_from_slice(&[0, 1, 0, 1]);
+        packet
+    }
+
+    #[test]
+    fn pointer_to_itself_is_rejected() {
+        let packet = [0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0xc0, 12, 0, 1, 0, 1];
+        for result in [
+            skip_name(&packet, DNS_OFFSET_QUESTION).map(|_| ()),
+            expand_name(&packet, DNS_OFFSET_QUESTION).map(|_| ()),
+        ] {
+            assert_eq!(result, Err(DnsError::ForwardCompressionPointer));
+        }
+    }
+
+    #[test]
+    fn forward_pointer_is_rejected() {
+        let packet = [0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0xc0, 14, 0, 0, 1, 0, 1];
+        for result in [
+            skip_name(&packet, DNS_OFFSET_QUESTION).map(|_| ()),
+            expand_name(&packet, DNS_OFFSET_QUESTION).map(|_| ()),
+        ] {
+            assert_eq!(result, Err(DnsError::ForwardCompressionPointer));
+        }
+    }
+
+    #[test]
+    fn pointer_chains_are_bounded() {
+        let packet = pointer_chain(DNS_MAX_COMPRESSION_JUMPS);
+        let last = packet.len() - 6;
+        assert_eq!(skip_name(&packet, last), Ok(last + 2));
+        assert_eq!(expand_name(&packet, last), Ok((vec![0], last + 2)));
+
+        let packet = pointer_chain(DNS_MAX_COMPRESSION_JUMPS + 1);
+        let last = packet.len() - 6;
+        for result in [
+            skip_name(&packet, last).map(|_| ()),
+            expand_name(&packet, last).map(|_| ()),
+        ] {
+            assert_eq!(result, Err(DnsError::TooManyCompressionPointers));
+        }
+    }
+
+    #[test]
+    fn pointer_targets_are_checked() {
+        // The pointer is valid, but the label it points to exceeds the packet
+        let packet = [
+            0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0x3f, 0xc0, 12, 0, 1, 0, 1,
+        ];
+        assert_eq!(skip_name(&packet, 13), Err(DnsError::OutOfBoundsName));
+    }
+
+    #[test]
+    fn qname_case_is_copied_to_uncompressed_questions() {
+        let query = build_query("www", DNS_TYPE_A, false, false).unwrap();
+        let mut packet = build_query("WWW", DNS_TYPE_A, false, false).unwrap();
+        set_qname_case(&mut packet, &query).unwrap();
+        assert_eq!(packet, query);
+    }
+
+    #[test]
+    fn compressed_question_is_left_untouched() {
+        let query = build_query("www", DNS_TYPE_A, false, false).unwrap();
+        let mut packet = compressed_response(&COMPRESSED_WWW);
+        packet.extend_from_slice(&[0, 1, 0, 1]);
+        packet.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 0, 2, 1]);
+        let original = packet.clone();
+        set_qname_case(&mut packet, &query).unwrap();
+        assert_eq!(packet, original);
+        assert!(names_match(&packet, DNS_OFFSET_QUESTION, &query, DNS_OFFSET_QUESTION).unwrap());
+    }
+
+    #[test]
+    fn truncated_compressed_question_is_rejected() {
+        let query = build_query("www", DNS_TYPE_A, false, false).unwrap();
+        for len in 0..COMPRESSED_WWW.len() {
+            let mut packet = compressed_response(&COMPRESSED_WWW[..len]);
+            assert!(expanded_qname(&packet).is_err());
+            assert!(set_qname_case(&mut packet, &query).is_err());
+        }
+        // The name is complete, but the type and class are missing
+        let mut packet = compressed_response(&COMPRESSED_WWW);
+        assert!(expanded_qname(&packet).is_err());
+        assert!(set_qname_case(&mut packet, &query).is_err());
+    }
+
+    #[test]
+    fn edns_functions_check_the_question() {
+        let query = build_query("example.com", DNS_TYPE_A, false, false).unwrap();
+        let mut packet = query.clone();
+        set_edns_max_payload_size(&mut packet, 1232).unwrap();
+        set_edns_max_payload_size(&mut packet, 1400).unwrap();
+        assert_eq!(arcount(&packet), 1);
+        let unpadded_len = packet.len();
+        add_edns_padding(&mut packet).unwrap();
+        assert_eq!(packet.len(), padded_len(unpadded_len) + 4);
+        assert_eq!(min_ttl(&packet, 0, 3600, 60), Ok(3600));
+
+        // The class of the question is missing
+        let mut packet = query[..query.len() - 2].to_vec();
+        assert_eq!(
+            set_edns_max_payload_size(&mut packet, 1232),
+            Err(DnsError::ShortPacket)
+        );
+        assert_eq!(add_edns_padding(&mut packet), Err(DnsError::ShortPacket));
+        assert_eq!(min_ttl(&packet, 0, 3600, 60), Err(DnsError::ShortPacket));
+    }
+
+    // xorshift64, so that a failing input can be reproduced
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    fn parse_everything(packet: &[u8]) {
+        let _ = validate_packet(packet);
+        let _ = min_ttl(packet, 0, u32::MAX, 60);
+        let _ = qname(packet);
+        let _ = qtype(packet);
+        for offset in 0..packet.len() + 1 {
+            let _ = expand_name(packet, offset);
+        }
+        let _ = answer_addresses(packet);
+        let _ = set_edns_max_payload_size(&mut packet.to_vec(), 1232);
+        let _ = add_edns_padding(&mut packet.to_vec());
+        let _ = remove_answer_addresses(&mut packet.to_vec(), |_| true);
+    }
+
+    #[test]
+    fn parsers_dont_panic_on_random_input() {
+        let query = build_query("www.example.com", DNS_TYPE_A, false, false).unwrap();
+        let mut response = response_from_query(&query, DNS_RCODE_NOERROR).unwrap();
+        add_answer_rr(&mut response, DNS_TYPE_A, 300, &[192, 0, 2, 1]).unwrap();
+        let ipv6 = [0x20, 1, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+        add_answer_rr(&mut response, DNS_TYPE_AAAA, 300, &ipv6).unwrap();
+        set_edns_max_payload_size(&mut response, 1232).unwrap();
+
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..20_000 {
+            // Random bytes, usually with a single question so that parsing goes further
+            let len = rng.below(128);
+            let mut packet: Vec<u8> = (0..len).map(|_| rng.next() as u8).collect();
+            if len >= DNS_HEADER_SIZE && rng.next() & 3 != 0 {
+                BigEndian::write_u16(&mut packet[4..], 1);
+            }
+            parse_everything(&packet);
+
+            // A valid response with a few random bytes, possibly truncated
+            let mut packet = response.clone();
+            for _ in 0..1 + rng.below(4) {
+                let offset = rng.below(packet.len());
+                packet[offset] = rng.next() as u8;
+            }
+            packet.truncate(rng.below(packet.len() + 1));
+            parse_everything(&packet);
+        }
+    }
+}
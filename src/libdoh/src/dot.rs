@@ -0,0 +1,130 @@
+use crate::constants::*;
+use crate::dns;
+use crate::errors::*;
+use crate::malformed::PacketSource;
+use crate::metrics::Metrics;
+use crate::DoH;
+
+use byteorder::{BigEndian, ByteOrder};
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::stream::StreamExt;
+use tokio_rustls::TlsAcceptor;
+
+// DNS over TLS (RFC 7858): the same DNS messages as DoH, prefixed with their
+// length like DNS over TCP
+impl DoH {
+    // Returns `None` if the connection has to be closed
+    async fn dot_response(&self, query: Vec<u8>, client_ip: Option<IpAddr>) -> Option<Vec<u8>> {
+        let globals = &self.globals;
+        Metrics::inc(&globals.metrics.counters.queries_total);
+        if let Err(err) = dns::validate_packet(&query) {
+            globals.metrics.observe_invalid_query(err);
+            globals
+                .malformed_packet_log
+                .log(PacketSource::Client(client_ip), err, &query);
+            return None;
+        }
+        if dns::qdcount(&query) != 1 {
+            return None;
+        }
+        if let Some(client_ip) = client_ip {
+            if globals.rate_limiter.check(client_ip).is_err() {
+                return dns::response_from_query(&query, dns::DNS_RCODE_REFUSED).ok();
+            }
+        }
+        let client_subnet = self.derived_client_subnet(&query, client_ip);
+        let mut response = self.resolve(query, client_ip, client_subnet).await.ok()?;
+        self.pad_response(&mut response);
+        Some(response.packet)
+    }
+
+    // Queries are answered in order; the connection is closed once it has been idle
+    // for the duration of the query timeout
+    async fn serve_dot_connection<S>(self, mut stream: S, client_ip: Option<IpAddr>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let globals = self.globals.clone();
+        loop {
+            let mut binlen = [0u8; 2];
+            match tokio::time::timeout(globals.timeout, stream.read_exact(&mut binlen)).await {
+                Ok(Ok(_)) => {}
+                _ => return,
+            }
+            let len = BigEndian::read_u16(&binlen) as usize;
+            if len < dns::DNS_HEADER_SIZE || len > globals.max_client_packet_size {
+                return;
+            }
+            let mut query = vec![0u8; len];
+            match tokio::time::timeout(globals.body_read_timeout, stream.read_exact(&mut query))
+                .await
+            {
+                Ok(Ok(_)) => {}
+                _ => return,
+            }
+            let packet = match self.dot_response(query, client_ip).await {
+                Some(packet) => packet,
+                None => return,
+            };
+            let mut frame = Vec::with_capacity(2 + packet.len());
+            frame.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+            frame.extend_from_slice(&packet);
+            if stream.write_all(&frame).await.is_err() || stream.flush().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    pub(crate) async fn start_dot(
+        self,
+        tls_acceptor: TlsAcceptor,
+        mut listener: TcpListener,
+    ) -> Result<(), DoHError> {
+        let globals = self.globals.clone();
+        let tls_acceptor = Arc::new(RwLock::new(tls_acceptor));
+        if let (Some(certs_path), Some(certs_keys_path)) =
+            (&globals.tls_cert_path, &globals.tls_cert_key_path)
+        {
+            globals.runtime_handle.spawn(Self::reload_tls_acceptor(
+                tls_acceptor.clone(),
+                certs_path.clone(),
+                certs_keys_path.clone(),
+            ));
+        }
+        while let Some(raw_stream) = listener.incoming().next().await {
+            let raw_stream = match raw_stream {
+                Ok(raw_stream) => raw_stream,
+                Err(_) => continue,
+            };
+            // Without HTTP headers, the client IP is always the address of the peer
+            let client_ip = raw_stream.peer_addr().ok().map(|address| address.ip());
+            if globals.access_list.is_enabled()
+                && !client_ip.is_some_and(|client_ip| globals.access_list.contains(client_ip))
+            {
+                continue;
+            }
+            let clients_count = globals.clients_count.clone();
+            if clients_count.increment() > globals.max_clients {
+                clients_count.decrement();
+                continue;
+            }
+            let current_tls_acceptor = tls_acceptor.read().unwrap().clone();
+            let self_inner = self.clone();
+            let handshake_timeout = Duration::from_secs(DOT_HANDSHAKE_TIMEOUT_SECS);
+            globals.runtime_handle.spawn(async move {
+                let handshake =
+                    tokio::time::timeout(handshake_timeout, current_tls_acceptor.accept(raw_stream))
+                        .await;
+                if let Ok(Ok(stream)) = handshake {
+                    self_inner.serve_dot_connection(stream, client_ip).await;
+                }
+                clients_count.decrement();
+            });
+        }
+        Ok(())
+    }
+}
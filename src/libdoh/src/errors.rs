@@ -7,22 +7,130 @@ pub enum DoHError {
     Incomplete,
     InvalidData,
     TooLarge,
+    RequestTimeout,
     UpstreamIssue,
     UpstreamTimeout,
+    Overloaded,
+    StaleKey,
     Hyper(hyper::Error),
     Io(io::Error),
 }
 
 impl std::error::Error for DoHError {}
 
+/// Reasons for which a DNS packet can be rejected by the parsers
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DnsError {
+    CompressedQuestionName,
+    DuplicateOptRr,
+    DuplicateCookie,
+    EdnsOptionTooLarge,
+    EdnsSectionTooLarge,
+    EdnsSectionTooLargeForPadding,
+    EmptyLabel,
+    ForwardCompressionPointer,
+    GarbageAfterPacket,
+    IncompleteOffset,
+    InvalidARecord,
+    InvalidAaaaRecord,
+    InvalidMxRecord,
+    InvalidSoaRecord,
+    InvalidSrvRecord,
+    InvalidTxtRecord,
+    InvalidCookieLength,
+    InvalidServerCookie,
+    InvalidSourcePrefixLength,
+    InvalidTcpKeepalive,
+    LargePacket,
+    LongLabel,
+    MalformedName,
+    OutOfBoundsName,
+    NameTooLong,
+    NoAdditionalRecords,
+    NoQuestion,
+    OptRrOutsideAdditionalSection,
+    OptRrWithNonRootName,
+    OutOfRangeEdnsSize,
+    NoRoomForRecord,
+    RecordCountsExceedPacketLength,
+    RecordExceedsPacketLength,
+    ShortSoaRecord,
+    ShortPacket,
+    TxtRecordTooLarge,
+    TooManyAdditionalRecords,
+    TooManyAnswerRecords,
+    TooManyCompressionPointers,
+    TruncatedEdnsOption,
+    TruncatedExtendedError,
+}
+
+impl DnsError {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DnsError::CompressedQuestionName => "Compressed name in question",
+            DnsError::DuplicateOptRr => "Duplicate OPT RR found",
+            DnsError::DuplicateCookie => "Duplicate cookie",
+            DnsError::EdnsOptionTooLarge => "EDNS option too large",
+            DnsError::EdnsSectionTooLarge => "EDNS section too large",
+            DnsError::EdnsSectionTooLargeForPadding => "EDNS section too large for padding",
+            DnsError::EmptyLabel => "Empty label",
+            DnsError::ForwardCompressionPointer => "Forward compression pointer",
+            DnsError::GarbageAfterPacket => "Garbage after packet",
+            DnsError::IncompleteOffset => "Incomplete offset",
+            DnsError::InvalidARecord => "Invalid A record",
+            DnsError::InvalidAaaaRecord => "Invalid AAAA record",
+            DnsError::InvalidMxRecord => "Invalid MX record",
+            DnsError::InvalidSoaRecord => "Invalid SOA record",
+            DnsError::InvalidSrvRecord => "Invalid SRV record",
+            DnsError::InvalidTxtRecord => "Invalid TXT record",
+            DnsError::InvalidCookieLength => "Invalid cookie length",
+            DnsError::InvalidServerCookie => "Invalid server cookie",
+            DnsError::InvalidSourcePrefixLength => "Invalid source prefix length",
+            DnsError::InvalidTcpKeepalive => "Invalid TCP keepalive option",
+            DnsError::LargePacket => "Large packet",
+            DnsError::LongLabel => "Long label",
+            DnsError::MalformedName => "Malformed name",
+            DnsError::OutOfBoundsName => "Malformed packet with an out-of-bounds name",
+            DnsError::NameTooLong => "Name too long",
+            DnsError::NoAdditionalRecords => "No additional records",
+            DnsError::NoQuestion => "No question",
+            DnsError::OptRrOutsideAdditionalSection => "OPT RR outside the additional section",
+            DnsError::OptRrWithNonRootName => "OPT RR with a non-root name",
+            DnsError::OutOfRangeEdnsSize => "Out of range EDNS size",
+            DnsError::NoRoomForRecord => "Packet would be too large to add a new record",
+            DnsError::RecordCountsExceedPacketLength => "Record counts exceed the packet length",
+            DnsError::RecordExceedsPacketLength => "Record length would exceed packet length",
+            DnsError::ShortSoaRecord => "Short SOA record",
+            DnsError::ShortPacket => "Short packet",
+            DnsError::TxtRecordTooLarge => "TXT record too large",
+            DnsError::TooManyAdditionalRecords => "Too many additional records",
+            DnsError::TooManyAnswerRecords => "Too many answer records",
+            DnsError::TooManyCompressionPointers => "Too many compression pointers",
+            DnsError::TruncatedEdnsOption => "Truncated EDNS option",
+            DnsError::TruncatedExtendedError => "Truncated extended error",
+        }
+    }
+}
+
+impl std::error::Error for DnsError {}
+
+impl std::fmt::Display for DnsError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(fmt, "{}", self.as_str())
+    }
+}
+
 impl std::fmt::Display for DoHError {
     fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
         match self {
             DoHError::Incomplete => write!(fmt, "Incomplete"),
             DoHError::InvalidData => write!(fmt, "Invalid data"),
             DoHError::TooLarge => write!(fmt, "Too large"),
+            DoHError::RequestTimeout => write!(fmt, "Request timeout"),
             DoHError::UpstreamIssue => write!(fmt, "Upstream error"),
             DoHError::UpstreamTimeout => write!(fmt, "Upstream timeout"),
+            DoHError::Overloaded => write!(fmt, "Too many in-flight queries"),
+            DoHError::StaleKey => write!(fmt, "Query encrypted with an unknown key"),
             DoHError::Hyper(e) => write!(fmt, "HTTP error: {}", e),
             DoHError::Io(e) => write!(fmt, "IO error: {}", e),
         }
@@ -35,8 +143,11 @@ impl From<DoHError> for StatusCode {
             DoHError::Incomplete => StatusCode::UNPROCESSABLE_ENTITY,
             DoHError::InvalidData => StatusCode::BAD_REQUEST,
             DoHError::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            DoHError::RequestTimeout => StatusCode::REQUEST_TIMEOUT,
             DoHError::UpstreamIssue => StatusCode::BAD_GATEWAY,
             DoHError::UpstreamTimeout => StatusCode::BAD_GATEWAY,
+            DoHError::Overloaded => StatusCode::SERVICE_UNAVAILABLE,
+            DoHError::StaleKey => StatusCode::UNAUTHORIZED,
             DoHError::Hyper(_) => StatusCode::SERVICE_UNAVAILABLE,
             DoHError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
@@ -0,0 +1,94 @@
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::TcpStream;
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use socket2::Socket;
+    use std::io;
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+
+    // The SYN-ACK acknowledged the data sent along with the SYN
+    const TCPI_OPT_SYN_DATA: u8 = 32;
+
+    // With TCP_FASTOPEN_CONNECT, connect() returns immediately, and the SYN is
+    // only sent along with the first write
+    pub fn set_fastopen_connect(socket: &Socket) -> io::Result<()> {
+        let enable: libc::c_int = 1;
+        let ret = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_FASTOPEN_CONNECT,
+                &enable as *const _ as *const libc::c_void,
+                mem::size_of_val(&enable) as libc::socklen_t,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn syn_data_acked<S: AsRawFd>(socket: &S) -> bool {
+        let mut info: libc::tcp_info = unsafe { mem::zeroed() };
+        let mut len = mem::size_of_val(&info) as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_INFO,
+                &mut info as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        ret == 0 && info.tcpi_options & TCPI_OPT_SYN_DATA != 0
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use socket2::Socket;
+    use std::io;
+
+    pub fn set_fastopen_connect(_socket: &Socket) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "TCP Fast Open is not supported on this platform",
+        ))
+    }
+
+    pub fn syn_data_acked<S>(_socket: &S) -> bool {
+        false
+    }
+}
+
+fn fastopen_socket(address: SocketAddr) -> io::Result<std::net::TcpStream> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    sys::set_fastopen_connect(&socket)?;
+    Ok(socket.into())
+}
+
+/// Connects to an upstream server. With `fastopen`, the first data written is
+/// sent in the SYN if the kernel has a Fast Open cookie for the server; where
+/// TCP Fast Open isn't available, a regular connection is made instead.
+pub(crate) async fn connect_tcp(address: SocketAddr, fastopen: bool) -> io::Result<TcpStream> {
+    if fastopen {
+        if let Ok(socket) = fastopen_socket(address) {
+            return TcpStream::connect_std(socket, &address).await;
+        }
+    }
+    TcpStream::connect(&address).await
+}
+
+/// Returns `true` if the data sent along with the SYN was accepted by the server.
+/// Only meaningful once a response has been received.
+pub(crate) fn used_fastopen(stream: &TcpStream) -> bool {
+    sys::syn_data_acked(stream)
+}
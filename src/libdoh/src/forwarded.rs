@@ -0,0 +1,141 @@
+use crate::acl::AccessList;
+
+use hyper::header::{HeaderMap, FORWARDED};
+use std::net::{IpAddr, SocketAddr};
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+// `for=192.0.2.1`, `for="192.0.2.1:4711"`, `for="[2001:db8::1]:4711"`
+fn parse_forwarded_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    if let Ok(address) = node.parse::<SocketAddr>() {
+        return Some(address.ip());
+    }
+    node.strip_prefix('[')
+        .and_then(|node| node.strip_suffix(']'))
+        .and_then(|ip| ip.parse().ok())
+}
+
+// Hops in the order they were appended, the closest proxy's peer last.
+// Unparsable hops (`unknown`, obfuscated identifiers) are kept as `None`.
+fn forwarded_chain(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let mut chain = vec![];
+    for value in headers.get_all(FORWARDED) {
+        let value = match value.to_str() {
+            Ok(value) => value,
+            Err(_) => return vec![None],
+        };
+        for element in value.split(',') {
+            let node = element.split(';').find_map(|pair| {
+                let mut kv = pair.splitn(2, '=');
+                match (kv.next(), kv.next()) {
+                    (Some(key), Some(node)) if key.trim().eq_ignore_ascii_case("for") => Some(node),
+                    _ => None,
+                }
+            });
+            chain.push(node.and_then(parse_forwarded_node));
+        }
+    }
+    if !chain.is_empty() {
+        return chain;
+    }
+    for value in headers.get_all(X_FORWARDED_FOR) {
+        let value = match value.to_str() {
+            Ok(value) => value,
+            Err(_) => return vec![None],
+        };
+        chain.extend(value.split(',').map(parse_forwarded_node));
+    }
+    chain
+}
+
+// Walks the chain from the direct peer, skipping trusted proxies, and returns the
+// first address that was not added by one of them
+pub(crate) fn real_client_ip(
+    peer_ip: IpAddr,
+    headers: &HeaderMap,
+    trusted_proxies: &AccessList,
+) -> IpAddr {
+    let mut client_ip = peer_ip;
+    for hop in forwarded_chain(headers).into_iter().rev() {
+        if !trusted_proxies.contains(client_ip) {
+            break;
+        }
+        match hop {
+            Some(ip) => client_ip = ip,
+            None => break,
+        }
+    }
+    client_ip
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acl::Network;
+
+    fn trusted_proxies() -> AccessList {
+        let networks: Vec<Network> = ["10.0.0.0/8", "2001:db8::/32"]
+            .iter()
+            .map(|network| network.parse().unwrap())
+            .collect();
+        AccessList::new(&networks)
+    }
+
+    fn client_ip(peer_ip: &str, headers: &[(&'static str, &str)]) -> String {
+        let mut header_map = HeaderMap::new();
+        for (name, value) in headers {
+            header_map.append(*name, value.parse().unwrap());
+        }
+        real_client_ip(peer_ip.parse().unwrap(), &header_map, &trusted_proxies()).to_string()
+    }
+
+    #[test]
+    fn trusted_hops_are_skipped() {
+        let xff = [(X_FORWARDED_FOR, "203.0.113.7, 10.0.0.2, 10.0.0.1")];
+        assert_eq!(client_ip("10.0.0.3", &xff), "203.0.113.7");
+    }
+
+    #[test]
+    fn rightmost_untrusted_hop_is_the_client() {
+        // The first address was added by the client itself and can't be trusted
+        let xff = [(X_FORWARDED_FOR, "192.0.2.66, 203.0.113.7, 10.0.0.1")];
+        assert_eq!(client_ip("10.0.0.3", &xff), "203.0.113.7");
+    }
+
+    #[test]
+    fn hops_from_multiple_headers_are_chained() {
+        let xff = [
+            (X_FORWARDED_FOR, "203.0.113.7"),
+            (X_FORWARDED_FOR, "10.0.0.2, 10.0.0.1"),
+        ];
+        assert_eq!(client_ip("10.0.0.3", &xff), "203.0.113.7");
+    }
+
+    #[test]
+    fn headers_from_untrusted_peers_are_ignored() {
+        let xff = [(X_FORWARDED_FOR, "203.0.113.7")];
+        assert_eq!(client_ip("192.0.2.1", &xff), "192.0.2.1");
+    }
+
+    #[test]
+    fn unknown_hops_stop_the_walk() {
+        let xff = [(X_FORWARDED_FOR, "203.0.113.7, unknown, 10.0.0.1")];
+        assert_eq!(client_ip("10.0.0.3", &xff), "10.0.0.1");
+    }
+
+    #[test]
+    fn forwarded_header_takes_precedence() {
+        let headers = [
+            (
+                "forwarded",
+                "for=203.0.113.7;proto=https, for=\"[2001:db8::1]:4711\"",
+            ),
+            (X_FORWARDED_FOR, "192.0.2.66"),
+        ];
+        assert_eq!(client_ip("10.0.0.3", &headers), "203.0.113.7");
+    }
+}
@@ -1,12 +1,29 @@
-use std::net::SocketAddr;
+use crate::acl::AccessList;
+use crate::blocklist::Blocklist;
+use crate::cache::Cache;
+use crate::coalesce::InflightQueries;
+use crate::dns::FailureTtls;
+use crate::headers::ResponseHeader;
+use crate::health::HealthCheck;
+use crate::malformed::MalformedPacketLog;
+use crate::metrics::Metrics;
+use crate::odoh::ODoHKeyPair;
+use crate::overrides::Overrides;
+use crate::poison::PoisonFilter;
+use crate::querylog::QueryLog;
+use crate::ratelimit::RateLimiter;
+use crate::rebinding::RebindingProtection;
+use crate::discovery::UpstreamSource;
+use crate::upstream::Upstreams;
+use crate::zone::LocalZone;
+
+use std::net::{Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::runtime;
 
-#[cfg(feature = "tls")]
-use std::path::PathBuf;
-
 #[derive(Debug)]
 pub struct Globals {
     #[cfg(feature = "tls")]
@@ -15,18 +32,100 @@ pub struct Globals {
     #[cfg(feature = "tls")]
     pub tls_cert_key_path: Option<PathBuf>,
 
+    #[cfg(feature = "tls")]
+    pub enable_dot: bool,
+
+    #[cfg(feature = "tls")]
+    pub dot_listen_address: SocketAddr,
+
+    #[cfg(feature = "http3")]
+    pub enable_http3: bool,
+
     pub listen_address: SocketAddr,
+    pub reuse_port: bool,
     pub local_bind_address: SocketAddr,
-    pub server_address: SocketAddr,
+    pub local_bind_port_range: Option<(u16, u16)>,
+    pub upstreams: Upstreams,
+    pub upstream_sources: Vec<UpstreamSource>,
+    pub upstream_refresh_interval: Option<Duration>,
+    pub tcp_pool_size: usize,
+    pub tcp_idle_timeout: Duration,
+    pub tcp_keepalive: bool,
+    pub upstream_tcp_fastopen: bool,
+    pub udp_edns_size: u16,
+    pub adaptive_edns_size: bool,
+    pub tcp_edns_size: u16,
+    pub force_tcp: bool,
+    pub qname_minimization: bool,
+    pub hostname: Option<String>,
+    pub response_headers: Vec<ResponseHeader>,
+    pub server_timing: bool,
     pub path: String,
+    pub path_aliases: Vec<String>,
     pub max_clients: usize,
+    pub max_request_size: usize,
+    pub max_client_packet_size: usize,
+    pub body_read_timeout: Duration,
+    pub body_min_rate: usize,
     pub timeout: Duration,
+    pub shutdown_grace_period: Duration,
+    pub upstream_timeout: Duration,
+    pub upstream_retries: u32,
+    pub upstream_failure_threshold: usize,
+    pub upstream_cooldown: Duration,
+    pub race_upstreams: bool,
+    pub max_inflight_per_upstream: usize,
     pub clients_count: ClientsCount,
+    pub inflight_queries: ClientsCount,
+    pub inflight_upstream_queries: InflightQueries,
+    pub max_concurrent_streams: u32,
+    pub overload_servfail: bool,
     pub min_ttl: u32,
     pub max_ttl: u32,
     pub err_ttl: u32,
+    pub failure_ttls: FailureTtls,
+    pub unreachable_ede_code: u16,
     pub keepalive: bool,
     pub disable_post: bool,
+    pub padding_block_size: u16,
+    pub randomize_case: bool,
+    pub refuse_any: bool,
+    pub allow_all_opcodes: bool,
+    pub minimize_responses: bool,
+    pub max_response_size: usize,
+    pub debug_packets: bool,
+    pub cache: Cache,
+    pub max_cache_ttl: u32,
+    pub cache_persist_path: Option<PathBuf>,
+    pub ttl_jitter_pct: u32,
+    pub rate_limiter: RateLimiter,
+    pub client_ip_header: Option<String>,
+    pub access_list: AccessList,
+    pub trusted_proxies: AccessList,
+    pub blocklist: Blocklist,
+    pub allowlist: Blocklist,
+    pub default_deny: bool,
+    pub overrides: Overrides,
+    pub local_zone: LocalZone,
+    pub poison_filter: PoisonFilter,
+    pub append_client_subnet: bool,
+    pub allow_ping: bool,
+    pub odoh_key_pair: Option<ODoHKeyPair>,
+    pub client_subnet_prefix_v4: u8,
+    pub client_subnet_prefix_v6: u8,
+    pub synthesize_aaaa: bool,
+    pub dns64_prefix: Ipv6Addr,
+    pub chaos_version: Option<String>,
+    pub chaos_hostname: Option<String>,
+    pub nsid: Option<String>,
+    pub rebinding_protection: RebindingProtection,
+    pub enable_metrics: bool,
+    pub metrics_address: Option<SocketAddr>,
+    pub metrics: Metrics,
+    pub query_log: QueryLog,
+    pub malformed_packet_log: MalformedPacketLog,
+    pub health_check_name: Option<String>,
+    pub health_check: HealthCheck,
 
     pub runtime_handle: runtime::Handle,
 }
@@ -34,7 +133,28 @@ pub struct Globals {
 #[derive(Debug, Clone, Default)]
 pub struct ClientsCount(Arc<AtomicUsize>);
 
+#[derive(Debug)]
+pub struct ClientsCountGuard(ClientsCount);
+
+impl Drop for ClientsCountGuard {
+    fn drop(&mut self) {
+        self.0.decrement();
+    }
+}
+
 impl ClientsCount {
+    pub fn current(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn try_acquire(&self, max: usize) -> Option<ClientsCountGuard> {
+        if self.increment() >= max {
+            self.decrement();
+            return None;
+        }
+        Some(ClientsCountGuard(self.clone()))
+    }
+
     pub fn increment(&self) -> usize {
         self.0.fetch_add(1, Ordering::Relaxed)
     }
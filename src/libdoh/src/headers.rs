@@ -0,0 +1,70 @@
+use crate::constants::*;
+
+use hyper::header::{self, HeaderName, HeaderValue};
+use hyper::{Body, Response};
+use std::str::FromStr;
+
+// Headers that the server sets itself and that cannot be configured
+const RESERVED_HEADERS: [HeaderName; 3] = [
+    header::CONTENT_TYPE,
+    header::CACHE_CONTROL,
+    header::CONTENT_LENGTH,
+];
+
+/// An extra header added to every HTTP response, given as `Name: value`
+#[derive(Clone, Debug)]
+pub struct ResponseHeader {
+    name: HeaderName,
+    value: HeaderValue,
+}
+
+impl ResponseHeader {
+    pub fn hsts() -> Self {
+        ResponseHeader {
+            name: header::STRICT_TRANSPORT_SECURITY,
+            value: HeaderValue::from_str(&format!(
+                "max-age={}; includeSubDomains",
+                HSTS_MAX_AGE_SECS
+            ))
+            .unwrap(),
+        }
+    }
+}
+
+impl FromStr for ResponseHeader {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim();
+        let value = parts
+            .next()
+            .ok_or_else(|| format!("Expected \"Name: value\": {}", s))?
+            .trim();
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| format!("Invalid header name: {}", s))?;
+        if RESERVED_HEADERS.contains(&name) {
+            return Err(format!("The {} header cannot be overridden", name));
+        }
+        let value =
+            HeaderValue::from_str(value).map_err(|_| format!("Invalid header value: {}", s))?;
+        Ok(ResponseHeader { name, value })
+    }
+}
+
+// Headers already set by the handler are left untouched
+pub(crate) fn add_response_headers(
+    mut response: Response<Body>,
+    response_headers: &[ResponseHeader],
+) -> Response<Body> {
+    let headers = response.headers_mut();
+    for response_header in response_headers {
+        if !headers.contains_key(&response_header.name) {
+            headers.insert(
+                response_header.name.clone(),
+                response_header.value.clone(),
+            );
+        }
+    }
+    response
+}
@@ -0,0 +1,71 @@
+use crate::constants::*;
+use crate::dns;
+use crate::utils::*;
+use crate::{http_error, DoH};
+
+use hyper::{http, Body, Response, StatusCode};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Default)]
+pub struct HealthCheck {
+    last_result: Mutex<Option<(Instant, bool)>>,
+}
+
+impl HealthCheck {
+    fn cached(&self) -> Option<bool> {
+        match *self.last_result.lock().unwrap() {
+            Some((checked_at, healthy))
+                if checked_at.elapsed() < Duration::from_secs(HEALTH_CHECK_CACHE_SECS) =>
+            {
+                Some(healthy)
+            }
+            _ => None,
+        }
+    }
+
+    fn store(&self, healthy: bool) {
+        *self.last_result.lock().unwrap() = Some((Instant::now(), healthy));
+    }
+}
+
+impl DoH {
+    async fn probe_upstream(&self) -> bool {
+        let query = match &self.globals.health_check_name {
+            Some(name) => dns::build_query(name, dns::DNS_TYPE_A, false, false),
+            None => dns::build_query(".", dns::DNS_TYPE_NS, false, false),
+        };
+        let mut query = match query {
+            Ok(query) => query,
+            Err(_) => return false,
+        };
+        dns::set_tid(&mut query, random_u64() as u16);
+        match tokio::time::timeout(
+            Duration::from_secs(HEALTH_CHECK_TIMEOUT_SECS),
+            self.upstream_query(&query),
+        )
+        .await
+        {
+            Ok(Ok(packet)) => !dns::is_recoverable_error(&packet),
+            _ => false,
+        }
+    }
+
+    pub(crate) async fn serve_health(&self) -> Result<Response<Body>, http::Error> {
+        let health_check = &self.globals.health_check;
+        let healthy = match health_check.cached() {
+            Some(healthy) => healthy,
+            None => {
+                let healthy = self.probe_upstream().await;
+                health_check.store(healthy);
+                healthy
+            }
+        };
+        if !healthy {
+            return http_error(StatusCode::SERVICE_UNAVAILABLE);
+        }
+        Response::builder()
+            .header(hyper::header::CONTENT_TYPE, "text/plain")
+            .body(Body::from("OK"))
+    }
+}
@@ -0,0 +1,278 @@
+use crate::constants::*;
+use crate::DoH;
+
+use bytes::{BufMut, Bytes};
+use h3::quic::BidiStream;
+use h3::server::RequestStream;
+use hyper::header::{self, HeaderValue};
+use hyper::service::Service;
+use hyper::{http, Body, Request, Response, StatusCode};
+use quinn::crypto::rustls::QuicServerConfig;
+use rustls23::pki_types::pem::PemObject;
+use rustls23::pki_types::{CertificateDer, PrivateKeyDer};
+use std::convert::TryFrom;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+pub fn create_quic_server_config<P, P2>(
+    certs_path: P,
+    certs_keys_path: P2,
+) -> io::Result<quinn::ServerConfig>
+where
+    P: AsRef<Path>,
+    P2: AsRef<Path>,
+{
+    let certs = CertificateDer::pem_file_iter(certs_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unable to load the certificates: {}", e),
+            )
+        })?;
+    let certs_key = PrivateKeyDer::from_pem_file(certs_keys_path).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Unable to load the certificate keys: {}", e),
+        )
+    })?;
+    let provider = Arc::new(rustls23::crypto::ring::default_provider());
+    let mut tls_config = rustls23::ServerConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls23::version::TLS13])
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+        .with_no_client_auth()
+        .with_single_cert(certs, certs_key)
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid private key for the given certificate",
+            )
+        })?;
+    tls_config.alpn_protocols = vec![HTTP3_ALPN.to_vec()];
+    let quic_config = QuicServerConfig::try_from(tls_config)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    Ok(quinn::ServerConfig::with_crypto(Arc::new(quic_config)))
+}
+
+// The h3 request converted to the types of the hyper version the handler uses
+fn to_hyper_request(req: http1::Request<()>, body: Vec<u8>) -> Option<Request<Body>> {
+    let (parts, _) = req.into_parts();
+    let mut builder = Request::builder()
+        .method(parts.method.as_str())
+        .uri(parts.uri.to_string())
+        .version(http::Version::HTTP_3);
+    for (name, value) in &parts.headers {
+        builder = builder.header(name.as_str(), value.as_bytes());
+    }
+    builder.body(Body::from(body)).ok()
+}
+
+fn to_h3_response(parts: http::response::Parts) -> Option<http1::Response<()>> {
+    let mut builder = http1::Response::builder().status(parts.status.as_u16());
+    for (name, value) in &parts.headers {
+        builder = builder.header(name.as_str(), value.as_bytes());
+    }
+    builder.body(()).ok()
+}
+
+// quinn and h3 require tokio 1, so HTTP/3 connections are accepted on a runtime of
+// their own; requests are handed over to the main runtime, and go through the same
+// handler as HTTP/1.1 and HTTP/2 ones
+impl DoH {
+    // Advertises the HTTP/3 listener, which shares the port of the TCP one
+    pub(crate) fn add_alt_svc_header(&self, mut response: Response<Body>) -> Response<Body> {
+        let alt_svc = format!(
+            "h3=\":{}\"; ma={}",
+            self.globals.listen_address.port(),
+            HTTP3_ALT_SVC_MAX_AGE_SECS
+        );
+        if let Ok(alt_svc) = HeaderValue::from_str(&alt_svc) {
+            response.headers_mut().insert(header::ALT_SVC, alt_svc);
+        }
+        response
+    }
+
+    // The response, with its body, from the main runtime
+    async fn http3_response(&self, req: Request<Body>) -> Option<(http::response::Parts, Bytes)> {
+        let mut self_inner = self.clone();
+        let (parts, body) = self
+            .globals
+            .runtime_handle
+            .spawn(async move {
+                let response = self_inner.call(req).await.ok()?;
+                let (parts, body) = response.into_parts();
+                let body = hyper::body::to_bytes(body).await.ok()?;
+                Some((parts, body.to_vec()))
+            })
+            .await
+            .ok()??;
+        Some((parts, Bytes::from(body)))
+    }
+
+    async fn serve_http3_request<S>(
+        &self,
+        req: http1::Request<()>,
+        mut stream: RequestStream<S, Bytes>,
+    ) where
+        S: BidiStream<Bytes>,
+    {
+        // Larger bodies are rejected by the handler, so there is no need to read more
+        let max_request_size = self.globals.max_request_size;
+        let mut body = Vec::new();
+        while body.len() <= max_request_size {
+            match stream.recv_data().await {
+                Ok(Some(chunk)) => body.put(chunk),
+                Ok(None) => break,
+                Err(_) => return,
+            }
+        }
+        let response = match to_hyper_request(req, body) {
+            Some(req) => self.http3_response(req).await,
+            None => None,
+        };
+        let (response, body) = match response {
+            Some((parts, body)) => (to_h3_response(parts), body),
+            None => (None, Bytes::new()),
+        };
+        let response = response.unwrap_or_else(|| {
+            http1::Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR.as_u16())
+                .body(())
+                .unwrap()
+        });
+        if stream.send_response(response).await.is_err() {
+            return;
+        }
+        if !body.is_empty() && stream.send_data(body).await.is_err() {
+            return;
+        }
+        let _ = stream.finish().await;
+    }
+
+    async fn serve_http3_connection(mut self, incoming: quinn::Incoming) {
+        self.remote_addr = Some(incoming.remote_address());
+        let connection = match incoming.await {
+            Ok(connection) => connection,
+            Err(_) => return,
+        };
+        let mut connection: h3::server::Connection<_, Bytes> =
+            match h3::server::Connection::new(h3_quinn::Connection::new(connection)).await {
+                Ok(connection) => connection,
+                Err(_) => return,
+            };
+        while let Ok(Some(resolver)) = connection.accept().await {
+            let self_inner = self.clone();
+            tokio1::spawn(async move {
+                if let Ok((req, stream)) = resolver.resolve_request().await {
+                    self_inner.serve_http3_request(req, stream).await;
+                }
+            });
+        }
+    }
+
+    async fn accept_http3(self, endpoint: quinn::Endpoint) {
+        let globals = self.globals.clone();
+        while let Some(incoming) = endpoint.accept().await {
+            let clients_count = globals.clients_count.clone();
+            if clients_count.increment() > globals.max_clients {
+                clients_count.decrement();
+                incoming.refuse();
+                continue;
+            }
+            let self_inner = self.clone();
+            let timeout = globals.timeout + Duration::from_secs(1);
+            tokio1::spawn(async move {
+                tokio1::time::timeout(timeout, self_inner.serve_http3_connection(incoming))
+                    .await
+                    .ok();
+                clients_count.decrement();
+            });
+        }
+    }
+
+    pub(crate) fn start_http3(self, server_config: quinn::ServerConfig) -> io::Result<()> {
+        let runtime = tokio1::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .thread_name("doh-proxy-h3")
+            .build()?;
+        let endpoint = {
+            let _guard = runtime.enter();
+            quinn::Endpoint::server(server_config, self.globals.listen_address)?
+        };
+        // Like the TCP listener, new connections are refused on shutdown, while the
+        // ones already accepted keep being served while draining
+        let shutdown_endpoint = endpoint.clone();
+        self.globals.runtime_handle.spawn(async move {
+            crate::shutdown::shutdown_requested().await;
+            shutdown_endpoint.set_server_config(None);
+        });
+        thread::Builder::new()
+            .name("doh-proxy-h3".to_string())
+            .spawn(move || runtime.block_on(self.accept_http3(endpoint)))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn requests_and_responses_are_converted() {
+        let req = http1::Request::post("https://example.com/dns-query?ct")
+            .header("content-type", DNS_MESSAGE_CONTENT_TYPE)
+            .header("x-forwarded-for", "192.0.2.1")
+            .body(())
+            .unwrap();
+        let req = to_hyper_request(req, b"query".to_vec()).unwrap();
+        assert_eq!(req.method(), hyper::Method::POST);
+        assert_eq!(req.version(), http::Version::HTTP_3);
+        assert_eq!(req.uri().authority().unwrap(), "example.com");
+        assert_eq!(req.uri().path(), "/dns-query");
+        assert_eq!(req.uri().query(), Some("ct"));
+        assert_eq!(
+            req.headers()[header::CONTENT_TYPE],
+            DNS_MESSAGE_CONTENT_TYPE
+        );
+        assert_eq!(req.headers()["x-forwarded-for"], "192.0.2.1");
+
+        let response = Response::builder()
+            .status(StatusCode::PAYLOAD_TOO_LARGE)
+            .header(header::CACHE_CONTROL, "max-age=0")
+            .body(Body::empty())
+            .unwrap();
+        let (parts, _) = response.into_parts();
+        let response = to_h3_response(parts).unwrap();
+        assert_eq!(response.status(), 413);
+        assert_eq!(response.headers()["cache-control"], "max-age=0");
+    }
+
+    #[test]
+    fn http3_is_advertised_when_enabled() {
+        testing::runtime().block_on(async {
+            let address = testing::udp_upstream(testing::answer).await;
+            let req = || Request::get("/health").body(Body::empty()).unwrap();
+            let mut doh = testing::doh(testing::globals(address));
+            let response = doh.call(req()).await.unwrap();
+            assert!(!response.headers().contains_key(header::ALT_SVC));
+
+            let mut globals = testing::globals(address);
+            globals.enable_http3 = true;
+            let mut doh = testing::doh(globals);
+            let response = doh.call(req()).await.unwrap();
+            assert_eq!(
+                response.headers()[header::ALT_SVC],
+                "h3=\":3000\"; ma=86400"
+            );
+        });
+    }
+
+    #[test]
+    fn missing_certificates_are_an_error() {
+        assert!(create_quic_server_config("/nonexistent.pem", "/nonexistent.pem").is_err());
+    }
+}
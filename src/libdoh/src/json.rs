@@ -0,0 +1,604 @@
+use crate::acl::Network;
+use crate::constants::*;
+use crate::dns::{self, ensure};
+use crate::errors::*;
+use crate::{http_error, DoH};
+
+use byteorder::{BigEndian, ByteOrder};
+use hyper::{http, Body, Request, Response, StatusCode};
+use std::fmt::Write;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+const DNS_JSON_CONTENT_TYPE: &str = "application/dns-json";
+
+const DNS_TYPES: [(&str, u16); 22] = [
+    ("A", 1),
+    ("NS", 2),
+    ("CNAME", 5),
+    ("SOA", 6),
+    ("PTR", 12),
+    ("HINFO", 13),
+    ("MX", 15),
+    ("TXT", 16),
+    ("AAAA", 28),
+    ("SRV", 33),
+    ("NAPTR", 35),
+    ("DNAME", 39),
+    ("DS", 43),
+    ("SSHFP", 44),
+    ("RRSIG", 46),
+    ("NSEC", 47),
+    ("DNSKEY", 48),
+    ("NSEC3", 50),
+    ("TLSA", 52),
+    ("HTTPS", 65),
+    ("ANY", 255),
+    ("CAA", 257),
+];
+
+pub(crate) fn query_params(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let mut kv = part.splitn(2, '=');
+            let k = percent_decode(kv.next().unwrap_or(""));
+            let v = percent_decode(kv.next().unwrap_or(""));
+            (k, v)
+        })
+        .collect()
+}
+
+pub(crate) fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                let hex = |c: u8| (c as char).to_digit(16);
+                match (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                    (Some(hi), Some(lo)) => {
+                        decoded.push((hi * 16 + lo) as u8);
+                        i += 3;
+                        continue;
+                    }
+                    _ => decoded.push(b'%'),
+                }
+            }
+            b'+' => decoded.push(b' '),
+            c => decoded.push(c),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// JSON queries are recognized by their `name` parameter. The `Accept` header is
+// ignored, as clients commonly list several types, and queries with a `dns`
+// parameter are always RFC 8484 queries.
+pub(crate) fn is_json_request(req: &Request<Body>) -> bool {
+    let params = query_params(req.uri().query().unwrap_or(""));
+    let has_param = |name: &str| params.iter().any(|(k, _)| k == name);
+    has_param("name") && !has_param(DNS_QUERY_PARAM)
+}
+
+fn parse_qtype(qtype: &str) -> Option<u16> {
+    if let Ok(qtype) = qtype.parse() {
+        return Some(qtype);
+    }
+    DNS_TYPES
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(qtype))
+        .map(|&(_, qtype)| qtype)
+}
+
+pub(crate) fn qtype_to_string(qtype: u16) -> String {
+    DNS_TYPES
+        .iter()
+        .find(|&&(_, t)| t == qtype)
+        .map(|(name, _)| name.to_string())
+        .unwrap_or_else(|| format!("TYPE{}", qtype))
+}
+
+fn parse_flag(value: &str) -> bool {
+    matches!(value, "1" | "true")
+}
+
+pub(crate) fn json_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+fn rdata_to_string(
+    packet: &[u8],
+    rtype: u16,
+    offset: usize,
+    rdlen: usize,
+) -> Result<String, DnsError> {
+    let rdata = &packet[offset..offset + rdlen];
+    let s = match rtype {
+        1 => {
+            ensure!(rdlen == 4, DnsError::InvalidARecord);
+            Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]).to_string()
+        }
+        28 => {
+            ensure!(rdlen == 16, DnsError::InvalidAaaaRecord);
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(rdata);
+            Ipv6Addr::from(octets).to_string()
+        }
+        2 | 5 | 12 | 39 => dns::name_to_string(&dns::expand_name(packet, offset)?.0),
+        15 => {
+            ensure!(rdlen > 2, DnsError::InvalidMxRecord);
+            let preference = BigEndian::read_u16(rdata);
+            let exchange = dns::expand_name(packet, offset + 2)?.0;
+            format!("{} {}", preference, dns::name_to_string(&exchange))
+        }
+        16 => {
+            let mut strings = vec![];
+            let mut i = 0;
+            while i < rdlen {
+                let len = rdata[i] as usize;
+                ensure!(rdlen - i > len, DnsError::InvalidTxtRecord);
+                let text = String::from_utf8_lossy(&rdata[i + 1..i + 1 + len]);
+                strings.push(format!(
+                    "\"{}\"",
+                    text.replace('\\', "\\\\").replace('"', "\\\"")
+                ));
+                i += len + 1;
+            }
+            strings.join(" ")
+        }
+        6 => {
+            let (mname, next_offset) = dns::expand_name(packet, offset)?;
+            let (rname, next_offset) = dns::expand_name(packet, next_offset)?;
+            ensure!(next_offset + 20 == offset + rdlen, DnsError::InvalidSoaRecord);
+            let values = &packet[next_offset..];
+            format!(
+                "{} {} {} {} {} {} {}",
+                dns::name_to_string(&mname),
+                dns::name_to_string(&rname),
+                BigEndian::read_u32(&values[0..]),
+                BigEndian::read_u32(&values[4..]),
+                BigEndian::read_u32(&values[8..]),
+                BigEndian::read_u32(&values[12..]),
+                BigEndian::read_u32(&values[16..])
+            )
+        }
+        33 => {
+            ensure!(rdlen > 6, DnsError::InvalidSrvRecord);
+            let target = dns::expand_name(packet, offset + 6)?.0;
+            format!(
+                "{} {} {} {}",
+                BigEndian::read_u16(&rdata[0..]),
+                BigEndian::read_u16(&rdata[2..]),
+                BigEndian::read_u16(&rdata[4..]),
+                dns::name_to_string(&target)
+            )
+        }
+        _ => {
+            let mut s = format!("\\# {}", rdlen);
+            if rdlen > 0 {
+                s.push(' ');
+                for c in rdata {
+                    let _ = write!(s, "{:02x}", c);
+                }
+            }
+            s
+        }
+    };
+    Ok(s)
+}
+
+fn records_to_json(
+    packet: &[u8],
+    offset: usize,
+    rrcount: usize,
+    records: &mut Vec<String>,
+) -> Result<usize, DnsError> {
+    let packet_len = packet.len();
+    let mut rr_start = offset;
+    dns::traverse_rrs(packet, offset, rrcount, |offset| {
+        let rtype = BigEndian::read_u16(&packet[offset..]);
+        let ttl = BigEndian::read_u32(&packet[offset + 4..]);
+        let rdlen = BigEndian::read_u16(&packet[offset + 8..]) as usize;
+        ensure!(
+            packet_len - offset - 10 >= rdlen,
+            DnsError::RecordExceedsPacketLength
+        );
+        let name = dns::expand_name(packet, rr_start)?.0;
+        rr_start = offset + 10 + rdlen;
+        if rtype == dns::DNS_TYPE_OPT {
+            return Ok(());
+        }
+        let data = rdata_to_string(packet, rtype, offset + 10, rdlen)?;
+        records.push(format!(
+            "{{\"name\":{},\"type\":{},\"TTL\":{},\"data\":{}}}",
+            json_escape(&dns::name_to_string(&name)),
+            rtype,
+            ttl,
+            json_escape(&data)
+        ));
+        Ok(())
+    })
+}
+
+fn response_to_json(packet: &[u8]) -> Result<String, DnsError> {
+    let qname = dns::expanded_qname(packet)?;
+    let qtype = dns::qtype(packet)?;
+    let flags = dns::header_flags(packet);
+    let mut json = format!(
+        "{{\"Status\":{},\"TC\":{},\"RD\":{},\"RA\":{},\"AD\":{},\"CD\":{},\"Question\":[{{\"name\":{},\"type\":{}}}]",
+        flags.rcode,
+        flags.tc,
+        flags.rd,
+        flags.ra,
+        flags.ad,
+        flags.cd,
+        json_escape(&dns::name_to_string(&qname)),
+        qtype
+    );
+    let mut offset = dns::question_type_offset(packet)? + 4;
+    let sections = [
+        ("Answer", dns::ancount(packet)),
+        ("Authority", dns::nscount(packet)),
+        ("Additional", dns::arcount(packet)),
+    ];
+    for &(section, rrcount) in sections.iter() {
+        let mut records = vec![];
+        offset = records_to_json(packet, offset, rrcount as usize, &mut records)?;
+        if !records.is_empty() {
+            let _ = write!(json, ",\"{}\":[{}]", section, records.join(","));
+        }
+    }
+    json.push('}');
+    Ok(json)
+}
+
+// Trailing whitespace is valid JSON, and hides the length of the response
+fn pad_json(json: &mut String) {
+    let padded_len = json.len().div_ceil(JSON_PADDING_BLOCK_SIZE) * JSON_PADDING_BLOCK_SIZE;
+    json.extend(std::iter::repeat_n(' ', padded_len - json.len()));
+}
+
+impl DoH {
+    pub(crate) async fn serve_json(
+        &self,
+        req: Request<Body>,
+    ) -> Result<Response<Body>, http::Error> {
+        let params = query_params(req.uri().query().unwrap_or(""));
+        let param = |name: &str| {
+            params
+                .iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.as_str())
+        };
+        let name = match param("name") {
+            Some(name) if !name.is_empty() => name,
+            _ => return http_error(StatusCode::BAD_REQUEST),
+        };
+        let qtype = match param("type").map(parse_qtype) {
+            None => 1,
+            Some(Some(qtype)) => qtype,
+            Some(None) => return http_error(StatusCode::BAD_REQUEST),
+        };
+        let dnssec_ok = param("do").map(parse_flag).unwrap_or(false);
+        let checking_disabled = param("cd").map(parse_flag).unwrap_or(false);
+        // Sent by clients to hide the length of the query; the response is padded in return
+        let padding = matches!(param("random_padding"), Some(padding) if !padding.is_empty());
+        let wire_format = match param("ct") {
+            None | Some("") | Some(DNS_JSON_CONTENT_TYPE) => false,
+            Some(DNS_MESSAGE_CONTENT_TYPE) => true,
+            Some(_) => return http_error(StatusCode::UNSUPPORTED_MEDIA_TYPE),
+        };
+        // A /0 prefix opts out of ECS, including the subnet derived from the client IP
+        let explicit_client_subnet = match param("edns_client_subnet") {
+            None | Some("") => None,
+            Some(subnet) => match subnet.parse::<Network>() {
+                Ok(subnet) if subnet.prefix_len() == 0 => Some(None),
+                Ok(subnet) => Some(Some(subnet)),
+                Err(_) => return http_error(StatusCode::BAD_REQUEST),
+            },
+        };
+        let query = match dns::build_query(name, qtype, dnssec_ok, checking_disabled) {
+            Ok(query) => query,
+            Err(_) => return http_error(StatusCode::BAD_REQUEST),
+        };
+        let client_ip = self.client_ip(&req);
+        let client_subnet = explicit_client_subnet
+            .unwrap_or_else(|| self.derived_client_subnet(&query, client_ip));
+        let response = match self.resolve(query, client_ip, client_subnet).await
+        {
+            Ok(response) => response,
+            Err(e) => return http_error(StatusCode::from(e)),
+        };
+        if wire_format {
+            return Ok(self.build_response(response));
+        }
+        let cache_control = if response.is_cacheable() {
+            format!("max-age={}", response.ttl)
+        } else {
+            NO_STORE_CACHE_CONTROL.to_string()
+        };
+        let mut json = match response_to_json(&response.packet) {
+            Ok(json) => json,
+            Err(_) => return http_error(StatusCode::from(DoHError::UpstreamIssue)),
+        };
+        if padding {
+            pad_json(&mut json);
+        }
+        let mut builder = Response::builder()
+            .header(hyper::header::CONTENT_LENGTH, json.len())
+            .header(hyper::header::CONTENT_TYPE, DNS_JSON_CONTENT_TYPE)
+            .header(hyper::header::CACHE_CONTROL, cache_control.as_str());
+        if self.globals.server_timing {
+            builder = builder.header(SERVER_TIMING_HEADER, response.server_timing());
+        }
+        builder.body(Body::from(json))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn parameters_are_percent_decoded() {
+        assert_eq!(percent_decode("a%2Eb%3d"), "a.b=");
+        assert_eq!(percent_decode("a+b%20c"), "a b c");
+        // Invalid or incomplete escapes are kept as is
+        assert_eq!(percent_decode("%zz%4"), "%zz%4");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%ff"), "\u{fffd}");
+        assert_eq!(
+            query_params("name=example.com&&type=%41&do"),
+            vec![
+                ("name".to_string(), "example.com".to_string()),
+                ("type".to_string(), "A".to_string()),
+                ("do".to_string(), String::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn strings_are_escaped() {
+        assert_eq!(json_escape("example.com."), "\"example.com.\"");
+        assert_eq!(json_escape("a\"b\\c"), "\"a\\\"b\\\\c\"");
+        assert_eq!(json_escape("\n\u{1}"), "\"\\u000a\\u0001\"");
+        assert_eq!(json_escape("\u{e9}\u{7f}"), "\"\u{e9}\u{7f}\"");
+    }
+
+    #[test]
+    fn types_are_converted_both_ways() {
+        for &(name, qtype) in DNS_TYPES.iter() {
+            assert_eq!(parse_qtype(name), Some(qtype));
+            assert_eq!(parse_qtype(&name.to_lowercase()), Some(qtype));
+            assert_eq!(qtype_to_string(qtype), name);
+        }
+        assert_eq!(parse_qtype("65535"), Some(65535));
+        assert_eq!(parse_qtype("65536"), None);
+        assert_eq!(parse_qtype("TYPE99"), None);
+        assert_eq!(qtype_to_string(99), "TYPE99");
+    }
+
+    // The data of a record added to a response for example.com, whose question name
+    // is at offset 12, so that names in the data can point to it
+    fn rdata(rtype: u16, rdata: &[u8]) -> Result<String, DnsError> {
+        let query = dns::build_query("example.com", dns::DNS_TYPE_A, false, false).unwrap();
+        let mut packet = dns::response_from_query(&query, dns::DNS_RCODE_NOERROR).unwrap();
+        dns::add_answer_rr(&mut packet, rtype, 300, rdata).unwrap();
+        rdata_to_string(&packet, rtype, packet.len() - rdata.len(), rdata.len())
+    }
+
+    #[test]
+    fn record_data_is_converted_to_text() {
+        assert_eq!(rdata(1, &[192, 0, 2, 1]).unwrap(), "192.0.2.1");
+        assert_eq!(rdata(1, &[192, 0, 2]), Err(DnsError::InvalidARecord));
+        let ipv6 = [0x20, 1, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+        assert_eq!(rdata(28, &ipv6).unwrap(), "2001:db8::1");
+        assert_eq!(rdata(28, &ipv6[..4]), Err(DnsError::InvalidAaaaRecord));
+
+        // Compressed names
+        assert_eq!(rdata(5, b"\x03www\xc0\x0c").unwrap(), "www.example.com.");
+        assert_eq!(rdata(15, b"\x00\x0a\xc0\x0c").unwrap(), "10 example.com.");
+        assert_eq!(rdata(15, b"\x00\x0a"), Err(DnsError::InvalidMxRecord));
+        assert_eq!(
+            rdata(33, b"\x00\x01\x00\x02\x00\x35\xc0\x0c").unwrap(),
+            "1 2 53 example.com."
+        );
+        let mut soa = b"\x02ns\xc0\x0c\x05admin\xc0\x0c".to_vec();
+        for value in &[1u32, 7200, 3600, 1209600, 300] {
+            soa.extend_from_slice(&value.to_be_bytes());
+        }
+        assert_eq!(
+            rdata(6, &soa).unwrap(),
+            "ns.example.com. admin.example.com. 1 7200 3600 1209600 300"
+        );
+        soa.push(0);
+        assert_eq!(rdata(6, &soa), Err(DnsError::InvalidSoaRecord));
+
+        assert_eq!(
+            rdata(16, b"\x05hello\x05a\"b\\c").unwrap(),
+            "\"hello\" \"a\\\"b\\\\c\""
+        );
+        assert_eq!(rdata(16, b"\x05hi"), Err(DnsError::InvalidTxtRecord));
+
+        assert_eq!(rdata(99, &[0xab, 0xcd]).unwrap(), "\\# 2 abcd");
+        assert_eq!(rdata(99, &[]).unwrap(), "\\# 0");
+    }
+
+    #[test]
+    fn json_queries_are_answered() {
+        testing::runtime().block_on(async {
+            let address = testing::udp_upstream(testing::answer).await;
+            let doh = testing::doh(testing::globals(address));
+            let response = testing::get(&doh, "/dns-query?name=example.com&type=a").await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                response.headers()[hyper::header::CONTENT_TYPE],
+                DNS_JSON_CONTENT_TYPE
+            );
+            let json = String::from_utf8(testing::body(response).await).unwrap();
+            assert_eq!(
+                json,
+                "{\"Status\":0,\"TC\":false,\"RD\":true,\"RA\":true,\"AD\":false,\"CD\":false,\
+                 \"Question\":[{\"name\":\"example.com.\",\"type\":1}],\
+                 \"Answer\":[{\"name\":\"example.com.\",\"type\":1,\"TTL\":300,\"data\":\"192.0.2.1\"}]}"
+            );
+
+            let response = testing::get(&doh, "/dns-query?name=example.com&type=BOGUS").await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        });
+    }
+
+    #[test]
+    fn json_queries_can_ask_for_the_wire_format() {
+        testing::runtime().block_on(async {
+            let address = testing::udp_upstream(testing::answer).await;
+            let doh = testing::doh(testing::globals(address));
+            let uri = "/dns-query?name=example.com&ct=application/dns-message";
+            let response = testing::get(&doh, uri).await;
+            assert_eq!(
+                response.headers()[hyper::header::CONTENT_TYPE],
+                DNS_MESSAGE_CONTENT_TYPE
+            );
+            let packet = testing::body(response).await;
+            let address: std::net::IpAddr = "192.0.2.1".parse().unwrap();
+            assert_eq!(dns::answer_addresses(&packet).unwrap(), vec![address]);
+
+            let uri = "/dns-query?name=example.com&ct=application%2Fdns-json";
+            let response = testing::get(&doh, uri).await;
+            assert_eq!(
+                response.headers()[hyper::header::CONTENT_TYPE],
+                DNS_JSON_CONTENT_TYPE
+            );
+
+            let uri = "/dns-query?name=example.com&ct=text/html";
+            let response = testing::get(&doh, uri).await;
+            assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        });
+    }
+
+    #[test]
+    fn json_queries_can_set_the_client_subnet() {
+        testing::runtime().block_on(async {
+            let queries = Arc::new(Mutex::new(vec![]));
+            let received_queries = queries.clone();
+            let address = testing::udp_upstream(move |query| {
+                received_queries.lock().unwrap().push(query.to_vec());
+                testing::answer(query)
+            })
+            .await;
+            let mut globals = testing::globals(address);
+            globals.append_client_subnet = true;
+            let doh = testing::doh(globals);
+            let cases: [(&str, Option<&[u8]>); 4] = [
+                // Derived from the client IP
+                ("", Some(&[0, 1, 24, 0, 192, 0, 2])),
+                ("198.51.100.7/24", Some(&[0, 1, 24, 0, 198, 51, 100])),
+                (
+                    "2001:db8:ffff::/36",
+                    Some(&[0, 2, 36, 0, 0x20, 1, 0x0d, 0xb8, 0xf0]),
+                ),
+                ("0.0.0.0/0", None),
+            ];
+            for &(subnet, client_subnet) in cases.iter() {
+                let uri = format!("/dns-query?name=example.com&edns_client_subnet={}", subnet);
+                let response = testing::get(&doh, &uri).await;
+                assert_eq!(response.status(), StatusCode::OK);
+                let query = queries.lock().unwrap().pop().unwrap();
+                match client_subnet {
+                    Some(client_subnet) => {
+                        let mut option = vec![0, 8, 0, client_subnet.len() as u8];
+                        option.extend_from_slice(client_subnet);
+                        assert!(query.windows(option.len()).any(|w| w == &option[..]));
+                    }
+                    None => assert!(!dns::has_edns_client_subnet(&query).unwrap()),
+                }
+            }
+
+            for subnet in &["192.0.2.0/33", "bogus"] {
+                let uri = format!("/dns-query?name=example.com&edns_client_subnet={}", subnet);
+                let response = testing::get(&doh, &uri).await;
+                assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            }
+        });
+    }
+
+    #[test]
+    fn json_responses_are_padded_on_request() {
+        testing::runtime().block_on(async {
+            let address = testing::udp_upstream(testing::answer).await;
+            let doh = testing::doh(testing::globals(address));
+            let response = testing::get(&doh, "/dns-query?name=example.com").await;
+            let json = testing::body(response).await;
+            assert_ne!(json.len() % JSON_PADDING_BLOCK_SIZE, 0);
+
+            // An empty value is ignored
+            let uri = "/dns-query?name=example.com&random_padding=";
+            let response = testing::get(&doh, uri).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(testing::body(response).await, json);
+
+            let uri = "/dns-query?name=example.com&random_padding=XXXXXXXXXXXXXXXX";
+            let response = testing::get(&doh, uri).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let content_length = response.headers()[hyper::header::CONTENT_LENGTH].clone();
+            let padded_json = testing::body(response).await;
+            assert_eq!(content_length, padded_json.len().to_string().as_str());
+            assert_eq!(padded_json.len(), JSON_PADDING_BLOCK_SIZE);
+            assert!(padded_json.starts_with(&json));
+            assert!(padded_json[json.len()..].iter().all(|&c| c == b' '));
+        });
+    }
+
+    fn get_with_accept(uri: &str, accept: &str) -> Request<Body> {
+        Request::get(uri)
+            .header(hyper::header::ACCEPT, accept)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn queries_with_a_dns_parameter_are_never_json_queries() {
+        let accept = "application/dns-message, application/dns-json;q=0.9";
+        let is_json = |uri, accept| is_json_request(&get_with_accept(uri, accept));
+        assert!(is_json("/dns-query?name=example.com", accept));
+        assert!(!is_json("/dns-query?dns=AAAB", DNS_JSON_CONTENT_TYPE));
+        assert!(!is_json(
+            "/dns-query?dns=AAAB&name=x",
+            DNS_JSON_CONTENT_TYPE
+        ));
+
+        testing::runtime().block_on(async {
+            let address = testing::udp_upstream(testing::answer).await;
+            let doh = testing::doh(testing::globals(address));
+            let query = dns::build_query("example.com", dns::DNS_TYPE_A, false, false).unwrap();
+            let dns = base64::encode_config(&query, base64::URL_SAFE_NO_PAD);
+            let req = get_with_accept(&format!("/dns-query?dns={}", dns), accept);
+            let response = doh.route(req).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                response.headers()[hyper::header::CONTENT_TYPE],
+                DNS_MESSAGE_CONTENT_TYPE
+            );
+            assert_eq!(dns::ancount(&testing::body(response).await), 1);
+        });
+    }
+}
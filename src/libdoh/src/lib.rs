@@ -1,32 +1,88 @@
+mod acl;
+mod blocklist;
+mod cache;
+mod coalesce;
 mod constants;
+mod discovery;
+#[cfg(feature = "tls")]
+mod dot;
 pub mod dns;
 mod errors;
+mod fastopen;
+mod forwarded;
 mod globals;
+mod headers;
+mod health;
+#[cfg(feature = "http3")]
+mod http3;
+mod json;
+mod listener;
+mod malformed;
+mod metrics;
+mod odoh;
+mod overrides;
+mod poison;
+mod qmin;
+mod querylog;
+mod ratelimit;
+mod rebinding;
+mod shutdown;
+mod tcppool;
+#[cfg(test)]
+mod testing;
 #[cfg(feature = "tls")]
 mod tls;
+mod upstream;
+mod utils;
+mod zone;
 
+pub use crate::acl::{AccessList, Network};
+pub use crate::blocklist::Blocklist;
+pub use crate::cache::*;
+pub use crate::coalesce::InflightQueries;
 use crate::constants::*;
 pub use crate::errors::*;
 pub use crate::globals::*;
+pub use crate::headers::ResponseHeader;
+pub use crate::health::HealthCheck;
+pub use crate::malformed::{MalformedPacketLog, PacketSource};
+pub use crate::metrics::*;
+pub use crate::odoh::ODoHKeyPair;
+pub use crate::overrides::Overrides;
+pub use crate::poison::PoisonFilter;
+pub use crate::querylog::{QueryLog, QueryLogFormat};
+pub use crate::ratelimit::*;
+pub use crate::rebinding::RebindingProtection;
+pub use crate::discovery::{resolve_upstreams, UpstreamSource};
+pub use crate::upstream::{BreakerState, Upstream, Upstreams};
+pub use crate::zone::LocalZone;
 
+#[cfg(feature = "http3")]
+pub use crate::http3::create_quic_server_config;
 #[cfg(feature = "tls")]
 use crate::tls::*;
 
+use crate::headers::add_response_headers;
+use crate::querylog::QueryLogEntry;
+use crate::utils::random_u64;
+
 use futures::prelude::*;
 use futures::task::{Context, Poll};
 use hyper::http;
 use hyper::server::conn::Http;
 use hyper::{Body, Method, Request, Response, StatusCode};
+use std::net::{IpAddr, SocketAddr};
 use std::pin::Pin;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::net::{TcpListener, UdpSocket};
+use tokio::net::TcpListener;
 use tokio::runtime;
 
 #[derive(Clone, Debug)]
 pub struct DoH {
     pub globals: Arc<Globals>,
+    pub remote_addr: Option<SocketAddr>,
 }
 
 fn http_error(status_code: StatusCode) -> Result<Response<Body>, http::Error> {
@@ -37,6 +93,113 @@ fn http_error(status_code: StatusCode) -> Result<Response<Body>, http::Error> {
     Ok(response)
 }
 
+fn too_many_requests(retry_after: u64) -> Result<Response<Body>, http::Error> {
+    Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .header(hyper::header::RETRY_AFTER, retry_after)
+        .body(Body::empty())
+}
+
+// A successful response to an AAAA query, without any AAAA records
+fn is_aaaa_nodata(query: &[u8], packet: &[u8]) -> bool {
+    matches!(dns::qtype(query), Ok(dns::DNS_TYPE_AAAA))
+        && dns::rcode(packet) == dns::DNS_RCODE_NOERROR
+        && matches!(dns::answer_addresses(packet), Ok(addresses) if !addresses.iter().any(IpAddr::is_ipv6))
+}
+
+fn bad_request(reason: &'static str) -> Result<Response<Body>, http::Error> {
+    Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .header(hyper::header::CONTENT_TYPE, "text/plain")
+        .body(Body::from(reason))
+}
+
+// Padding is optional in base64url, and some clients percent-encode it
+fn trim_base64_padding(s: &str) -> &str {
+    let mut s = s.trim_end_matches('=');
+    while let Some(end) = s.len().checked_sub(3) {
+        if !s.as_bytes()[end..].eq_ignore_ascii_case(b"%3D") {
+            break;
+        }
+        s = s[..end].trim_end_matches('=');
+    }
+    s
+}
+
+// Durations of the phases of a query, reported in the Server-Timing header
+#[derive(Debug, Default)]
+struct Timings {
+    cache: Option<Duration>,
+    upstream: Option<Duration>,
+    rewrite: Duration,
+}
+
+#[derive(Debug)]
+struct DnsResponse {
+    packet: Vec<u8>,
+    ttl: u32,
+    padding: bool,
+    cache_hit: bool,
+    timings: Timings,
+}
+
+// Hyper drops the request future when the client disconnects, which cancels the
+// upstream queries that no other client is waiting for
+struct PendingRequest<'t> {
+    metrics: &'t Metrics,
+    completed: bool,
+}
+
+impl Drop for PendingRequest<'_> {
+    fn drop(&mut self) {
+        if !self.completed {
+            Metrics::inc(&self.metrics.counters.cancelled_requests_total);
+        }
+    }
+}
+
+impl DnsResponse {
+    fn synthesized(query: &[u8], rcode: u8, ttl: u32, padding: bool) -> Result<Self, DoHError> {
+        let packet = dns::response_from_query(query, rcode).map_err(|_| DoHError::InvalidData)?;
+        Ok(DnsResponse {
+            packet,
+            ttl,
+            padding,
+            cache_hit: false,
+            timings: Timings::default(),
+        })
+    }
+
+    // Truncated and SERVFAIL responses are transient and must not be kept by HTTP caches
+    fn is_cacheable(&self) -> bool {
+        !dns::is_truncated(&self.packet) && dns::rcode(&self.packet) != dns::DNS_RCODE_SERVFAIL
+    }
+
+    fn cache_control(&self) -> String {
+        if !self.is_cacheable() {
+            return NO_STORE_CACHE_CONTROL.to_string();
+        }
+        format!(
+            "max-age={}, stale-if-error={}, stale-while-revalidate={}",
+            self.ttl, STALE_IF_ERROR_SECS, STALE_WHILE_REVALIDATE_SECS
+        )
+    }
+
+    fn server_timing(&self) -> String {
+        let as_ms = |duration: Duration| duration.as_micros() as f64 / 1000.0;
+        let mut metrics = vec![];
+        if let Some(cache) = self.timings.cache {
+            let desc = if self.cache_hit { "hit" } else { "miss" };
+            metrics.push(format!("cache;desc={};dur={:.3}", desc, as_ms(cache)));
+        }
+        if let Some(upstream) = self.timings.upstream {
+            metrics.push(format!("upstream;dur={:.3}", as_ms(upstream)));
+        }
+        metrics.push(format!("rewrite;dur={:.3}", as_ms(self.timings.rewrite)));
+        metrics.join(", ")
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct LocalExecutor {
     runtime_handle: runtime::Handle,
@@ -58,20 +221,80 @@ where
     }
 }
 
+type ResponseFuture = Pin<Box<dyn Future<Output = Result<Response<Body>, http::Error>> + Send>>;
+
 impl hyper::service::Service<http::Request<Body>> for DoH {
     type Response = Response<Body>;
     type Error = http::Error;
-    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+    type Future = ResponseFuture;
 
     fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         Poll::Ready(Ok(()))
     }
 
     fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let response = self.route(req);
+        #[cfg(feature = "http3")]
+        let response: ResponseFuture = if self.globals.enable_http3 {
+            let self_inner = self.clone();
+            Box::pin(response.map_ok(move |response| self_inner.add_alt_svc_header(response)))
+        } else {
+            response
+        };
+        if self.globals.response_headers.is_empty() {
+            return response;
+        }
+        let globals = self.globals.clone();
+        Box::pin(
+            response
+                .map_ok(move |response| add_response_headers(response, &globals.response_headers)),
+        )
+    }
+}
+
+impl DoH {
+    fn route(&self, req: Request<Body>) -> ResponseFuture {
         let globals = &self.globals;
-        if req.uri().path() != globals.path {
+        if globals.enable_metrics
+            && globals.metrics_address.is_none()
+            && req.uri().path() == METRICS_PATH
+        {
+            let response = self.serve_metrics();
+            return Box::pin(async { response });
+        }
+        if req.uri().path() == HEALTH_PATH {
+            let self_inner = self.clone();
+            return Box::pin(async move { self_inner.serve_health().await });
+        }
+        if !self.authority_matches(&req) {
+            return Box::pin(async { http_error(StatusCode::MISDIRECTED_REQUEST) });
+        }
+        let path = req.uri().path();
+        if let Some(odoh_key_pair) = &globals.odoh_key_pair {
+            if path == ODOH_CONFIGS_PATH && req.method() == Method::GET {
+                let response = self.serve_odoh_configs(odoh_key_pair);
+                return Box::pin(async { response });
+            }
+        }
+        if path != globals.path && !globals.path_aliases.iter().any(|alias| alias == path) {
             return Box::pin(async { http_error(StatusCode::NOT_FOUND) });
         }
+        Metrics::inc(&globals.metrics.counters.queries_total);
+        if globals.access_list.is_enabled() {
+            let allowed = self
+                .client_ip(&req)
+                .is_some_and(|client_ip| globals.access_list.contains(client_ip));
+            if !allowed {
+                return Box::pin(async { http_error(StatusCode::FORBIDDEN) });
+            }
+        }
+        if globals.rate_limiter.is_enabled() {
+            if let Some(client_ip) = self.client_ip(&req) {
+                if let Err(retry_after) = globals.rate_limiter.check(client_ip) {
+                    return Box::pin(async move { too_many_requests(retry_after) });
+                }
+            }
+        }
         let self_inner = self.clone();
         match *req.method() {
             Method::POST => Box::pin(async move { self_inner.serve_post(req).await }),
@@ -79,44 +302,135 @@ impl hyper::service::Service<http::Request<Body>> for DoH {
             _ => Box::pin(async { http_error(StatusCode::METHOD_NOT_ALLOWED) }),
         }
     }
-}
 
-impl DoH {
+    // The :authority pseudo-header with HTTP/2, the Host header otherwise
+    fn authority_matches(&self, req: &Request<Body>) -> bool {
+        let hostname = match &self.globals.hostname {
+            Some(hostname) => hostname,
+            None => return true,
+        };
+        let authority = match req.uri().authority() {
+            Some(authority) => Some(authority.clone()),
+            None => req
+                .headers()
+                .get(hyper::header::HOST)
+                .and_then(|host| host.to_str().ok())
+                .and_then(|host| host.parse::<http::uri::Authority>().ok()),
+        };
+        authority.is_some_and(|authority| {
+            authority
+                .host()
+                .trim_end_matches('.')
+                .eq_ignore_ascii_case(hostname.trim_end_matches('.'))
+        })
+    }
+
+    pub(crate) fn client_ip(&self, req: &Request<Body>) -> Option<IpAddr> {
+        if self.globals.trusted_proxies.is_enabled() {
+            return self.remote_addr.map(|remote_addr| {
+                forwarded::real_client_ip(
+                    remote_addr.ip(),
+                    req.headers(),
+                    &self.globals.trusted_proxies,
+                )
+            });
+        }
+        if let Some(client_ip_header) = &self.globals.client_ip_header {
+            return req
+                .headers()
+                .get(client_ip_header.as_str())
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.rsplit(',').next())
+                .and_then(|ip| ip.trim().parse().ok());
+        }
+        self.remote_addr.map(|remote_addr| remote_addr.ip())
+    }
+
     async fn serve_post(&self, req: Request<Body>) -> Result<Response<Body>, http::Error> {
         if self.globals.disable_post {
             return http_error(StatusCode::METHOD_NOT_ALLOWED);
         }
+        if let Some(odoh_key_pair) = &self.globals.odoh_key_pair {
+            if odoh::is_odoh_request(&req) {
+                return self.serve_odoh_post(req, odoh_key_pair).await;
+            }
+        }
         if let Err(response) = Self::check_content_type(&req) {
             return Ok(response);
         }
-        match self.read_body_and_proxy(req.into_body()).await {
+        let client_ip = self.client_ip(&req);
+        match self.read_body_and_proxy(req.into_body(), client_ip).await {
             Err(e) => http_error(StatusCode::from(e)),
             Ok(res) => Ok(res),
         }
     }
 
     async fn serve_get(&self, req: Request<Body>) -> Result<Response<Body>, http::Error> {
+        // Some clients probe connectivity with a GET request without any parameters
+        if self.globals.allow_ping && req.uri().query().unwrap_or("").is_empty() {
+            return Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(Body::empty());
+        }
+        if json::is_json_request(&req) {
+            return self.serve_json(req).await;
+        }
         let query = req.uri().query().unwrap_or("");
         let mut question_str = None;
+        let mut content_type = None;
         for parts in query.split('&') {
-            let mut kv = parts.split('=');
-            if let Some(k) = kv.next() {
-                if k == DNS_QUERY_PARAM {
-                    question_str = kv.next();
-                }
+            let mut kv = parts.splitn(2, '=');
+            match kv.next() {
+                Some(DNS_QUERY_PARAM) => question_str = kv.next(),
+                Some(DNS_CONTENT_TYPE_PARAM) => content_type = kv.next().map(json::percent_decode),
+                _ => {}
             }
         }
-        let question = match question_str.and_then(|question_str| {
-            base64::decode_config(question_str, base64::URL_SAFE_NO_PAD).ok()
-        }) {
-            Some(question) => question,
-            _ => {
-                return http_error(StatusCode::BAD_REQUEST);
-            }
+        // Older drafts let clients pick the response type with a `ct` parameter
+        let content_type = match content_type.as_deref() {
+            None | Some("") | Some(DNS_MESSAGE_CONTENT_TYPE) => DNS_MESSAGE_CONTENT_TYPE,
+            Some(DNS_UDPWIREFORMAT_CONTENT_TYPE) => DNS_UDPWIREFORMAT_CONTENT_TYPE,
+            Some(_) => return http_error(StatusCode::UNSUPPORTED_MEDIA_TYPE),
+        };
+        let question_str = match question_str {
+            Some(question_str) => trim_base64_padding(question_str),
+            None => return bad_request("Missing dns parameter"),
         };
-        match self.proxy(question).await {
+        let uppercase_question_str = question_str.to_ascii_uppercase();
+        if question_str.contains(['+', '/'])
+            || uppercase_question_str.contains("%2B")
+            || uppercase_question_str.contains("%2F")
+        {
+            return bad_request("The dns parameter must be encoded using base64url, not base64");
+        }
+        if question_str.contains('%') {
+            return bad_request("The dns parameter must not be percent-encoded");
+        }
+        if question_str.len() / 4 * 3 > self.globals.max_request_size {
+            return http_error(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        let question = match base64::decode_config(question_str, base64::URL_SAFE_NO_PAD) {
+            Ok(question) => question,
+            Err(_) => return bad_request("Invalid base64url encoding"),
+        };
+        if question.len() < dns::DNS_HEADER_SIZE {
+            return bad_request("DNS message too short");
+        }
+        if question.len() > self.globals.max_client_packet_size {
+            return http_error(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        if dns::qdcount(&question) != 1 {
+            return bad_request("DNS message must contain exactly one question");
+        }
+        match self.proxy(question, self.client_ip(&req)).await {
             Err(e) => http_error(StatusCode::from(e)),
-            Ok(res) => Ok(res),
+            Ok(mut res) => {
+                res.headers_mut().insert(
+                    hyper::header::CONTENT_TYPE,
+                    hyper::header::HeaderValue::from_static(content_type),
+                );
+                Ok(res)
+            }
         }
     }
 
@@ -142,7 +456,7 @@ impl DoH {
             }
             Ok(content_type) => content_type.to_lowercase(),
         };
-        if content_type != "application/dns-message" {
+        if content_type != DNS_MESSAGE_CONTENT_TYPE {
             let response = Response::builder()
                 .status(StatusCode::UNSUPPORTED_MEDIA_TYPE)
                 .body(Body::empty())
@@ -152,81 +466,673 @@ impl DoH {
         Ok(())
     }
 
-    async fn read_body_and_proxy(&self, mut body: Body) -> Result<Response<Body>, DoHError> {
-        let mut sum_size = 0;
+    // Clients trickling the body are cut off once the deadline expires, or as soon as
+    // their throughput drops below the minimum rate
+    async fn read_body(&self, mut body: Body, max_size: usize) -> Result<Vec<u8>, DoHError> {
+        let globals = &self.globals;
+        let start = Instant::now();
         let mut query = vec![];
         while let Some(chunk) = body.next().await {
             let chunk = chunk.map_err(|_| DoHError::TooLarge)?;
-            sum_size += chunk.len();
-            if sum_size >= MAX_DNS_QUESTION_LEN {
+            if query.len() + chunk.len() > max_size {
                 return Err(DoHError::TooLarge);
             }
             query.extend(chunk);
+            let elapsed = start.elapsed();
+            if globals.body_min_rate > 0
+                && elapsed > Duration::from_millis(BODY_MIN_RATE_GRACE_MS)
+                && (query.len() as f64) < globals.body_min_rate as f64 * elapsed.as_secs_f64()
+            {
+                return Err(DoHError::RequestTimeout);
+            }
         }
-        let response = self.proxy(query).await?;
+        Ok(query)
+    }
+
+    async fn read_body_and_proxy(
+        &self,
+        body: Body,
+        client_ip: Option<IpAddr>,
+    ) -> Result<Response<Body>, DoHError> {
+        let globals = &self.globals;
+        let max_size = globals.max_request_size.min(globals.max_client_packet_size);
+        let query = tokio::time::timeout(globals.body_read_timeout, self.read_body(body, max_size))
+            .await
+            .map_err(|_| DoHError::RequestTimeout)??;
+        if query.len() < dns::DNS_HEADER_SIZE {
+            self.globals.malformed_packet_log.log(
+                PacketSource::Client(client_ip),
+                DnsError::ShortPacket,
+                &query,
+            );
+            return Err(DoHError::InvalidData);
+        }
+        let response = self.proxy(query, client_ip).await?;
         Ok(response)
     }
 
-    async fn proxy(&self, query: Vec<u8>) -> Result<Response<Body>, DoHError> {
-        let proxy_timeout = self.globals.timeout;
-        let timeout_res = tokio::time::timeout(proxy_timeout, self._proxy(query)).await;
-        timeout_res.map_err(|_| DoHError::UpstreamTimeout)?
+    async fn proxy(
+        &self,
+        query: Vec<u8>,
+        client_ip: Option<IpAddr>,
+    ) -> Result<Response<Body>, DoHError> {
+        let response = self.proxy_response(query, client_ip).await?;
+        Ok(self.build_response(response))
     }
 
-    async fn _proxy(&self, mut query: Vec<u8>) -> Result<Response<Body>, DoHError> {
+    async fn proxy_response(
+        &self,
+        query: Vec<u8>,
+        client_ip: Option<IpAddr>,
+    ) -> Result<DnsResponse, DoHError> {
+        if let Err(err) = dns::validate_packet(&query) {
+            self.globals.metrics.observe_invalid_query(err);
+            self.globals
+                .malformed_packet_log
+                .log(PacketSource::Client(client_ip), err, &query);
+            return Err(DoHError::InvalidData);
+        }
+        let client_subnet = self.derived_client_subnet(&query, client_ip);
+        self.resolve(query, client_ip, client_subnet).await
+    }
+
+    // The client subnet sent upstream with `append_client_subnet`. Clients that sent
+    // their own ECS option, including a /0 opt-out, don't get one: their option is
+    // never forwarded, and no other subnet is sent in its place.
+    pub(crate) fn derived_client_subnet(
+        &self,
+        query: &[u8],
+        client_ip: Option<IpAddr>,
+    ) -> Option<Network> {
+        let globals = &self.globals;
+        if !globals.append_client_subnet || dns::has_edns_client_subnet(query).unwrap_or(true) {
+            return None;
+        }
+        match client_ip? {
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => Some(Network::new(
+                    IpAddr::V4(ip),
+                    globals.client_subnet_prefix_v4,
+                )),
+                None => Some(Network::new(
+                    IpAddr::V6(ip),
+                    globals.client_subnet_prefix_v6,
+                )),
+            },
+            ip => Some(Network::new(ip, globals.client_subnet_prefix_v4)),
+        }
+    }
+
+    async fn resolve(
+        &self,
+        query: Vec<u8>,
+        client_ip: Option<IpAddr>,
+        client_subnet: Option<Network>,
+    ) -> Result<DnsResponse, DoHError> {
+        let globals = &self.globals;
+        let start = Instant::now();
+        if globals.debug_packets {
+            eprintln!("Query from {:?}:\n{}", client_ip, dns::describe(&query));
+        }
+        let requests_nsid = globals.nsid.is_some() && dns::requests_nsid(&query).unwrap_or(false);
+        // Read before the query is rewritten for upstream servers
+        let client_edns_size = dns::client_edns_size(&query);
+        let logged_query = if globals.query_log.is_enabled() {
+            Some(query.clone())
+        } else {
+            None
+        };
+        let mut pending_request = PendingRequest {
+            metrics: &globals.metrics,
+            completed: false,
+        };
+        let response = self._resolve_with_timeout(query, client_subnet).await;
+        pending_request.completed = true;
+        let elapsed = start.elapsed();
+        if let Some(logged_query) = logged_query {
+            let response = response.as_ref().ok();
+            globals.query_log.log(QueryLogEntry {
+                client_ip,
+                query: &logged_query,
+                response: response.map(|response| response.packet.as_slice()),
+                cache_hit: response.is_some_and(|response| response.cache_hit),
+                elapsed,
+            });
+        }
+        let mut response = response?;
+        let rewrite_start = Instant::now();
+        if globals.minimize_responses {
+            let _ = dns::strip_additional_records(&mut response.packet);
+        }
+        if let (true, Some(nsid)) = (requests_nsid, &globals.nsid) {
+            let _ = dns::set_edns_nsid(&mut response.packet, nsid.as_bytes());
+        }
+        if client_edns_size.is_some() {
+            let _ = dns::set_edns_max_payload_size(&mut response.packet, globals.udp_edns_size);
+        }
+        self.limit_response_size(&mut response.packet, client_edns_size);
+        response.timings.rewrite += rewrite_start.elapsed();
+        if globals.debug_packets {
+            eprintln!("Response:\n{}", dns::describe(&response.packet));
+        }
+        globals.metrics.observe_response(&response.packet, elapsed);
+        Ok(response)
+    }
+
+    // Only the response sent to the client is limited; cached responses are complete.
+    // The limit is lowered to the payload size advertised by the client, if any.
+    fn limit_response_size(&self, packet: &mut Vec<u8>, client_edns_size: Option<u16>) {
+        let globals = &self.globals;
+        if globals.max_response_size == 0 {
+            return;
+        }
+        let max_response_size = match client_edns_size {
+            Some(client_edns_size) => globals
+                .max_response_size
+                .min(client_edns_size.min(globals.udp_edns_size) as usize),
+            None => globals.max_response_size,
+        };
+        if packet.len() <= max_response_size {
+            return;
+        }
+        let _ = dns::strip_additional_records(packet);
+        if packet.len() > max_response_size {
+            let _ = dns::truncate(packet);
+        }
+    }
+
+    async fn _resolve_with_timeout(
+        &self,
+        query: Vec<u8>,
+        client_subnet: Option<Network>,
+    ) -> Result<DnsResponse, DoHError> {
+        let globals = &self.globals;
+        let original_query = query.clone();
+        let mut timings = Timings::default();
+        let timeout_res = tokio::time::timeout(
+            globals.timeout,
+            self._resolve(query, client_subnet, &mut timings),
+        )
+        .await;
+        let res = match timeout_res {
+            Ok(res) => res,
+            Err(_) if globals.cache.serves_stale() => self
+                .stale_response(&original_query)
+                .ok_or(DoHError::UpstreamTimeout),
+            Err(_) => Err(DoHError::UpstreamTimeout),
+        };
+        // Upstream failures are reported to the client as a SERVFAIL response rather than
+        // an HTTP error; only malformed queries and overload get an HTTP error status
+        let res = match res {
+            Err(DoHError::UpstreamTimeout)
+            | Err(DoHError::UpstreamIssue)
+            | Err(DoHError::Io(_)) => {
+                let client_edns = matches!(dns::find_edns_section(&original_query), Ok(Some(_)));
+                DnsResponse::synthesized(
+                    &original_query,
+                    dns::DNS_RCODE_SERVFAIL,
+                    globals.failure_ttls.servfail,
+                    client_edns,
+                )
+            }
+            res => res,
+        };
+        res.map(|response| DnsResponse {
+            timings,
+            ..response
+        })
+    }
+
+    fn stale_response(&self, query: &[u8]) -> Option<DnsResponse> {
+        let cache_key = dns::cache_key(query).ok()?;
+        let packet = self.globals.cache.get_stale(&cache_key)?;
+        Some(self.cached_response(query, packet, STALE_RESPONSE_TTL))
+    }
+
+    // Shortens the remaining TTLs by a random amount, so that copies of a response
+    // cached downstream at the same time don't all expire at the same time
+    fn jitter_ttls(&self, mut packet: Vec<u8>, ttl: u32) -> (Vec<u8>, u32) {
+        let globals = &self.globals;
+        let max_reduction = ttl as u64 * globals.ttl_jitter_pct as u64 / 100;
+        if max_reduction == 0 {
+            return (packet, ttl);
+        }
+        let reduction = (random_u64() % (max_reduction + 1)) as u32;
+        if dns::reduce_ttls(&mut packet, reduction, globals.min_ttl).is_err() {
+            return (packet, ttl);
+        }
+        (packet, dns::reduce_ttl(ttl, reduction, globals.min_ttl))
+    }
+
+    fn cached_response(&self, query: &[u8], mut packet: Vec<u8>, ttl: u32) -> DnsResponse {
+        let client_edns = matches!(dns::find_edns_section(query), Ok(Some(_)));
+        dns::set_tid(&mut packet, dns::tid(query));
+        let _ = dns::set_qname_case(&mut packet, query);
+        if !client_edns {
+            let _ = dns::remove_opt_rr(&mut packet);
+        }
+        DnsResponse {
+            packet,
+            ttl,
+            padding: client_edns,
+            cache_hit: true,
+            timings: Timings::default(),
+        }
+    }
+
+    async fn _resolve(
+        &self,
+        mut query: Vec<u8>,
+        client_subnet: Option<Network>,
+        timings: &mut Timings,
+    ) -> Result<DnsResponse, DoHError> {
         if query.len() < MIN_DNS_PACKET_LEN {
             return Err(DoHError::Incomplete);
         }
-        let _ = dns::set_edns_max_payload_size(&mut query, MAX_DNS_RESPONSE_LEN as _);
         let globals = &self.globals;
-        let mut socket = UdpSocket::bind(&globals.local_bind_address)
-            .await
-            .map_err(DoHError::Io)?;
-        let expected_server_address = globals.server_address;
         let (min_ttl, max_ttl, err_ttl) = (globals.min_ttl, globals.max_ttl, globals.err_ttl);
-        socket
-            .send_to(&query, &globals.server_address)
-            .map_err(DoHError::Io)
-            .await?;
-        let mut packet = vec![0; MAX_DNS_RESPONSE_LEN];
-        let (len, response_server_address) =
-            socket.recv_from(&mut packet).map_err(DoHError::Io).await?;
-        if len < MIN_DNS_PACKET_LEN || expected_server_address != response_server_address {
-            return Err(DoHError::UpstreamIssue);
-        }
-        packet.truncate(len);
+        let client_edns = matches!(dns::find_edns_section(&query), Ok(Some(_)));
+        // UPDATE, NOTIFY and other opcodes have no business being forwarded to a resolver
+        if !globals.allow_all_opcodes && dns::opcode(&query) != dns::DNS_OPCODE_QUERY {
+            return DnsResponse::synthesized(&query, dns::DNS_RCODE_NOTIMP, err_ttl, client_edns);
+        }
+        if globals.refuse_any && matches!(dns::qtype(&query), Ok(dns::DNS_TYPE_ANY)) {
+            return DnsResponse::synthesized(&query, dns::DNS_RCODE_REFUSED, err_ttl, client_edns);
+        }
+        if let Some(response) = self.local_response(&query, client_edns)? {
+            return Ok(response);
+        }
+        // Responses tailored to a client subnet are neither cached nor shared
+        let cache_key = if globals.cache.is_enabled() && client_subnet.is_none() {
+            dns::cache_key(&query).ok()
+        } else {
+            None
+        };
+        if let Some(cache_key) = &cache_key {
+            let cache_start = Instant::now();
+            let cached = globals.cache.get(cache_key);
+            timings.cache = Some(cache_start.elapsed());
+            if let Some(cached) = cached {
+                Metrics::inc(&globals.metrics.counters.cache_hits_total);
+                if cached.prefetch {
+                    self.prefetch(query.clone(), cache_key.clone());
+                }
+                let (packet, ttl) = self.jitter_ttls(cached.packet, cached.ttl);
+                return Ok(self.cached_response(&query, packet, ttl));
+            }
+            Metrics::inc(&globals.metrics.counters.cache_misses_total);
+        }
+        let _inflight_query = match globals.inflight_queries.try_acquire(globals.max_clients) {
+            Some(inflight_query) => inflight_query,
+            None if globals.overload_servfail => {
+                return DnsResponse::synthesized(&query, dns::DNS_RCODE_SERVFAIL, 0, client_edns);
+            }
+            None => return Err(DoHError::Overloaded),
+        };
+        self.prepare_query(&mut query);
+        let upstream_start = Instant::now();
+        let res = match client_subnet {
+            Some(client_subnet) => {
+                dns::add_edns_client_subnet(
+                    &mut query,
+                    client_subnet.address(),
+                    client_subnet.prefix_len(),
+                )
+                .map_err(|_| DoHError::TooLarge)?;
+                self.resolve_upstream(&query).await
+            }
+            None => self.resolve_coalesced(&query).await,
+        };
+        timings.upstream = Some(upstream_start.elapsed());
+        let mut packet = match res {
+            Ok(packet) => packet,
+            Err(DoHError::UpstreamTimeout)
+            | Err(DoHError::UpstreamIssue)
+            | Err(DoHError::Io(_)) => {
+                if let Some(response) = self.stale_response(&query) {
+                    return Ok(response);
+                }
+                let mut response = DnsResponse::synthesized(
+                    &query,
+                    dns::DNS_RCODE_SERVFAIL,
+                    globals.failure_ttls.servfail,
+                    client_edns,
+                )?;
+                if client_edns {
+                    let _ = dns::set_extended_error(
+                        &mut response.packet,
+                        globals.unreachable_ede_code,
+                        "",
+                    );
+                }
+                return Ok(response);
+            }
+            Err(e) => return Err(e),
+        };
+        if globals.synthesize_aaaa && is_aaaa_nodata(&query, &packet) {
+            if let Some(synthesized) = self.dns64_response(&query).await {
+                packet = synthesized;
+            }
+        }
+        timings.upstream = Some(upstream_start.elapsed());
+        let rewrite_start = Instant::now();
         let ttl = if dns::is_recoverable_error(&packet) {
+            if let Some(response) = self.stale_response(&query) {
+                return Ok(response);
+            }
+            globals.failure_ttls.for_rcode(dns::rcode(&packet))
+        } else if globals.rebinding_protection.rejects(&packet) {
+            packet = self.rebinding_response(&query, client_edns)?;
             err_ttl
         } else {
-            match dns::min_ttl(&packet, min_ttl, max_ttl, err_ttl) {
-                Err(_) => return Err(DoHError::UpstreamIssue),
+            match dns::min_ttl_by_rcode(&packet, min_ttl, max_ttl, &globals.failure_ttls) {
+                Err(e) => {
+                    if globals.debug_packets {
+                        eprintln!(
+                            "Invalid upstream response ({}):\n{}",
+                            e,
+                            dns::describe(&packet)
+                        );
+                    }
+                    return Err(DoHError::UpstreamIssue);
+                }
                 Ok(ttl) => ttl,
             }
         };
-        dns::add_edns_padding(&mut packet)
+        if !dns::dnssec_ok(&query).unwrap_or(false) {
+            let _ = dns::strip_dnssec_records(&mut packet);
+        }
+        if let Some(cache_key) = cache_key {
+            if !dns::is_recoverable_error(&packet) {
+                let cache_ttl = dns::cache_ttl(ttl, globals.max_cache_ttl);
+                globals.cache.insert(cache_key, packet.clone(), cache_ttl);
+            }
+        }
+        if !client_edns {
+            let _ = dns::remove_opt_rr(&mut packet);
+        }
+        timings.rewrite = rewrite_start.elapsed();
+        Ok(DnsResponse {
+            packet,
+            ttl,
+            padding: client_edns,
+            cache_hit: false,
+            timings: Timings::default(),
+        })
+    }
+
+    // DNS64 (RFC 6147): names without AAAA records get AAAA records made of the
+    // NAT64 prefix followed by their IPv4 addresses
+    async fn dns64_response(&self, query: &[u8]) -> Option<Vec<u8>> {
+        let globals = &self.globals;
+        let a_query = dns::with_qtype(query, dns::DNS_TYPE_A).ok()?;
+        let a_packet = self.resolve_coalesced(&a_query).await.ok()?;
+        let ttl =
+            dns::min_ttl(&a_packet, globals.min_ttl, globals.max_ttl, globals.err_ttl).ok()?;
+        let mut packet = dns::response_from_query(query, dns::DNS_RCODE_NOERROR).ok()?;
+        for address in dns::answer_addresses(&a_packet).ok()? {
+            if let IpAddr::V4(ip) = address {
+                let mut octets = globals.dns64_prefix.octets();
+                octets[12..].copy_from_slice(&ip.octets());
+                dns::add_answer_rr(&mut packet, dns::DNS_TYPE_AAAA, ttl, &octets).ok()?;
+            }
+        }
+        if dns::ancount(&packet) == 0 {
+            return None;
+        }
+        Some(packet)
+    }
+
+    fn rebinding_response(&self, query: &[u8], client_edns: bool) -> Result<Vec<u8>, DoHError> {
+        let mut packet = dns::response_from_query(query, dns::DNS_RCODE_NXDOMAIN)
+            .map_err(|_| DoHError::InvalidData)?;
+        if client_edns {
+            let _ = dns::set_extended_error(&mut packet, dns::DNS_EDE_BLOCKED, "");
+        }
+        Ok(packet)
+    }
+
+    fn local_response(
+        &self,
+        query: &[u8],
+        client_edns: bool,
+    ) -> Result<Option<DnsResponse>, DoHError> {
+        let globals = &self.globals;
+        let name = match dns::normalized_qname(query) {
+            Ok(qname) => dns::name_to_string(&qname),
+            Err(_) => return Ok(None),
+        };
+        if dns::qclass(query).map_err(|_| DoHError::InvalidData)? == dns::DNS_CLASS_CHAOS {
+            return self.chaos_response(query, &name, client_edns).map(Some);
+        }
+        let allowed = globals.allowlist.is_enabled() && globals.allowlist.matches(&name);
+        if globals.default_deny && !allowed {
+            let mut response = DnsResponse::synthesized(
+                query,
+                dns::DNS_RCODE_REFUSED,
+                globals.failure_ttls.refused,
+                client_edns,
+            )?;
+            if client_edns {
+                let _ = dns::set_extended_error(&mut response.packet, dns::DNS_EDE_PROHIBITED, "");
+            }
+            return Ok(Some(response));
+        }
+        if !allowed && globals.blocklist.is_enabled() && globals.blocklist.matches(&name) {
+            let rcode = if globals.blocklist.nxdomain() {
+                dns::DNS_RCODE_NXDOMAIN
+            } else {
+                dns::DNS_RCODE_NOERROR
+            };
+            let mut response =
+                DnsResponse::synthesized(query, rcode, globals.err_ttl, client_edns)?;
+            if client_edns {
+                let _ = dns::set_extended_error(&mut response.packet, dns::DNS_EDE_FILTERED, "");
+            }
+            return Ok(Some(response));
+        }
+        if globals.local_zone.is_enabled() {
+            if let Some(response) = self.local_zone_response(query, &name, client_edns)? {
+                return Ok(Some(response));
+            }
+        }
+        if !globals.overrides.is_enabled() {
+            return Ok(None);
+        }
+        let qtype = dns::qtype(query).map_err(|_| DoHError::InvalidData)?;
+        let rdatas = match globals.overrides.lookup(&name, qtype) {
+            Some(rdatas) => rdatas,
+            None => return Ok(None),
+        };
+        let ttl = globals.overrides.ttl();
+        let mut response =
+            DnsResponse::synthesized(query, dns::DNS_RCODE_NOERROR, ttl, client_edns)?;
+        for rdata in rdatas {
+            dns::add_answer_rr(&mut response.packet, qtype, ttl, rdata)
+                .map_err(|_| DoHError::TooLarge)?;
+        }
+        Ok(Some(response))
+    }
+
+    fn local_zone_response(
+        &self,
+        query: &[u8],
+        name: &str,
+        client_edns: bool,
+    ) -> Result<Option<DnsResponse>, DoHError> {
+        let qtype = dns::qtype(query).map_err(|_| DoHError::InvalidData)?;
+        let answers = match self.globals.local_zone.answer(name, qtype) {
+            Some(answers) => answers,
+            None => return Ok(None),
+        };
+        let ttl = answers.iter().map(|answer| answer.ttl).min().unwrap_or(0);
+        let mut response =
+            DnsResponse::synthesized(query, dns::DNS_RCODE_NOERROR, ttl, client_edns)?;
+        // The first records are owned by the name in the question, whose case is preserved
+        let qname_owner = answers[0].owner;
+        for answer in answers {
+            if answer.owner == qname_owner {
+                dns::add_answer_rr(&mut response.packet, answer.rtype, answer.ttl, answer.rdata)
+            } else {
+                let owner = dns::encode_name(answer.owner).map_err(|_| DoHError::InvalidData)?;
+                dns::add_answer_rr_for_owner(
+                    &mut response.packet,
+                    &owner,
+                    answer.rtype,
+                    answer.ttl,
+                    answer.rdata,
+                )
+            }
+            .map_err(|_| DoHError::TooLarge)?;
+        }
+        Ok(Some(response))
+    }
+
+    // CHAOS queries are never forwarded: the server's own identity is returned if
+    // configured, and the query is refused otherwise
+    fn chaos_response(
+        &self,
+        query: &[u8],
+        name: &str,
+        client_edns: bool,
+    ) -> Result<DnsResponse, DoHError> {
+        let globals = &self.globals;
+        let qtype = dns::qtype(query).map_err(|_| DoHError::InvalidData)?;
+        let text = if qtype == dns::DNS_TYPE_TXT || qtype == dns::DNS_TYPE_ANY {
+            match name.trim_end_matches('.').to_ascii_lowercase().as_str() {
+                "version.bind" => globals.chaos_version.as_ref(),
+                "hostname.bind" => globals.chaos_hostname.as_ref(),
+                _ => None,
+            }
+        } else {
+            None
+        };
+        let text = match text {
+            Some(text) => text,
+            None => {
+                return DnsResponse::synthesized(
+                    query,
+                    dns::DNS_RCODE_REFUSED,
+                    globals.err_ttl,
+                    client_edns,
+                )
+            }
+        };
+        let mut response = DnsResponse::synthesized(query, dns::DNS_RCODE_NOERROR, 0, client_edns)?;
+        dns::add_txt_answer(&mut response.packet, dns::DNS_CLASS_CHAOS, 0, text)
+            .map_err(|_| DoHError::TooLarge)?;
+        Ok(response)
+    }
+
+    fn prepare_query(&self, query: &mut Vec<u8>) {
+        let _ = dns::strip_edns_client_subnet(query);
+        let _ = dns::set_edns_max_payload_size(query, self.globals.udp_edns_size);
+    }
+
+    fn prefetch(&self, query: Vec<u8>, cache_key: Vec<u8>) {
+        let self_inner = self.clone();
+        self.globals.runtime_handle.spawn(async move {
+            let globals = &self_inner.globals;
+            match self_inner.prefetch_response(query).await {
+                Some((packet, cache_ttl)) => globals.cache.insert(cache_key, packet, cache_ttl),
+                // The entry can be prefetched again on a later hit
+                None => globals.cache.cancel_prefetch(&cache_key),
+            }
+        });
+    }
+
+    async fn prefetch_response(&self, mut query: Vec<u8>) -> Option<(Vec<u8>, u32)> {
+        let globals = &self.globals;
+        self.prepare_query(&mut query);
+        let mut packet = tokio::time::timeout(globals.timeout, self.upstream_query(&query))
+            .await
+            .ok()?
+            .ok()?;
+        if dns::is_recoverable_error(&packet) || globals.rebinding_protection.rejects(&packet) {
+            return None;
+        }
+        if !dns::dnssec_ok(&query).unwrap_or(false) {
+            let _ = dns::strip_dnssec_records(&mut packet);
+        }
+        let ttl = dns::min_ttl_by_rcode(
+            &packet,
+            globals.min_ttl,
+            globals.max_ttl,
+            &globals.failure_ttls,
+        )
+        .ok()?;
+        // Responses that can't be cached wouldn't replace the entry
+        match dns::cache_ttl(ttl, globals.max_cache_ttl) {
+            0 => None,
+            cache_ttl => Some((packet, cache_ttl)),
+        }
+    }
+
+    // Warms the cache up with the responses saved by the previous instance
+    fn load_cache(&self) {
+        let globals = &self.globals;
+        let path = match &globals.cache_persist_path {
+            Some(path) if globals.cache.is_enabled() && path.exists() => path,
+            _ => return,
+        };
+        match globals.cache.load(path) {
+            Ok(count) => println!("Loaded {} cached responses from {}", count, path.display()),
+            Err(e) => eprintln!("Unable to load the cache from {}: {}", path.display(), e),
+        }
+    }
+
+    fn save_cache(&self) {
+        let globals = &self.globals;
+        let path = match &globals.cache_persist_path {
+            Some(path) if globals.cache.is_enabled() => path,
+            _ => return,
+        };
+        match globals.cache.save(path) {
+            Ok(count) => println!("Saved {} cached responses to {}", count, path.display()),
+            Err(e) => eprintln!("Unable to save the cache to {}: {}", path.display(), e),
+        }
+    }
+
+    fn pad_response(&self, response: &mut DnsResponse) {
+        let globals = &self.globals;
+        let rewrite_start = Instant::now();
+        if response.padding {
+            let packet = &mut response.packet;
+            if globals.padding_block_size > 0 {
+                dns::set_edns_padding(packet, globals.padding_block_size)
+            } else {
+                dns::add_edns_padding(packet)
+            }
             .map_err(|_| DoHError::TooLarge)
             .ok();
-        let packet_len = packet.len();
-        let response = Response::builder()
+        }
+        response.timings.rewrite += rewrite_start.elapsed();
+    }
+
+    fn build_response(&self, mut response: DnsResponse) -> Response<Body> {
+        let globals = &self.globals;
+        let cache_control = response.cache_control();
+        self.pad_response(&mut response);
+        let packet_len = response.packet.len();
+        let mut builder = Response::builder()
             .header(hyper::header::CONTENT_LENGTH, packet_len)
-            .header(hyper::header::CONTENT_TYPE, "application/dns-message")
-            .header(
-                hyper::header::CACHE_CONTROL,
-                format!(
-                    "max-age={}, stale-if-error={}, stale-while-revalidate={}",
-                    ttl, STALE_IF_ERROR_SECS, STALE_WHILE_REVALIDATE_SECS
-                )
-                .as_str(),
-            )
-            .body(Body::from(packet))
-            .unwrap();
-        Ok(response)
+            .header(hyper::header::CONTENT_TYPE, DNS_MESSAGE_CONTENT_TYPE)
+            .header(hyper::header::CACHE_CONTROL, cache_control.as_str());
+        if globals.server_timing {
+            builder = builder.header(SERVER_TIMING_HEADER, response.server_timing());
+        }
+        builder.body(Body::from(response.packet)).unwrap()
     }
 
-    async fn client_serve<I>(self, stream: I, server: Http<LocalExecutor>)
-    where
+    async fn client_serve<I>(
+        mut self,
+        stream: I,
+        remote_addr: Option<SocketAddr>,
+        server: Http<LocalExecutor>,
+    ) where
         I: AsyncRead + AsyncWrite + Send + Unpin + 'static,
     {
+        self.remote_addr = remote_addr;
         let clients_count = self.globals.clients_count.clone();
         if clients_count.increment() > self.globals.max_clients {
             clients_count.decrement();
@@ -254,7 +1160,10 @@ impl DoH {
                     Ok(stream) => stream,
                     Err(_) => continue,
                 };
-                self.clone().client_serve(stream, server.clone()).await;
+                let remote_addr = stream.peer_addr().ok();
+                self.clone()
+                    .client_serve(stream, remote_addr, server.clone())
+                    .await;
             }
             Ok(()) as Result<(), DoHError>
         };
@@ -264,8 +1173,7 @@ impl DoH {
 
     pub async fn entrypoint(self) -> Result<(), DoHError> {
         let listen_address = self.globals.listen_address;
-        let listener = TcpListener::bind(&listen_address)
-            .await
+        let listener = listener::bind_tcp_listener(listen_address, self.globals.reuse_port)
             .map_err(DoHError::Io)?;
         let path = &self.globals.path;
 
@@ -287,18 +1195,411 @@ impl DoH {
 
         let mut server = Http::new();
         server.http1_keep_alive(self.globals.keepalive);
+        server.http2_max_concurrent_streams(self.globals.max_concurrent_streams);
         server.pipeline_flush(true);
         let executor = LocalExecutor::new(self.globals.runtime_handle.clone());
         let server = server.with_executor(executor);
 
+        self.load_cache();
+        if self.globals.blocklist.is_enabled() {
+            let globals = self.globals.clone();
+            self.globals
+                .runtime_handle
+                .spawn(async move { globals.blocklist.reload_on_signal().await });
+        }
+        if let Some(interval) = self.globals.upstream_refresh_interval {
+            discovery::spawn_upstream_refresh(self.globals.clone(), interval)
+                .map_err(DoHError::Io)?;
+        }
+        if self.globals.allowlist.is_enabled() {
+            let globals = self.globals.clone();
+            self.globals
+                .runtime_handle
+                .spawn(async move { globals.allowlist.reload_on_signal().await });
+        }
+
+        if let (true, Some(metrics_address)) =
+            (self.globals.enable_metrics, self.globals.metrics_address)
+        {
+            let metrics_listener = TcpListener::bind(&metrics_address)
+                .await
+                .map_err(DoHError::Io)?;
+            println!(
+                "Metrics available on http://{}{}",
+                metrics_address, METRICS_PATH
+            );
+            self.globals.runtime_handle.spawn(
+                self.clone()
+                    .start_metrics(metrics_listener, server.clone())
+                    .map(|_| ()),
+            );
+        }
+
         #[cfg(feature = "tls")]
         {
-            if let Some(tls_acceptor) = tls_acceptor {
-                self.start_with_tls(tls_acceptor, listener, server).await?;
-                return Ok(());
+            if self.globals.enable_dot {
+                let tls_acceptor = tls_acceptor.clone().ok_or_else(|| {
+                    DoHError::Io(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "DNS over TLS requires a certificate and a key",
+                    ))
+                })?;
+                let dot_address = self.globals.dot_listen_address;
+                let dot_listener =
+                    listener::bind_tcp_listener(dot_address, self.globals.reuse_port)
+                        .map_err(DoHError::Io)?;
+                println!("Listening on tls://{}", dot_address);
+                // The listener is dropped on shutdown, like the DoH one
+                self.globals.runtime_handle.spawn(future::select(
+                    Box::pin(self.clone().start_dot(tls_acceptor, dot_listener)),
+                    Box::pin(shutdown::shutdown_requested()),
+                ));
             }
         }
-        self.start_without_tls(listener, server).await?;
-        Ok(())
+
+        #[cfg(feature = "http3")]
+        {
+            if self.globals.enable_http3 {
+                let (tls_cert_path, tls_cert_key_path) =
+                    match (&self.globals.tls_cert_path, &self.globals.tls_cert_key_path) {
+                        (Some(tls_cert_path), Some(tls_cert_key_path)) => {
+                            (tls_cert_path, tls_cert_key_path)
+                        }
+                        _ => {
+                            return Err(DoHError::Io(std::io::Error::new(
+                                std::io::ErrorKind::InvalidInput,
+                                "HTTP/3 requires a certificate and a key",
+                            )))
+                        }
+                    };
+                let server_config = create_quic_server_config(tls_cert_path, tls_cert_key_path)
+                    .map_err(DoHError::Io)?;
+                self.clone()
+                    .start_http3(server_config)
+                    .map_err(DoHError::Io)?;
+                println!("Listening on https://{}{} (HTTP/3)", listen_address, path);
+            }
+        }
+
+        shutdown::install_signal_handlers();
+        let doh = self.clone();
+        let listener_service = async move {
+            #[cfg(feature = "tls")]
+            {
+                if let Some(tls_acceptor) = tls_acceptor {
+                    return self.start_with_tls(tls_acceptor, listener, server).await;
+                }
+            }
+            self.start_without_tls(listener, server).await
+        };
+        // Dropping the listener service stops accepting new connections;
+        // connections that were already accepted keep being served while draining
+        match future::select(
+            Box::pin(listener_service),
+            Box::pin(shutdown::shutdown_requested()),
+        )
+        .await
+        {
+            future::Either::Left((res, _)) => res,
+            future::Either::Right(_) => {
+                doh.drain().await;
+                doh.save_cache();
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn query() -> Vec<u8> {
+        dns::build_query("example.com", dns::DNS_TYPE_A, false, false).unwrap()
+    }
+
+    #[test]
+    fn clients_over_the_rate_limit_get_a_429() {
+        testing::runtime().block_on(async {
+            let address = testing::udp_upstream(testing::answer).await;
+            let mut globals = testing::globals(address);
+            globals.rate_limiter = RateLimiter::new(1);
+            let doh = testing::doh(globals);
+
+            let response = testing::get_query(&doh, "/dns-query", &query()).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(dns::ancount(&testing::body(response).await), 1);
+
+            let response = testing::get_query(&doh, "/dns-query", &query()).await;
+            assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+            assert_eq!(response.headers()[hyper::header::RETRY_AFTER], "1");
+        });
+    }
+
+    #[test]
+    fn response_content_type_can_be_chosen() {
+        testing::runtime().block_on(async {
+            let address = testing::udp_upstream(testing::answer).await;
+            let doh = testing::doh(testing::globals(address));
+            let dns = base64::encode_config(query(), base64::URL_SAFE_NO_PAD);
+            for &(ct, content_type) in &[
+                ("", DNS_MESSAGE_CONTENT_TYPE),
+                ("application/dns-message", DNS_MESSAGE_CONTENT_TYPE),
+                (
+                    "application%2Fdns-udpwireformat",
+                    DNS_UDPWIREFORMAT_CONTENT_TYPE,
+                ),
+            ] {
+                let uri = format!("/dns-query?dns={}&ct={}", dns, ct);
+                let response = testing::get(&doh, &uri).await;
+                assert_eq!(response.status(), StatusCode::OK);
+                assert_eq!(
+                    response.headers()[hyper::header::CONTENT_TYPE],
+                    content_type
+                );
+                assert_eq!(dns::ancount(&testing::body(response).await), 1);
+            }
+            let uri = format!("/dns-query?dns={}&ct=text/plain", dns);
+            let response = testing::get(&doh, &uri).await;
+            assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        });
+    }
+
+    async fn bad_request_body(doh: &DoH, uri: &str) -> String {
+        let response = testing::get(doh, uri).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        String::from_utf8(testing::body(response).await).unwrap()
+    }
+
+    #[test]
+    fn invalid_dns_parameters_are_explained() {
+        testing::runtime().block_on(async {
+            let address = testing::udp_upstream(testing::answer).await;
+            let doh = testing::doh(testing::globals(address));
+            let dns = base64::encode_config(query(), base64::URL_SAFE_NO_PAD);
+            for padding in &["", "=", "==", "%3D", "%3d%3D", "=%3D"] {
+                let uri = format!("/dns-query?dns={}{}", dns, padding);
+                let response = testing::get(&doh, &uri).await;
+                assert_eq!(response.status(), StatusCode::OK, "{}", padding);
+            }
+
+            let body = bad_request_body(&doh, "/dns-query?ct=").await;
+            assert_eq!(body, "Missing dns parameter");
+            let mut no_question = query();
+            no_question[5] = 0;
+            let no_question = base64::encode_config(&no_question, base64::URL_SAFE_NO_PAD);
+            for &(dns, reason) in &[
+                ("ab+d", "not base64"),
+                ("ab/d", "not base64"),
+                ("ab%2Bd", "not base64"),
+                ("ab%2fd", "not base64"),
+                ("ab%41d", "must not be percent-encoded"),
+                ("ab.d", "Invalid base64url encoding"),
+                ("AAAA", "DNS message too short"),
+                (no_question.as_str(), "exactly one question"),
+            ] {
+                let body = bad_request_body(&doh, &format!("/dns-query?dns={}", dns)).await;
+                assert!(body.contains(reason), "{}: {}", dns, body);
+            }
+        });
+    }
+
+    #[test]
+    fn path_aliases_are_served() {
+        testing::runtime().block_on(async {
+            let address = testing::udp_upstream(testing::answer).await;
+            let mut globals = testing::globals(address);
+            globals.path_aliases = vec!["/resolve".to_string()];
+            let doh = testing::doh(globals);
+            for path in &["/dns-query", "/resolve"] {
+                let response = testing::get_query(&doh, path, &query()).await;
+                assert_eq!(response.status(), StatusCode::OK);
+            }
+            // Exact matches only
+            for path in &["/query", "/resolve/", "/resolvex", "/dns-query/x"] {
+                let response = testing::get_query(&doh, path, &query()).await;
+                assert_eq!(response.status(), StatusCode::NOT_FOUND);
+            }
+        });
+    }
+
+    #[test]
+    fn upstream_servfail_is_a_200_response() {
+        testing::runtime().block_on(async {
+            let address = testing::udp_upstream(|query| {
+                dns::response_from_query(query, dns::DNS_RCODE_SERVFAIL).ok()
+            })
+            .await;
+            let doh = testing::doh(testing::globals(address));
+            let response = testing::get_query(&doh, "/dns-query", &query()).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                response.headers()[hyper::header::CONTENT_TYPE],
+                DNS_MESSAGE_CONTENT_TYPE
+            );
+            let packet = testing::body(response).await;
+            assert_eq!(dns::rcode(&packet), dns::DNS_RCODE_SERVFAIL);
+            assert!(dns::question_matches(&query(), &packet).unwrap());
+        });
+    }
+
+    #[test]
+    fn upstream_timeout_is_a_200_servfail_response() {
+        testing::runtime().block_on(async {
+            let address = testing::udp_upstream(|_query| None).await;
+            let mut globals = testing::globals(address);
+            globals.timeout = Duration::from_millis(100);
+            globals.upstream_timeout = Duration::from_millis(100);
+            let doh = testing::doh(globals);
+            let response = testing::get_query(&doh, "/dns-query", &query()).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let packet = testing::body(response).await;
+            assert_eq!(dns::rcode(&packet), dns::DNS_RCODE_SERVFAIL);
+            assert!(dns::question_matches(&query(), &packet).unwrap());
+        });
+    }
+
+    #[test]
+    fn malformed_queries_are_a_400_response() {
+        testing::runtime().block_on(async {
+            let address = testing::udp_upstream(testing::answer).await;
+            let doh = testing::doh(testing::globals(address));
+            let query = query();
+            let truncated_query = &query[..query.len() - 2];
+            let response = testing::get_query(&doh, "/dns-query", truncated_query).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        });
+    }
+
+    async fn post_query(doh: &DoH, query: Vec<u8>) -> Response<Body> {
+        let req = Request::post("/dns-query")
+            .header(hyper::header::CONTENT_TYPE, DNS_MESSAGE_CONTENT_TYPE)
+            .body(Body::from(query))
+            .unwrap();
+        doh.route(req).await.unwrap()
+    }
+
+    #[test]
+    fn oversized_queries_are_a_413_response() {
+        testing::runtime().block_on(async {
+            let address = testing::udp_upstream(testing::answer).await;
+            // Messages the parser would reject are too large, whatever max_request_size says
+            let mut oversized_query = query();
+            oversized_query.resize(dns::DNS_MAX_PACKET_SIZE + 1, 0);
+            let doh = testing::doh(testing::globals(address));
+            let response = testing::get_query(&doh, "/dns-query", &oversized_query).await;
+            assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+            let response = post_query(&doh, oversized_query).await;
+            assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+            let mut globals = testing::globals(address);
+            globals.max_client_packet_size = query().len();
+            let doh = testing::doh(globals);
+            let response = post_query(&doh, query()).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let mut oversized_query = query();
+            oversized_query.push(0);
+            let response = testing::get_query(&doh, "/dns-query", &oversized_query).await;
+            assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+            let response = post_query(&doh, oversized_query).await;
+            assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        });
+    }
+
+    fn chaos_query(name: &str) -> Vec<u8> {
+        let mut query = dns::build_query(name, dns::DNS_TYPE_TXT, false, false).unwrap();
+        let class_offset = query.len() - 2;
+        query[class_offset..].copy_from_slice(&dns::DNS_CLASS_CHAOS.to_be_bytes());
+        query
+    }
+
+    #[test]
+    fn chaos_queries_are_answered_locally() {
+        testing::runtime().block_on(async {
+            // The upstream server never answers, so that forwarded queries fail
+            let address = testing::udp_upstream(|_query| None).await;
+            let mut globals = testing::globals(address);
+            globals.chaos_version = Some("doh-proxy".to_string());
+            let doh = testing::doh(globals);
+
+            let query = chaos_query("version.bind");
+            let response = testing::get_query(&doh, "/dns-query", &query).await;
+            let packet = testing::body(response).await;
+            assert_eq!(dns::rcode(&packet), dns::DNS_RCODE_NOERROR);
+            assert_eq!(dns::ancount(&packet), 1);
+            assert!(packet.ends_with(b"\x09doh-proxy"));
+
+            // Not configured
+            let query = chaos_query("hostname.bind");
+            let response = testing::get_query(&doh, "/dns-query", &query).await;
+            let packet = testing::body(response).await;
+            assert_eq!(dns::rcode(&packet), dns::DNS_RCODE_REFUSED);
+            assert_eq!(dns::ancount(&packet), 0);
+        });
+    }
+
+    #[test]
+    fn names_outside_the_allowlist_are_refused() {
+        testing::runtime().block_on(async {
+            let address = testing::udp_upstream(testing::answer).await;
+            let mut globals = testing::globals(address);
+            globals.allowlist = testing::allowlist("example.com\n");
+            globals.default_deny = true;
+            let doh = testing::doh(globals);
+
+            let response = testing::get_query(&doh, "/dns-query", &query()).await;
+            let packet = testing::body(response).await;
+            assert_eq!(dns::rcode(&packet), dns::DNS_RCODE_NOERROR);
+            assert_eq!(dns::ancount(&packet), 1);
+
+            let query = dns::build_query("example.net", dns::DNS_TYPE_A, false, false).unwrap();
+            let response = testing::get_query(&doh, "/dns-query", &query).await;
+            let packet = testing::body(response).await;
+            assert_eq!(dns::rcode(&packet), dns::DNS_RCODE_REFUSED);
+            assert_eq!(dns::ancount(&packet), 0);
+        });
+    }
+
+    #[test]
+    fn update_queries_are_not_implemented() {
+        testing::runtime().block_on(async {
+            let address = testing::udp_upstream(testing::answer).await;
+            let mut query = query();
+            query[2] |= 5 << 3;
+
+            let doh = testing::doh(testing::globals(address));
+            let response = testing::get_query(&doh, "/dns-query", &query).await;
+            let packet = testing::body(response).await;
+            assert_eq!(dns::rcode(&packet), dns::DNS_RCODE_NOTIMP);
+            assert_eq!(dns::opcode(&packet), 5);
+
+            let mut globals = testing::globals(address);
+            globals.allow_all_opcodes = true;
+            let doh = testing::doh(globals);
+            let response = testing::get_query(&doh, "/dns-query", &query).await;
+            let packet = testing::body(response).await;
+            assert_eq!(dns::rcode(&packet), dns::DNS_RCODE_NOERROR);
+        });
+    }
+
+    #[test]
+    fn parameterless_get_is_a_ping_when_allowed() {
+        testing::runtime().block_on(async {
+            let address = testing::udp_upstream(testing::answer).await;
+            let doh = testing::doh(testing::globals(address));
+            let response = testing::get(&doh, "/dns-query").await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+            let mut globals = testing::globals(address);
+            globals.allow_ping = true;
+            let doh = testing::doh(globals);
+            let response = testing::get(&doh, "/dns-query").await;
+            assert_eq!(response.status(), StatusCode::NO_CONTENT);
+            assert!(testing::body(response).await.is_empty());
+            // Regular queries are still answered
+            let response = testing::get_query(&doh, "/dns-query", &query()).await;
+            assert_eq!(response.status(), StatusCode::OK);
+        });
     }
 }
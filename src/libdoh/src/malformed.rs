@@ -0,0 +1,99 @@
+use crate::errors::DnsError;
+
+use std::fmt::{self, Write};
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const HEXDUMP_LINE_LEN: usize = 16;
+
+/// Where a malformed packet came from
+#[derive(Clone, Copy, Debug)]
+pub enum PacketSource {
+    Client(Option<IpAddr>),
+    Upstream(SocketAddr),
+}
+
+impl fmt::Display for PacketSource {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PacketSource::Client(Some(ip)) => write!(fmt, "client {}", ip),
+            PacketSource::Client(None) => write!(fmt, "client"),
+            PacketSource::Upstream(address) => write!(fmt, "upstream {}", address),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct MalformedPacketLogState {
+    last_logged: Option<Instant>,
+    suppressed: u64,
+}
+
+/// Logs packets rejected as malformed to stderr, with a hex dump of their first
+/// bytes. At most one packet is logged per interval; the others are only counted.
+#[derive(Debug, Default)]
+pub struct MalformedPacketLog {
+    interval: Option<Duration>,
+    hexdump_len: usize,
+    state: Mutex<MalformedPacketLogState>,
+}
+
+fn hexdump(data: &[u8]) -> String {
+    let mut out = String::new();
+    for (i, line) in data.chunks(HEXDUMP_LINE_LEN).enumerate() {
+        let _ = write!(out, "\n  {:04x}:", i * HEXDUMP_LINE_LEN);
+        for byte in line {
+            let _ = write!(out, " {:02x}", byte);
+        }
+    }
+    out
+}
+
+impl MalformedPacketLog {
+    pub fn new(interval: Duration, hexdump_len: usize) -> Self {
+        MalformedPacketLog {
+            interval: Some(interval),
+            hexdump_len,
+            state: Mutex::new(MalformedPacketLogState::default()),
+        }
+    }
+
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.interval.is_some()
+    }
+
+    pub(crate) fn log(&self, source: PacketSource, err: DnsError, packet: &[u8]) {
+        let interval = match self.interval {
+            Some(interval) => interval,
+            None => return,
+        };
+        let now = Instant::now();
+        let suppressed = {
+            let mut state = self.state.lock().unwrap();
+            if matches!(state.last_logged, Some(last_logged) if now.duration_since(last_logged) < interval)
+            {
+                state.suppressed += 1;
+                return;
+            }
+            state.last_logged = Some(now);
+            mem::replace(&mut state.suppressed, 0)
+        };
+        let mut line = format!(
+            "Malformed packet from {}: {} ({} bytes)",
+            source,
+            err,
+            packet.len()
+        );
+        if suppressed > 0 {
+            let _ = write!(line, ", {} more since the last report", suppressed);
+        }
+        let dumped = &packet[..packet.len().min(self.hexdump_len)];
+        if !dumped.is_empty() {
+            line.push_str(&hexdump(dumped));
+        }
+        eprintln!("{}", line);
+    }
+}
@@ -0,0 +1,242 @@
+use crate::dns;
+use crate::errors::*;
+use crate::{DoH, LocalExecutor};
+
+use futures::prelude::*;
+use hyper::server::conn::Http;
+use hyper::{http, Body, Response};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+pub const METRICS_PATH: &str = "/metrics";
+
+const LATENCY_BUCKETS_MS: [u64; 12] = [1, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+macro_rules! counters {
+    ($($name:ident => $help:expr),* $(,)?) => {
+        #[derive(Debug, Default)]
+        pub struct Counters {
+            $(pub $name: AtomicU64,)*
+        }
+
+        impl Counters {
+            fn render(&self, out: &mut String) {
+                $(
+                    let _ = writeln!(out, "# HELP doh_{} {}", stringify!($name), $help);
+                    let _ = writeln!(out, "# TYPE doh_{} counter", stringify!($name));
+                    let _ = writeln!(
+                        out,
+                        "doh_{} {}",
+                        stringify!($name),
+                        self.$name.load(Ordering::Relaxed)
+                    );
+                )*
+            }
+        }
+    };
+}
+
+counters! {
+    queries_total => "Total number of DoH queries",
+    cache_hits_total => "Number of responses served from the cache",
+    cache_misses_total => "Number of queries not found in the cache",
+    upstream_timeouts_total => "Number of upstream queries that timed out",
+    upstream_errors_total => "Number of upstream queries that failed",
+    upstream_saturated_total => "Number of times an upstream server was skipped because too many queries were in flight",
+    tcp_fallbacks_total => "Number of truncated responses retried over TCP",
+    edns_size_reductions_total => "Number of times the payload size advertised to an upstream server was lowered after UDP timeouts",
+    tcp_fastopen_connections_total => "Number of upstream TCP connections whose first query was sent in the SYN",
+    poisoned_responses_total => "Number of upstream responses containing a poisoned address",
+    cancelled_requests_total => "Number of requests cancelled because the client disconnected",
+    coalesced_queries_total => "Number of queries answered by an identical in-flight upstream query",
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS_MS.len()],
+    sum_us: AtomicU64,
+    count: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub counters: Counters,
+    responses_by_rcode: [AtomicU64; 16],
+    latency: Histogram,
+    invalid_queries: Mutex<HashMap<DnsError, u64>>,
+}
+
+impl Metrics {
+    #[inline]
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn observe_response(&self, packet: &[u8], latency: Duration) {
+        let rcode = dns::rcode(packet) as usize;
+        Self::inc(&self.responses_by_rcode[rcode]);
+        let latency_ms = latency.as_millis() as u64;
+        for (bucket, &bound) in self.latency.buckets.iter().zip(LATENCY_BUCKETS_MS.iter()) {
+            if latency_ms <= bound {
+                Self::inc(bucket);
+            }
+        }
+        self.latency
+            .sum_us
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+        Self::inc(&self.latency.count);
+    }
+
+    pub fn observe_invalid_query(&self, err: DnsError) {
+        *self.invalid_queries.lock().unwrap().entry(err).or_insert(0) += 1;
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        self.counters.render(&mut out);
+        let _ = writeln!(out, "# HELP doh_responses_total Responses by DNS rcode");
+        let _ = writeln!(out, "# TYPE doh_responses_total counter");
+        for (rcode, count) in self.responses_by_rcode.iter().enumerate() {
+            let count = count.load(Ordering::Relaxed);
+            if count > 0 {
+                let _ = writeln!(out, "doh_responses_total{{rcode=\"{}\"}} {}", rcode, count);
+            }
+        }
+        let _ = writeln!(
+            out,
+            "# HELP doh_invalid_queries_total Rejected queries by parse error"
+        );
+        let _ = writeln!(out, "# TYPE doh_invalid_queries_total counter");
+        let mut invalid_queries: Vec<_> = self
+            .invalid_queries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(err, &count)| (err.as_str(), count))
+            .collect();
+        invalid_queries.sort_unstable();
+        for (err, count) in invalid_queries {
+            let _ = writeln!(
+                out,
+                "doh_invalid_queries_total{{error=\"{}\"}} {}",
+                err, count
+            );
+        }
+        let _ = writeln!(out, "# HELP doh_latency_seconds Query latency");
+        let _ = writeln!(out, "# TYPE doh_latency_seconds histogram");
+        for (bucket, &bound) in self.latency.buckets.iter().zip(LATENCY_BUCKETS_MS.iter()) {
+            let _ = writeln!(
+                out,
+                "doh_latency_seconds_bucket{{le=\"{}\"}} {}",
+                bound as f64 / 1000.0,
+                bucket.load(Ordering::Relaxed)
+            );
+        }
+        let count = self.latency.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "doh_latency_seconds_bucket{{le=\"+Inf\"}} {}", count);
+        let _ = writeln!(
+            out,
+            "doh_latency_seconds_sum {}",
+            self.latency.sum_us.load(Ordering::Relaxed) as f64 / 1_000_000.0
+        );
+        let _ = writeln!(out, "doh_latency_seconds_count {}", count);
+        out
+    }
+}
+
+fn render_gauge(out: &mut String, name: &str, help: &str, value: usize) {
+    let _ = writeln!(out, "# HELP doh_{} {}", name, help);
+    let _ = writeln!(out, "# TYPE doh_{} gauge", name);
+    let _ = writeln!(out, "doh_{} {}", name, value);
+}
+
+impl DoH {
+    pub(crate) fn serve_metrics(&self) -> Result<Response<Body>, http::Error> {
+        let globals = &self.globals;
+        let mut body = globals.metrics.render();
+        render_gauge(
+            &mut body,
+            "clients",
+            "Number of connected clients",
+            globals.clients_count.current(),
+        );
+        render_gauge(
+            &mut body,
+            "inflight_queries",
+            "Number of queries waiting for an upstream response",
+            globals.inflight_queries.current(),
+        );
+        let _ = writeln!(
+            body,
+            "# HELP doh_upstream_breaker_state Upstream circuit breaker state (0: closed, 1: open, 2: half-open)"
+        );
+        let _ = writeln!(body, "# TYPE doh_upstream_breaker_state gauge");
+        for upstream in globals.upstreams.current().iter() {
+            let _ = writeln!(
+                body,
+                "doh_upstream_breaker_state{{upstream=\"{}\"}} {}",
+                upstream.address,
+                upstream.breaker_state().as_gauge()
+            );
+        }
+        let _ = writeln!(
+            body,
+            "# HELP doh_upstream_inflight_queries Number of queries waiting for a response from each upstream server"
+        );
+        let _ = writeln!(body, "# TYPE doh_upstream_inflight_queries gauge");
+        for upstream in globals.upstreams.current().iter() {
+            let _ = writeln!(
+                body,
+                "doh_upstream_inflight_queries{{upstream=\"{}\"}} {}",
+                upstream.address,
+                upstream.inflight_queries()
+            );
+        }
+        let _ = writeln!(
+            body,
+            "# HELP doh_upstream_edns_size Payload size advertised to each upstream server"
+        );
+        let _ = writeln!(body, "# TYPE doh_upstream_edns_size gauge");
+        for upstream in globals.upstreams.current().iter() {
+            let _ = writeln!(
+                body,
+                "doh_upstream_edns_size{{upstream=\"{}\"}} {}",
+                upstream.address,
+                upstream.edns_size(globals.udp_edns_size)
+            );
+        }
+        Response::builder()
+            .header(hyper::header::CONTENT_LENGTH, body.len())
+            .header(hyper::header::CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Body::from(body))
+    }
+
+    pub(crate) async fn start_metrics(
+        self,
+        mut listener: TcpListener,
+        server: Http<LocalExecutor>,
+    ) -> Result<(), DoHError> {
+        while let Some(stream) = listener.incoming().next().await {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            let self_inner = self.clone();
+            let service = hyper::service::service_fn(move |_req| {
+                let response = self_inner.serve_metrics();
+                async move { response }
+            });
+            let connection = server.serve_connection(stream, service);
+            self.globals.runtime_handle.spawn(async move {
+                tokio::time::timeout(Duration::from_secs(10), connection)
+                    .await
+                    .ok();
+            });
+        }
+        Ok(())
+    }
+}
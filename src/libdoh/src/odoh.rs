@@ -0,0 +1,296 @@
+use crate::constants::*;
+use crate::dns;
+use crate::errors::*;
+use crate::{http_error, DoH};
+
+use hyper::{http, Body, Request, Response, StatusCode};
+use odoh_rs::{
+    ObliviousDoHConfig, ObliviousDoHConfigs, ObliviousDoHKeyPair, ObliviousDoHMessage,
+    ObliviousDoHMessagePlaintext, OdohSecret, ResponseNonce,
+};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// X25519, HKDF-SHA256 and AES-128-GCM, the only HPKE suite ODoH clients have to support
+const ODOH_KEM_ID: u16 = 0x0020;
+const ODOH_KDF_ID: u16 = 0x0001;
+const ODOH_AEAD_ID: u16 = 0x0001;
+const ODOH_SEED_LEN: usize = 32;
+
+/// The HPKE key pair used to decrypt Oblivious DoH queries (RFC 9230), along with
+/// the `ObliviousDoHConfigs` structure advertising its public key.
+#[derive(Clone)]
+pub struct ODoHKeyPair {
+    key_pair: ObliviousDoHKeyPair,
+    configs: Vec<u8>,
+}
+
+impl fmt::Debug for ODoHKeyPair {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("ODoHKeyPair")
+            .field("configs", &self.configs)
+            .finish()
+    }
+}
+
+impl ODoHKeyPair {
+    /// Derives the key pair from a seed of at least 32 random bytes, so that the
+    /// same key can be kept across restarts and shared between servers.
+    pub fn from_seed(seed: &[u8]) -> io::Result<Self> {
+        if seed.len() < ODOH_SEED_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "The ODoH key seed must be at least {} bytes long",
+                    ODOH_SEED_LEN
+                ),
+            ));
+        }
+        let key_pair =
+            ObliviousDoHKeyPair::from_parameters(ODOH_KEM_ID, ODOH_KDF_ID, ODOH_AEAD_ID, seed);
+        let configs =
+            ObliviousDoHConfigs::from(vec![ObliviousDoHConfig::from(key_pair.public().clone())]);
+        let configs = odoh_rs::compose(&configs)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?
+            .to_vec();
+        Ok(ODoHKeyPair { key_pair, configs })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::from_seed(&fs::read(path)?)
+    }
+
+    /// A new key pair, that only lasts until the server restarts
+    pub fn generate() -> io::Result<Self> {
+        let mut seed = [0u8; ODOH_SEED_LEN];
+        getrandom::fill(&mut seed).map_err(io::Error::other)?;
+        Self::from_seed(&seed)
+    }
+
+    /// The serialized `ObliviousDoHConfigs` structure
+    pub fn configs(&self) -> &[u8] {
+        &self.configs
+    }
+
+    fn decrypt_query(&self, encrypted_query: &[u8]) -> Result<(Vec<u8>, ODoHQuery), DoHError> {
+        let mut encrypted_query = encrypted_query;
+        let message: ObliviousDoHMessage =
+            odoh_rs::parse(&mut encrypted_query).map_err(|_| DoHError::InvalidData)?;
+        let key_id = self
+            .key_pair
+            .public()
+            .identifier()
+            .map_err(|_| DoHError::InvalidData)?;
+        if message.key_id() != key_id.as_slice() {
+            return Err(DoHError::StaleKey);
+        }
+        let (plaintext, secret) =
+            odoh_rs::decrypt_query(&message, &self.key_pair).map_err(|_| DoHError::InvalidData)?;
+        let query = plaintext.clone().into_msg().to_vec();
+        Ok((query, ODoHQuery { plaintext, secret }))
+    }
+}
+
+// What is needed to encrypt the response to a query
+struct ODoHQuery {
+    plaintext: ObliviousDoHMessagePlaintext,
+    secret: OdohSecret,
+}
+
+impl ODoHQuery {
+    fn encrypt_response(self, response: &[u8]) -> Result<Vec<u8>, DoHError> {
+        let mut nonce = ResponseNonce::default();
+        getrandom::fill(&mut nonce).map_err(|_| DoHError::InvalidData)?;
+        let response = ObliviousDoHMessagePlaintext::new(response, 0);
+        let encrypted_response =
+            odoh_rs::encrypt_response(&self.plaintext, &response, self.secret, nonce)
+                .map_err(|_| DoHError::InvalidData)?;
+        odoh_rs::compose(&encrypted_response)
+            .map(|encrypted_response| encrypted_response.to_vec())
+            .map_err(|_| DoHError::InvalidData)
+    }
+}
+
+pub(crate) fn is_odoh_request(req: &Request<Body>) -> bool {
+    req.headers()
+        .get(hyper::header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.eq_ignore_ascii_case(ODOH_CONTENT_TYPE))
+}
+
+impl DoH {
+    pub(crate) fn serve_odoh_configs(
+        &self,
+        key_pair: &ODoHKeyPair,
+    ) -> Result<Response<Body>, http::Error> {
+        Response::builder()
+            .header(hyper::header::CONTENT_TYPE, "application/octet-stream")
+            .header(
+                hyper::header::CACHE_CONTROL,
+                format!("max-age={}", ODOH_CONFIGS_MAX_AGE_SECS),
+            )
+            .body(Body::from(key_pair.configs().to_vec()))
+    }
+
+    // The client is hidden behind an ODoH proxy, so its address is neither known nor
+    // sent upstream
+    pub(crate) async fn serve_odoh_post(
+        &self,
+        req: Request<Body>,
+        key_pair: &ODoHKeyPair,
+    ) -> Result<Response<Body>, http::Error> {
+        match self.odoh_response(req, key_pair).await {
+            Ok(encrypted_response) => Response::builder()
+                .header(hyper::header::CONTENT_LENGTH, encrypted_response.len())
+                .header(hyper::header::CONTENT_TYPE, ODOH_CONTENT_TYPE)
+                .header(hyper::header::CACHE_CONTROL, "no-cache, no-store")
+                .body(Body::from(encrypted_response)),
+            Err(e) => http_error(StatusCode::from(e)),
+        }
+    }
+
+    async fn odoh_response(
+        &self,
+        req: Request<Body>,
+        key_pair: &ODoHKeyPair,
+    ) -> Result<Vec<u8>, DoHError> {
+        let globals = &self.globals;
+        let encrypted_query = tokio::time::timeout(
+            globals.body_read_timeout,
+            self.read_body(req.into_body(), globals.max_request_size),
+        )
+        .await
+        .map_err(|_| DoHError::RequestTimeout)??;
+        let (query, odoh_query) = key_pair.decrypt_query(&encrypted_query)?;
+        if query.len() < dns::DNS_HEADER_SIZE {
+            return Err(DoHError::InvalidData);
+        }
+        if query.len() > globals.max_client_packet_size {
+            return Err(DoHError::TooLarge);
+        }
+        let mut response = self.proxy_response(query, None).await?;
+        self.pad_response(&mut response);
+        odoh_query.encrypt_response(&response.packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    use getrandom::{rand_core::UnwrapErr, SysRng};
+
+    fn odoh_doh(address: std::net::SocketAddr, key_pair: &ODoHKeyPair) -> DoH {
+        let mut globals = testing::globals(address);
+        globals.odoh_key_pair = Some(key_pair.clone());
+        testing::doh(globals)
+    }
+
+    async fn post_odoh_query(doh: &DoH, encrypted_query: Vec<u8>) -> Response<Body> {
+        let req = Request::post("/dns-query")
+            .header(hyper::header::CONTENT_TYPE, ODOH_CONTENT_TYPE)
+            .body(Body::from(encrypted_query))
+            .unwrap();
+        doh.route(req).await.unwrap()
+    }
+
+    // The query encrypted as a client would, with the plaintext and secret needed to
+    // decrypt the response
+    fn encrypt_query(
+        key_pair: &ODoHKeyPair,
+        query: &[u8],
+    ) -> (Vec<u8>, ObliviousDoHMessagePlaintext, OdohSecret) {
+        let plaintext = ObliviousDoHMessagePlaintext::new(query, 0);
+        let (message, secret) = odoh_rs::encrypt_query(
+            &plaintext,
+            key_pair.key_pair.public(),
+            &mut UnwrapErr(SysRng),
+        )
+        .unwrap();
+        let encrypted_query = odoh_rs::compose(&message).unwrap().to_vec();
+        (encrypted_query, plaintext, secret)
+    }
+
+    #[test]
+    fn seeds_must_be_long_enough() {
+        assert!(ODoHKeyPair::from_seed(&[7; 31]).is_err());
+        let key_pair = ODoHKeyPair::from_seed(&[7; 32]).unwrap();
+        assert_eq!(
+            ODoHKeyPair::from_seed(&[7; 32]).unwrap().configs(),
+            key_pair.configs()
+        );
+        assert_ne!(
+            ODoHKeyPair::from_seed(&[8; 32]).unwrap().configs(),
+            key_pair.configs()
+        );
+        let configs: ObliviousDoHConfigs = odoh_rs::parse(&mut key_pair.configs()).unwrap();
+        assert_eq!(configs.supported().len(), 1);
+    }
+
+    #[test]
+    fn configs_are_published() {
+        testing::runtime().block_on(async {
+            let address = testing::udp_upstream(testing::answer).await;
+            let key_pair = ODoHKeyPair::from_seed(&[7; 32]).unwrap();
+            let doh = odoh_doh(address, &key_pair);
+            let response = testing::get(&doh, ODOH_CONFIGS_PATH).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(testing::body(response).await, key_pair.configs());
+
+            // Neither the configs nor encrypted queries are served unless ODoH is enabled
+            let doh = testing::doh(testing::globals(address));
+            let response = testing::get(&doh, ODOH_CONFIGS_PATH).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+            let query = dns::build_query("example.com", dns::DNS_TYPE_A, false, false).unwrap();
+            let (encrypted_query, _, _) = encrypt_query(&key_pair, &query);
+            let response = post_odoh_query(&doh, encrypted_query).await;
+            assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        });
+    }
+
+    #[test]
+    fn encrypted_queries_are_answered() {
+        testing::runtime().block_on(async {
+            let address = testing::udp_upstream(testing::answer).await;
+            let key_pair = ODoHKeyPair::from_seed(&[7; 32]).unwrap();
+            let doh = odoh_doh(address, &key_pair);
+            let query = dns::build_query("example.com", dns::DNS_TYPE_A, false, false).unwrap();
+            let (encrypted_query, plaintext, secret) = encrypt_query(&key_pair, &query);
+            let response = post_odoh_query(&doh, encrypted_query).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                response.headers()[hyper::header::CONTENT_TYPE],
+                ODOH_CONTENT_TYPE
+            );
+            let encrypted_response = testing::body(response).await;
+            let message: ObliviousDoHMessage =
+                odoh_rs::parse(&mut encrypted_response.as_slice()).unwrap();
+            let response = odoh_rs::decrypt_response(&plaintext, &message, secret).unwrap();
+            let response = response.into_msg();
+            assert_eq!(dns::rcode(&response), dns::DNS_RCODE_NOERROR);
+            assert_eq!(
+                dns::answer_addresses(&response).unwrap(),
+                vec!["192.0.2.1".parse::<std::net::IpAddr>().unwrap()]
+            );
+        });
+    }
+
+    #[test]
+    fn queries_for_another_key_are_rejected() {
+        testing::runtime().block_on(async {
+            let address = testing::udp_upstream(testing::answer).await;
+            let doh = odoh_doh(address, &ODoHKeyPair::from_seed(&[7; 32]).unwrap());
+            let other_key_pair = ODoHKeyPair::from_seed(&[8; 32]).unwrap();
+            let query = dns::build_query("example.com", dns::DNS_TYPE_A, false, false).unwrap();
+            let (encrypted_query, _, _) = encrypt_query(&other_key_pair, &query);
+            let response = post_odoh_query(&doh, encrypted_query).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+            let response = post_odoh_query(&doh, b"garbage".to_vec()).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        });
+    }
+}
@@ -0,0 +1,71 @@
+use crate::dns;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+#[derive(Debug, Default)]
+pub struct Overrides {
+    ttl: u32,
+    records: HashMap<(String, u16), Vec<Vec<u8>>>,
+}
+
+fn invalid_line(line_number: usize, reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Overrides, line {}: {}", line_number, reason),
+    )
+}
+
+impl Overrides {
+    pub fn load<P: AsRef<Path>>(path: P, ttl: u32) -> io::Result<Self> {
+        let mut records: HashMap<(String, u16), Vec<Vec<u8>>> = HashMap::new();
+        for (i, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if parts.len() != 3 {
+                return Err(invalid_line(i + 1, "expected <name> <type> <value>"));
+            }
+            let name = parts[0].trim_end_matches('.').to_ascii_lowercase();
+            let (rtype, rdata) = match parts[1].to_ascii_uppercase().as_str() {
+                "A" => {
+                    let ip: Ipv4Addr = parts[2]
+                        .parse()
+                        .map_err(|_| invalid_line(i + 1, "invalid IPv4 address"))?;
+                    (dns::DNS_TYPE_A, ip.octets().to_vec())
+                }
+                "AAAA" => {
+                    let ip: Ipv6Addr = parts[2]
+                        .parse()
+                        .map_err(|_| invalid_line(i + 1, "invalid IPv6 address"))?;
+                    (dns::DNS_TYPE_AAAA, ip.octets().to_vec())
+                }
+                _ => return Err(invalid_line(i + 1, "only A and AAAA records are supported")),
+            };
+            records.entry((name, rtype)).or_default().push(rdata);
+        }
+        Ok(Overrides { ttl, records })
+    }
+
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        !self.records.is_empty()
+    }
+
+    #[inline]
+    pub fn ttl(&self) -> u32 {
+        self.ttl
+    }
+
+    pub fn lookup(&self, name: &str, qtype: u16) -> Option<&[Vec<u8>]> {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        self.records
+            .get(&(name, qtype))
+            .map(|rdatas| rdatas.as_slice())
+    }
+}
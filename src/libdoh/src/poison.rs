@@ -0,0 +1,46 @@
+use crate::dns;
+use crate::errors::DnsError;
+
+use std::collections::HashSet;
+use std::net::IpAddr;
+
+/// Addresses known to be injected by networks tampering with DNS responses
+#[derive(Debug, Default)]
+pub struct PoisonFilter {
+    addresses: HashSet<IpAddr>,
+    // Remove the poisoned records instead of rejecting the whole response
+    strip: bool,
+}
+
+impl PoisonFilter {
+    pub fn new(addresses: &[IpAddr], strip: bool) -> Self {
+        PoisonFilter {
+            addresses: addresses.iter().copied().collect(),
+            strip,
+        }
+    }
+
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        !self.addresses.is_empty()
+    }
+
+    #[inline]
+    pub fn strips(&self) -> bool {
+        self.strip
+    }
+
+    pub(crate) fn is_poisoned(&self, packet: &[u8]) -> bool {
+        if !self.is_enabled() {
+            return false;
+        }
+        match dns::answer_addresses(packet) {
+            Ok(addresses) => addresses.iter().any(|ip| self.addresses.contains(ip)),
+            Err(_) => false,
+        }
+    }
+
+    pub(crate) fn strip(&self, packet: &mut Vec<u8>) -> Result<bool, DnsError> {
+        dns::remove_answer_addresses(packet, |ip| self.addresses.contains(ip))
+    }
+}
@@ -0,0 +1,139 @@
+use crate::constants::*;
+use crate::dns;
+use crate::errors::*;
+use crate::utils::*;
+use crate::DoH;
+
+enum Step {
+    Probe(usize),
+    Denied,
+    Full,
+}
+
+// Offsets of the proper suffixes of a wire-format name, shortest (TLD) first
+fn suffix_offsets(qname: &[u8]) -> Vec<usize> {
+    let mut offsets = vec![];
+    let mut offset = 0;
+    while offset < qname.len() && qname[offset] != 0 {
+        offsets.push(offset);
+        offset += qname[offset] as usize + 1;
+    }
+    offsets.reverse();
+    offsets.pop();
+    offsets
+}
+
+impl DoH {
+    pub(crate) async fn resolve_upstream(&self, query: &[u8]) -> Result<Vec<u8>, DoHError> {
+        if self.globals.qname_minimization {
+            if let Ok(qname) = dns::qname(query) {
+                if let Step::Denied = self.minimize(&qname).await {
+                    return dns::response_from_query(query, dns::DNS_RCODE_NXDOMAIN)
+                        .map_err(|_| DoHError::InvalidData);
+                }
+            }
+        }
+        self.upstream_query(query).await
+    }
+
+    async fn minimize(&self, qname: &[u8]) -> Step {
+        let suffixes = suffix_offsets(qname);
+        let mut suffixes = suffixes.iter().take(QNAME_MINIMIZATION_MAX_STEPS);
+        let mut step = match suffixes.next() {
+            Some(&offset) => Step::Probe(offset),
+            None => Step::Full,
+        };
+        while let Step::Probe(offset) = step {
+            step = match self.probe(&qname[offset..]).await {
+                Some(dns::DNS_RCODE_NXDOMAIN) => Step::Denied,
+                Some(_) => match suffixes.next() {
+                    Some(&offset) => Step::Probe(offset),
+                    None => Step::Full,
+                },
+                None => Step::Full,
+            };
+        }
+        step
+    }
+
+    async fn probe(&self, qname: &[u8]) -> Option<u8> {
+        let mut query = dns::build_query_for_qname(qname, dns::DNS_TYPE_NS).ok()?;
+        dns::set_tid(&mut query, random_u64() as u16);
+        let packet = self.upstream_query(&query).await.ok()?;
+        Some(dns::rcode(&packet))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn suffixes_are_listed_from_the_tld() {
+        let qname = b"\x03www\x07example\x03com\x00";
+        assert_eq!(suffix_offsets(qname), vec![12, 4]);
+        assert_eq!(suffix_offsets(b"\x03com\x00"), Vec::<usize>::new());
+        assert_eq!(suffix_offsets(b"\x00"), Vec::<usize>::new());
+    }
+
+    // Returns the questions received by the server, and the response to the full query
+    async fn minimized_queries(nxdomain_name: &'static str) -> (Vec<(String, u16)>, Vec<u8>) {
+        let questions = Arc::new(Mutex::new(vec![]));
+        let address = testing::udp_upstream({
+            let questions = questions.clone();
+            move |query| {
+                let name = dns::name_to_string(&dns::qname(query).ok()?);
+                questions
+                    .lock()
+                    .unwrap()
+                    .push((name.clone(), dns::qtype(query).ok()?));
+                if name == nxdomain_name {
+                    return dns::response_from_query(query, dns::DNS_RCODE_NXDOMAIN).ok();
+                }
+                testing::answer(query)
+            }
+        })
+        .await;
+        let mut globals = testing::globals(address);
+        globals.qname_minimization = true;
+        let doh = testing::doh(globals);
+        let query = dns::build_query("www.example.com", dns::DNS_TYPE_A, false, false).unwrap();
+        let packet = doh.resolve_upstream(&query).await.unwrap();
+        let questions = questions.lock().unwrap().clone();
+        (questions, packet)
+    }
+
+    #[test]
+    fn parent_names_are_queried_first() {
+        testing::runtime().block_on(async {
+            let (questions, packet) = minimized_queries("").await;
+            let expected = [
+                ("com.".to_string(), dns::DNS_TYPE_NS),
+                ("example.com.".to_string(), dns::DNS_TYPE_NS),
+                ("www.example.com.".to_string(), dns::DNS_TYPE_A),
+            ];
+            assert_eq!(questions, expected);
+            assert_eq!(dns::rcode(&packet), dns::DNS_RCODE_NOERROR);
+            assert_eq!(dns::ancount(&packet), 1);
+        });
+    }
+
+    #[test]
+    fn nonexistent_parents_stop_the_resolution() {
+        testing::runtime().block_on(async {
+            let (questions, packet) = minimized_queries("example.com.").await;
+            let expected = [
+                ("com.".to_string(), dns::DNS_TYPE_NS),
+                ("example.com.".to_string(), dns::DNS_TYPE_NS),
+            ];
+            assert_eq!(questions, expected);
+            assert_eq!(dns::rcode(&packet), dns::DNS_RCODE_NXDOMAIN);
+            assert_eq!(
+                dns::qname(&packet),
+                Ok(b"\x03www\x07example\x03com\x00".to_vec())
+            );
+        });
+    }
+}
@@ -0,0 +1,152 @@
+use crate::dns;
+use crate::json;
+
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Write};
+use std::net::IpAddr;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const QUERY_LOG_QUEUE_SIZE: usize = 4096;
+
+const RCODES: [&str; 11] = [
+    "NOERROR", "FORMERR", "SERVFAIL", "NXDOMAIN", "NOTIMP", "REFUSED", "YXDOMAIN", "YXRRSET",
+    "NXRRSET", "NOTAUTH", "NOTZONE",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QueryLogFormat {
+    Plain,
+    Json,
+}
+
+impl std::str::FromStr for QueryLogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(QueryLogFormat::Plain),
+            "json" => Ok(QueryLogFormat::Json),
+            _ => Err(format!("Unsupported query log format: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct QueryLogEntry<'t> {
+    pub client_ip: Option<IpAddr>,
+    pub query: &'t [u8],
+    pub response: Option<&'t [u8]>,
+    pub cache_hit: bool,
+    pub elapsed: Duration,
+}
+
+#[derive(Debug)]
+pub struct QueryLog {
+    format: QueryLogFormat,
+    sender: Option<SyncSender<String>>,
+}
+
+impl Default for QueryLog {
+    fn default() -> Self {
+        QueryLog {
+            format: QueryLogFormat::Plain,
+            sender: None,
+        }
+    }
+}
+
+impl QueryLog {
+    pub fn new(destination: &str, format: QueryLogFormat) -> io::Result<Self> {
+        let writer: Box<dyn Write + Send> = if destination == "-" {
+            Box::new(io::stderr())
+        } else {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(destination)?;
+            Box::new(BufWriter::new(file))
+        };
+        let (sender, receiver) = mpsc::sync_channel(QUERY_LOG_QUEUE_SIZE);
+        thread::Builder::new()
+            .name("query-log".to_string())
+            .spawn(move || Self::writer(receiver, writer))?;
+        Ok(QueryLog {
+            format,
+            sender: Some(sender),
+        })
+    }
+
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.sender.is_some()
+    }
+
+    pub fn log(&self, entry: QueryLogEntry<'_>) {
+        if let Some(sender) = &self.sender {
+            // Lines are dropped rather than stalling the request if the writer falls behind
+            let _ = sender.try_send(self.format_entry(&entry));
+        }
+    }
+
+    fn writer(receiver: Receiver<String>, mut writer: Box<dyn Write + Send>) {
+        while let Ok(line) = receiver.recv() {
+            let _ = writer.write_all(line.as_bytes());
+            while let Ok(line) = receiver.try_recv() {
+                let _ = writer.write_all(line.as_bytes());
+            }
+            let _ = writer.flush();
+        }
+    }
+
+    fn format_entry(&self, entry: &QueryLogEntry<'_>) -> String {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let timestamp = format!("{}.{:03}", timestamp.as_secs(), timestamp.subsec_millis());
+        let client_ip = entry
+            .client_ip
+            .map(|ip| ip.to_string())
+            .unwrap_or_else(|| "-".to_string());
+        let qname = dns::qname(entry.query)
+            .map(|qname| dns::name_to_string(&qname))
+            .unwrap_or_else(|_| "-".to_string());
+        let qtype = dns::qtype(entry.query)
+            .map(json::qtype_to_string)
+            .unwrap_or_else(|_| "-".to_string());
+        let rcode = match entry.response {
+            None => "-".to_string(),
+            Some(response) => {
+                let rcode = dns::rcode(response) as usize;
+                RCODES
+                    .get(rcode)
+                    .map(|rcode| rcode.to_string())
+                    .unwrap_or_else(|| rcode.to_string())
+            }
+        };
+        let elapsed_ms = entry.elapsed.as_secs_f64() * 1000.0;
+        match self.format {
+            QueryLogFormat::Plain => format!(
+                "{} {} {} {} {} {} {:.3}ms\n",
+                timestamp,
+                client_ip,
+                qname,
+                qtype,
+                rcode,
+                if entry.cache_hit { "hit" } else { "miss" },
+                elapsed_ms
+            ),
+            QueryLogFormat::Json => format!(
+                "{{\"timestamp\":{},\"client_ip\":{},\"qname\":{},\"qtype\":{},\"rcode\":{},\"cache_hit\":{},\"elapsed_ms\":{:.3}}}\n",
+                timestamp,
+                json::json_escape(&client_ip),
+                json::json_escape(&qname),
+                json::json_escape(&qtype),
+                json::json_escape(&rcode),
+                entry.cache_hit,
+                elapsed_ms
+            ),
+        }
+    }
+}
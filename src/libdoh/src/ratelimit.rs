@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug)]
+struct RateLimiterInner {
+    buckets: HashMap<IpAddr, Bucket>,
+    last_prune: Instant,
+}
+
+impl Default for RateLimiterInner {
+    fn default() -> Self {
+        RateLimiterInner {
+            buckets: HashMap::new(),
+            last_prune: Instant::now(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    max_qps: u32,
+    inner: Mutex<RateLimiterInner>,
+}
+
+impl RateLimiter {
+    pub fn new(max_qps: u32) -> Self {
+        RateLimiter {
+            max_qps,
+            inner: Mutex::new(RateLimiterInner::default()),
+        }
+    }
+
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.max_qps > 0
+    }
+
+    /// Takes a token from the client's bucket, or returns the number of
+    /// seconds to wait before a new token becomes available.
+    pub fn check(&self, ip: IpAddr) -> Result<(), u64> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        let capacity = self.max_qps as f64;
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+        if now.duration_since(inner.last_prune) >= PRUNE_INTERVAL {
+            inner.buckets.retain(|_, bucket| {
+                let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+                bucket.tokens + elapsed * capacity < capacity
+            });
+            inner.last_prune = now;
+        }
+        let bucket = inner.buckets.entry(ip).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * capacity).min(capacity);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            let retry_after = ((1.0 - bucket.tokens) / capacity).ceil() as u64;
+            return Err(retry_after.max(1));
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_over_the_limit_are_rejected() {
+        let rate_limiter = RateLimiter::new(5);
+        let client_ip: IpAddr = "192.0.2.1".parse().unwrap();
+        for _ in 0..5 {
+            assert_eq!(rate_limiter.check(client_ip), Ok(()));
+        }
+        assert_eq!(rate_limiter.check(client_ip), Err(1));
+        // Other clients have their own bucket
+        assert_eq!(rate_limiter.check("192.0.2.2".parse().unwrap()), Ok(()));
+    }
+
+    #[test]
+    fn disabled_limiter_accepts_everything() {
+        let rate_limiter = RateLimiter::default();
+        let client_ip: IpAddr = "192.0.2.1".parse().unwrap();
+        for _ in 0..100 {
+            assert_eq!(rate_limiter.check(client_ip), Ok(()));
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use crate::dns;
+
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+fn is_private_ipv4(ip: &Ipv4Addr) -> bool {
+    let octets = ip.octets();
+    ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        // Shared address space (RFC 6598)
+        || (octets[0] == 100 && octets[1] & 0xc0 == 64)
+}
+
+fn is_private_ipv6(ip: &Ipv6Addr) -> bool {
+    if let Some(ip) = ip.to_ipv4_mapped() {
+        return is_private_ipv4(&ip);
+    }
+    let first_segment = ip.segments()[0];
+    ip.is_loopback()
+        || ip.is_unspecified()
+        // Unique local (fc00::/7) and link-local (fe80::/10) addresses
+        || first_segment & 0xfe00 == 0xfc00
+        || first_segment & 0xffc0 == 0xfe80
+}
+
+pub(crate) fn is_private_address(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_private_ipv4(ip),
+        IpAddr::V6(ip) => is_private_ipv6(ip),
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RebindingProtection {
+    enabled: bool,
+    // Names, and their subdomains, that are allowed to resolve to private addresses
+    allowed_names: HashSet<String>,
+}
+
+impl RebindingProtection {
+    pub fn new(allowed_names: &[String]) -> Self {
+        RebindingProtection {
+            enabled: true,
+            allowed_names: allowed_names
+                .iter()
+                .map(|name| name.trim_end_matches('.').to_ascii_lowercase())
+                .collect(),
+        }
+    }
+
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn is_allowed(&self, name: &str) -> bool {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        let mut suffix = name.as_str();
+        loop {
+            if self.allowed_names.contains(suffix) {
+                return true;
+            }
+            match suffix.find('.') {
+                Some(dot) => suffix = &suffix[dot + 1..],
+                None => return false,
+            }
+        }
+    }
+
+    // Returns `true` if the response must be replaced because it points to a private address
+    pub(crate) fn rejects(&self, packet: &[u8]) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        let addresses = match dns::answer_addresses(packet) {
+            Ok(addresses) => addresses,
+            Err(_) => return false,
+        };
+        if !addresses.iter().any(is_private_address) {
+            return false;
+        }
+        match dns::qname(packet) {
+            Ok(qname) => !self.is_allowed(&dns::name_to_string(&qname)),
+            Err(_) => true,
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use crate::constants::*;
+use crate::DoH;
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// A counter rather than a flag, so that every list being watched sees each request
+static RELOAD_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(unix)]
+extern "C" fn request_shutdown(_signal: libc::c_int) {
+    SHUTDOWN_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+#[cfg(unix)]
+extern "C" fn request_reload(_signal: libc::c_int) {
+    RELOAD_REQUESTS.fetch_add(1, Ordering::Relaxed);
+}
+
+// The handlers only update atomics, which is async-signal-safe; they are polled from the runtime
+#[cfg(unix)]
+pub(crate) fn install_signal_handlers() {
+    let handler = request_shutdown as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    let reload_handler = request_reload as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGTERM, handler);
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGHUP, reload_handler);
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn install_signal_handlers() {}
+
+pub(crate) async fn shutdown_requested() {
+    while !SHUTDOWN_REQUESTED.load(Ordering::Relaxed) {
+        tokio::time::delay_for(Duration::from_millis(SHUTDOWN_SIGNAL_POLL_MS)).await;
+    }
+}
+
+// Returns once SIGHUP has been received since the previous call with the same counter
+pub(crate) async fn reload_requested(seen_requests: &mut usize) {
+    loop {
+        let requests = RELOAD_REQUESTS.load(Ordering::Relaxed);
+        if requests != *seen_requests {
+            *seen_requests = requests;
+            return;
+        }
+        tokio::time::delay_for(Duration::from_millis(SHUTDOWN_SIGNAL_POLL_MS)).await;
+    }
+}
+
+impl DoH {
+    pub(crate) async fn drain(&self) {
+        let clients_count = &self.globals.clients_count;
+        let grace_period = self.globals.shutdown_grace_period;
+        println!(
+            "Shutting down, waiting up to {}s for {} client(s)",
+            grace_period.as_secs(),
+            clients_count.current()
+        );
+        let started_at = Instant::now();
+        while clients_count.current() > 0 {
+            if started_at.elapsed() >= grace_period {
+                eprintln!(
+                    "Grace period elapsed with {} client(s) still connected",
+                    clients_count.current()
+                );
+                return;
+            }
+            tokio::time::delay_for(Duration::from_millis(SHUTDOWN_SIGNAL_POLL_MS)).await;
+        }
+    }
+}
@@ -0,0 +1,242 @@
+use crate::constants::*;
+use crate::dns;
+use crate::errors::*;
+use crate::fastopen;
+use crate::globals::Globals;
+use crate::metrics::Metrics;
+use crate::upstream::response_matches;
+use crate::utils::*;
+
+use byteorder::{BigEndian, ByteOrder};
+use futures::channel::oneshot;
+use futures::lock::Mutex as AsyncMutex;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::runtime;
+
+type PendingQueries = Mutex<HashMap<u16, oneshot::Sender<Vec<u8>>>>;
+
+#[derive(Debug)]
+struct PooledConnection {
+    writer: AsyncMutex<OwnedWriteHalf>,
+    pending: PendingQueries,
+    closed: AtomicBool,
+    // In milliseconds, lowered if the server advertises a shorter timeout
+    idle_timeout_ms: AtomicU64,
+}
+
+impl PooledConnection {
+    fn is_usable(&self) -> bool {
+        !self.closed.load(Ordering::Relaxed)
+            && self.pending.lock().unwrap().len() < TCP_POOL_MAX_PIPELINED_QUERIES
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        self.pending.lock().unwrap().clear();
+    }
+
+    async fn read_responses(
+        self: Arc<Self>,
+        mut reader: OwnedReadHalf,
+        pool: Weak<Mutex<TcpPoolInner>>,
+    ) {
+        let mut binlen = [0u8; 2];
+        loop {
+            let idle_timeout = Duration::from_millis(self.idle_timeout_ms.load(Ordering::Relaxed));
+            match tokio::time::timeout(idle_timeout, reader.read_exact(&mut binlen)).await {
+                Ok(Ok(_)) => {}
+                Err(_) if !self.pending.lock().unwrap().is_empty() => continue,
+                _ => break,
+            }
+            let len = BigEndian::read_u16(&binlen) as usize;
+            if len < MIN_DNS_PACKET_LEN {
+                break;
+            }
+            let mut packet = vec![0; len];
+            if reader.read_exact(&mut packet).await.is_err() {
+                break;
+            }
+            if let Ok(Some(server_timeout)) = dns::edns_tcp_keepalive(&packet) {
+                self.idle_timeout_ms
+                    .fetch_min(server_timeout.as_millis() as u64, Ordering::Relaxed);
+            }
+            let tid = dns::tid(&packet);
+            if let Some(sender) = self.pending.lock().unwrap().remove(&tid) {
+                let _ = sender.send(packet);
+            }
+        }
+        self.close();
+        if let Some(pool) = pool.upgrade() {
+            pool.lock()
+                .unwrap()
+                .connections
+                .retain(|connection| !Arc::ptr_eq(connection, &self));
+        }
+    }
+}
+
+// Removes the pending entry if the query is cancelled or times out
+struct PendingQuery<'t> {
+    connection: &'t PooledConnection,
+    tid: u16,
+}
+
+impl Drop for PendingQuery<'_> {
+    fn drop(&mut self) {
+        self.connection.pending.lock().unwrap().remove(&self.tid);
+    }
+}
+
+// Releases the slot reserved by `checkout()`, even if the connection attempt is cancelled
+struct Connecting<'t>(&'t TcpPool);
+
+impl Drop for Connecting<'_> {
+    fn drop(&mut self) {
+        self.0.inner.lock().unwrap().connecting -= 1;
+    }
+}
+
+enum Checkout {
+    Reuse(Arc<PooledConnection>),
+    Connect,
+    Exhausted,
+}
+
+#[derive(Debug, Default)]
+struct TcpPoolInner {
+    connections: Vec<Arc<PooledConnection>>,
+    connecting: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct TcpPool {
+    inner: Arc<Mutex<TcpPoolInner>>,
+}
+
+impl TcpPool {
+    fn checkout(&self, max_size: usize) -> Checkout {
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .connections
+            .retain(|connection| !connection.closed.load(Ordering::Relaxed));
+        let connection = inner
+            .connections
+            .iter()
+            .filter(|connection| connection.is_usable())
+            .min_by_key(|connection| connection.pending.lock().unwrap().len())
+            .cloned();
+        let has_room = inner.connections.len() + inner.connecting < max_size;
+        match connection {
+            Some(connection) if connection.pending.lock().unwrap().is_empty() || !has_room => {
+                Checkout::Reuse(connection)
+            }
+            _ if has_room => {
+                inner.connecting += 1;
+                Checkout::Connect
+            }
+            _ => Checkout::Exhausted,
+        }
+    }
+
+    async fn connect(
+        &self,
+        address: SocketAddr,
+        idle_timeout: Duration,
+        fastopen: bool,
+        runtime_handle: &runtime::Handle,
+    ) -> Result<Arc<PooledConnection>, DoHError> {
+        let connecting = Connecting(self);
+        let stream = fastopen::connect_tcp(address, fastopen)
+            .await
+            .map_err(DoHError::Io)?;
+        let (reader, writer) = stream.into_split();
+        let connection = Arc::new(PooledConnection {
+            writer: AsyncMutex::new(writer),
+            pending: Mutex::new(HashMap::new()),
+            closed: AtomicBool::new(false),
+            idle_timeout_ms: AtomicU64::new(idle_timeout.as_millis() as u64),
+        });
+        runtime_handle.spawn(
+            connection
+                .clone()
+                .read_responses(reader, Arc::downgrade(&self.inner)),
+        );
+        self.inner
+            .lock()
+            .unwrap()
+            .connections
+            .push(connection.clone());
+        drop(connecting);
+        Ok(connection)
+    }
+
+    pub(crate) async fn query(
+        &self,
+        address: SocketAddr,
+        query: &[u8],
+        globals: &Globals,
+    ) -> Result<Option<Vec<u8>>, DoHError> {
+        let (connection, connected) = match self.checkout(globals.tcp_pool_size) {
+            Checkout::Reuse(connection) => (connection, false),
+            Checkout::Connect => {
+                let connection = self
+                    .connect(
+                        address,
+                        globals.tcp_idle_timeout,
+                        globals.upstream_tcp_fastopen,
+                        &globals.runtime_handle,
+                    )
+                    .await?;
+                (connection, true)
+            }
+            Checkout::Exhausted => return Ok(None),
+        };
+        let mut query = query.to_vec();
+        let added_keepalive =
+            globals.tcp_keepalive && dns::add_edns_tcp_keepalive(&mut query).unwrap_or(false);
+        let original_tid = dns::tid(&query);
+        let (sender, receiver) = oneshot::channel();
+        let tid = {
+            let mut pending = connection.pending.lock().unwrap();
+            let mut tid = random_u64() as u16;
+            while pending.contains_key(&tid) {
+                tid = random_u64() as u16;
+            }
+            pending.insert(tid, sender);
+            tid
+        };
+        let _pending_query = PendingQuery {
+            connection: &connection,
+            tid,
+        };
+        dns::set_tid(&mut query, tid);
+        let mut frame = Vec::with_capacity(2 + query.len());
+        frame.extend_from_slice(&(query.len() as u16).to_be_bytes());
+        frame.extend_from_slice(&query);
+        if let Err(e) = connection.writer.lock().await.write_all(&frame).await {
+            connection.close();
+            return Err(DoHError::Io(e));
+        }
+        let mut packet = receiver.await.map_err(|_| DoHError::UpstreamIssue)?;
+        if connected
+            && globals.upstream_tcp_fastopen
+            && fastopen::used_fastopen(connection.writer.lock().await.as_ref())
+        {
+            Metrics::inc(&globals.metrics.counters.tcp_fastopen_connections_total);
+        }
+        if !response_matches(globals, address, &query, &packet) {
+            return Err(DoHError::UpstreamIssue);
+        }
+        if added_keepalive {
+            dns::strip_edns_tcp_keepalive(&mut packet).map_err(|_| DoHError::UpstreamIssue)?;
+        }
+        dns::set_tid(&mut packet, original_tid);
+        Ok(Some(packet))
+    }
+}
@@ -0,0 +1,230 @@
+// Fixtures for the tests that need a running `DoH` instance and upstream servers
+
+use crate::blocklist::Blocklist;
+use crate::dns;
+use crate::globals::Globals;
+use crate::upstream::Upstreams;
+use crate::DoH;
+
+use byteorder::{BigEndian, ByteOrder};
+use hyper::{Body, Request, Response};
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::runtime::{self, Runtime};
+use tokio::stream::StreamExt;
+
+pub(crate) fn runtime() -> Runtime {
+    runtime::Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()
+        .unwrap()
+}
+
+// The defaults of the command-line tool, with a single upstream server and a short timeout
+pub(crate) fn globals(upstream_address: SocketAddr) -> Globals {
+    Globals {
+        #[cfg(feature = "tls")]
+        tls_cert_path: None,
+        #[cfg(feature = "tls")]
+        tls_cert_key_path: None,
+        #[cfg(feature = "tls")]
+        enable_dot: false,
+        #[cfg(feature = "tls")]
+        dot_listen_address: "127.0.0.1:853".parse().unwrap(),
+        #[cfg(feature = "http3")]
+        enable_http3: false,
+
+        listen_address: "127.0.0.1:3000".parse().unwrap(),
+        reuse_port: false,
+        local_bind_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        local_bind_port_range: None,
+        upstreams: Upstreams::new(&[upstream_address]),
+        upstream_sources: vec![],
+        upstream_refresh_interval: None,
+        tcp_pool_size: 4,
+        tcp_idle_timeout: Duration::from_secs(10),
+        tcp_keepalive: false,
+        upstream_tcp_fastopen: false,
+        udp_edns_size: 1232,
+        adaptive_edns_size: false,
+        tcp_edns_size: 4096,
+        force_tcp: false,
+        qname_minimization: false,
+        hostname: None,
+        response_headers: vec![],
+        server_timing: false,
+        path: "/dns-query".to_string(),
+        path_aliases: vec![],
+        max_clients: 512,
+        max_request_size: 65535,
+        max_client_packet_size: 4096,
+        body_read_timeout: Duration::from_secs(5),
+        body_min_rate: 0,
+        timeout: Duration::from_secs(1),
+        shutdown_grace_period: Duration::from_secs(10),
+        upstream_timeout: Duration::from_secs(1),
+        upstream_retries: 0,
+        upstream_failure_threshold: 3,
+        upstream_cooldown: Duration::from_secs(30),
+        race_upstreams: false,
+        max_inflight_per_upstream: 0,
+        clients_count: Default::default(),
+        inflight_queries: Default::default(),
+        inflight_upstream_queries: Default::default(),
+        max_concurrent_streams: 16,
+        overload_servfail: false,
+        min_ttl: 10,
+        max_ttl: 86400 * 7,
+        err_ttl: 2,
+        failure_ttls: dns::FailureTtls::uniform(2),
+        unreachable_ede_code: 22,
+        keepalive: true,
+        disable_post: false,
+        padding_block_size: 0,
+        randomize_case: false,
+        refuse_any: false,
+        allow_all_opcodes: false,
+        minimize_responses: false,
+        max_response_size: 0,
+        debug_packets: false,
+        cache: Default::default(),
+        max_cache_ttl: 0,
+        cache_persist_path: None,
+        ttl_jitter_pct: 0,
+        rate_limiter: Default::default(),
+        client_ip_header: None,
+        access_list: Default::default(),
+        trusted_proxies: Default::default(),
+        blocklist: Default::default(),
+        allowlist: Default::default(),
+        default_deny: false,
+        overrides: Default::default(),
+        local_zone: Default::default(),
+        poison_filter: Default::default(),
+        append_client_subnet: false,
+        allow_ping: false,
+        odoh_key_pair: None,
+        client_subnet_prefix_v4: 24,
+        client_subnet_prefix_v6: 56,
+        synthesize_aaaa: false,
+        dns64_prefix: "64:ff9b::".parse().unwrap(),
+        chaos_version: None,
+        chaos_hostname: None,
+        nsid: None,
+        rebinding_protection: Default::default(),
+        enable_metrics: false,
+        metrics_address: None,
+        metrics: Default::default(),
+        query_log: Default::default(),
+        malformed_packet_log: Default::default(),
+        health_check_name: None,
+        health_check: Default::default(),
+
+        runtime_handle: runtime::Handle::current(),
+    }
+}
+
+pub(crate) fn doh(globals: Globals) -> DoH {
+    DoH {
+        globals: Arc::new(globals),
+        remote_addr: Some("192.0.2.1:4711".parse().unwrap()),
+    }
+}
+
+// Loaded from a temporary file, which is removed right away
+pub(crate) fn allowlist(contents: &str) -> Blocklist {
+    let path = std::env::temp_dir().join(format!(
+        "doh-allowlist-{}-{}",
+        std::process::id(),
+        crate::utils::random_u64()
+    ));
+    fs::write(&path, contents).unwrap();
+    let allowlist = Blocklist::allowlist(path.clone()).unwrap();
+    let _ = fs::remove_file(path);
+    allowlist
+}
+
+// A NOERROR response with a single A record
+pub(crate) fn answer(query: &[u8]) -> Option<Vec<u8>> {
+    let mut packet = dns::response_from_query(query, dns::DNS_RCODE_NOERROR).ok()?;
+    dns::add_answer_rr(&mut packet, dns::DNS_TYPE_A, 300, &[192, 0, 2, 1]).ok()?;
+    Some(packet)
+}
+
+// A server answering UDP queries with `respond`, which can drop queries by returning `None`
+pub(crate) async fn udp_upstream<F>(respond: F) -> SocketAddr
+where
+    F: Fn(&[u8]) -> Option<Vec<u8>> + Send + 'static,
+{
+    let address: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let mut socket = UdpSocket::bind(&address).await.unwrap();
+    let address = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut query = vec![0u8; 65535];
+        while let Ok((len, client_address)) = socket.recv_from(&mut query).await {
+            if let Some(packet) = respond(&query[..len]) {
+                let _ = socket.send_to(&packet, &client_address).await;
+            }
+        }
+    });
+    address
+}
+
+// The same as `udp_upstream()`, over TCP; `address` can reuse the port of a UDP server
+pub(crate) async fn tcp_upstream<F>(address: SocketAddr, respond: F) -> SocketAddr
+where
+    F: Fn(&[u8]) -> Option<Vec<u8>> + Send + Sync + 'static,
+{
+    let mut listener = TcpListener::bind(&address).await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let respond = Arc::new(respond);
+    tokio::spawn(async move {
+        while let Some(Ok(mut stream)) = listener.incoming().next().await {
+            let respond = respond.clone();
+            tokio::spawn(async move {
+                loop {
+                    let mut binlen = [0u8; 2];
+                    if stream.read_exact(&mut binlen).await.is_err() {
+                        return;
+                    }
+                    let mut query = vec![0u8; BigEndian::read_u16(&binlen) as usize];
+                    if stream.read_exact(&mut query).await.is_err() {
+                        return;
+                    }
+                    let packet = match respond(&query) {
+                        Some(packet) => packet,
+                        None => continue,
+                    };
+                    let mut frame = (packet.len() as u16).to_be_bytes().to_vec();
+                    frame.extend_from_slice(&packet);
+                    if stream.write_all(&frame).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    address
+}
+
+pub(crate) async fn get(doh: &DoH, uri: &str) -> Response<Body> {
+    let req = Request::get(uri).body(Body::empty()).unwrap();
+    doh.route(req).await.unwrap()
+}
+
+pub(crate) async fn get_query(doh: &DoH, path: &str, query: &[u8]) -> Response<Body> {
+    let dns = base64::encode_config(query, base64::URL_SAFE_NO_PAD);
+    get(doh, &format!("{}?dns={}", path, dns)).await
+}
+
+pub(crate) async fn body(response: Response<Body>) -> Vec<u8> {
+    hyper::body::to_bytes(response.into_body())
+        .await
+        .unwrap()
+        .to_vec()
+}
@@ -1,11 +1,13 @@
+use crate::constants::*;
 use crate::errors::*;
 use crate::{DoH, LocalExecutor};
 
 use hyper::server::conn::Http;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{self, BufReader};
-use std::path::Path;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
 use tokio::net::TcpListener;
 use tokio::stream::StreamExt;
 use tokio_rustls::{
@@ -78,24 +80,73 @@ where
     Ok(TlsAcceptor::from(Arc::new(server_config)))
 }
 
+fn modification_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
 impl DoH {
+    pub(crate) async fn reload_tls_acceptor(
+        tls_acceptor: Arc<RwLock<TlsAcceptor>>,
+        certs_path: PathBuf,
+        certs_keys_path: PathBuf,
+    ) {
+        let mtimes = || {
+            (
+                modification_time(&certs_path),
+                modification_time(&certs_keys_path),
+            )
+        };
+        let mut last_mtimes = mtimes();
+        loop {
+            tokio::time::delay_for(Duration::from_secs(TLS_CERTS_RELOAD_CHECK_SECS)).await;
+            let current_mtimes = mtimes();
+            if current_mtimes == last_mtimes {
+                continue;
+            }
+            last_mtimes = current_mtimes;
+            match create_tls_acceptor(&certs_path, &certs_keys_path) {
+                Ok(new_tls_acceptor) => {
+                    *tls_acceptor.write().unwrap() = new_tls_acceptor;
+                    println!("TLS certificates reloaded");
+                }
+                Err(e) => eprintln!("Unable to reload the TLS certificates: {}", e),
+            }
+        }
+    }
+
     pub async fn start_with_tls(
         self,
         tls_acceptor: TlsAcceptor,
         mut listener: TcpListener,
         server: Http<LocalExecutor>,
     ) -> Result<(), DoHError> {
+        let tls_acceptor = Arc::new(RwLock::new(tls_acceptor));
+        if let (Some(certs_path), Some(certs_keys_path)) =
+            (&self.globals.tls_cert_path, &self.globals.tls_cert_key_path)
+        {
+            self.globals.runtime_handle.spawn(Self::reload_tls_acceptor(
+                tls_acceptor.clone(),
+                certs_path.clone(),
+                certs_keys_path.clone(),
+            ));
+        }
         let listener_service = async {
             while let Some(raw_stream) = listener.incoming().next().await {
                 let raw_stream = match raw_stream {
                     Ok(raw_stream) => raw_stream,
                     Err(_) => continue,
                 };
-                let stream = match tls_acceptor.accept(raw_stream).await {
+                let remote_addr = raw_stream.peer_addr().ok();
+                let current_tls_acceptor = tls_acceptor.read().unwrap().clone();
+                let stream = match current_tls_acceptor.accept(raw_stream).await {
                     Ok(stream) => stream,
                     Err(_) => continue,
                 };
-                self.clone().client_serve(stream, server.clone()).await;
+                self.clone()
+                    .client_serve(stream, remote_addr, server.clone())
+                    .await;
             }
             Ok(()) as Result<(), DoHError>
         };
@@ -0,0 +1,780 @@
+use crate::constants::*;
+use crate::dns;
+use crate::errors::*;
+use crate::fastopen;
+use crate::globals::{ClientsCount, Globals};
+use crate::malformed::PacketSource;
+use crate::metrics::Metrics;
+use crate::tcppool::TcpPool;
+use crate::utils::*;
+use crate::DoH;
+
+use byteorder::{BigEndian, ByteOrder};
+use futures::prelude::*;
+use futures::stream::FuturesUnordered;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UdpSocket;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl BreakerState {
+    pub fn as_gauge(self) -> usize {
+        match self {
+            BreakerState::Closed => 0,
+            BreakerState::Open => 1,
+            BreakerState::HalfOpen => 2,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Breaker {
+    state: BreakerState,
+    // Consecutive failures, counted from `first_failure_at`
+    failures: usize,
+    first_failure_at: Instant,
+    // While open, the end of the cooldown; while half-open, when the trial started
+    since: Instant,
+}
+
+// Servers behind middleboxes that drop IP fragments never answer queries whose
+// response would be fragmented. After consecutive UDP timeouts, the payload size
+// advertised to the server is lowered to the largest response received over UDP,
+// so that larger responses get truncated and retried over TCP instead. The lowered
+// size expires, and is learned again if the issue persists.
+#[derive(Debug, Default)]
+struct AdaptiveEdnsSize {
+    lowered: Option<(u16, Instant)>,
+    max_udp_response_size: u16,
+    consecutive_timeouts: usize,
+}
+
+#[derive(Debug)]
+pub struct Upstream {
+    pub address: SocketAddr,
+    breaker: Mutex<Breaker>,
+    inflight_queries: ClientsCount,
+    tcp_pool: TcpPool,
+    client_cookie: [u8; 8],
+    server_cookie: Mutex<Vec<u8>>,
+    adaptive_edns_size: Mutex<AdaptiveEdnsSize>,
+}
+
+impl Upstream {
+    pub fn new(address: SocketAddr) -> Self {
+        Upstream {
+            address,
+            breaker: Mutex::new(Breaker {
+                state: BreakerState::Closed,
+                failures: 0,
+                first_failure_at: Instant::now(),
+                since: Instant::now(),
+            }),
+            inflight_queries: ClientsCount::default(),
+            tcp_pool: TcpPool::default(),
+            client_cookie: random_u64().to_le_bytes(),
+            server_cookie: Mutex::new(vec![]),
+            adaptive_edns_size: Mutex::new(AdaptiveEdnsSize::default()),
+        }
+    }
+
+    /// The payload size advertised to this server: `default`, unless it was lowered
+    /// after UDP responses failed to arrive
+    pub fn edns_size(&self, default: u16) -> u16 {
+        let mut adaptive = self.adaptive_edns_size.lock().unwrap();
+        match adaptive.lowered {
+            Some((_, since))
+                if since.elapsed() >= Duration::from_secs(ADAPTIVE_EDNS_SIZE_RESET_SECS) =>
+            {
+                *adaptive = AdaptiveEdnsSize::default();
+                default
+            }
+            Some((edns_size, _)) => edns_size.min(default),
+            None => default,
+        }
+    }
+
+    fn record_udp_response(&self, len: usize) {
+        let mut adaptive = self.adaptive_edns_size.lock().unwrap();
+        adaptive.consecutive_timeouts = 0;
+        adaptive.max_udp_response_size = adaptive.max_udp_response_size.max(len as u16);
+    }
+
+    // Returns `true` if the advertised size was lowered
+    fn record_udp_timeout(&self, default: u16) -> bool {
+        let edns_size = self.edns_size(default);
+        let mut adaptive = self.adaptive_edns_size.lock().unwrap();
+        adaptive.consecutive_timeouts += 1;
+        // Servers that never answered are down rather than behind a broken middlebox
+        let learned_size = adaptive
+            .max_udp_response_size
+            .max(dns::DNS_MIN_EDNS_PAYLOAD_SIZE);
+        if adaptive.consecutive_timeouts < ADAPTIVE_EDNS_SIZE_TIMEOUTS
+            || adaptive.max_udp_response_size == 0
+            || learned_size >= edns_size
+        {
+            return false;
+        }
+        adaptive.consecutive_timeouts = 0;
+        adaptive.lowered = Some((learned_size, Instant::now()));
+        true
+    }
+
+    pub fn breaker_state(&self) -> BreakerState {
+        self.breaker.lock().unwrap().state
+    }
+
+    pub fn inflight_queries(&self) -> usize {
+        self.inflight_queries.current()
+    }
+
+    // Returns `true` if `try_acquire` would let a query through, without taking the
+    // trial slot of a breaker whose cooldown has elapsed
+    fn is_available(&self, cooldown: Duration) -> bool {
+        let breaker = self.breaker.lock().unwrap();
+        let now = Instant::now();
+        match breaker.state {
+            BreakerState::Closed => true,
+            BreakerState::Open => now >= breaker.since,
+            BreakerState::HalfOpen => now >= breaker.since + cooldown,
+        }
+    }
+
+    // Returns `true` if a query can be sent. Once the cooldown has elapsed, a single
+    // trial query is let through; the breaker closes if it succeeds, and opens again
+    // otherwise. A trial that never reports back is retried after another cooldown.
+    fn try_acquire(&self, cooldown: Duration) -> bool {
+        let mut breaker = self.breaker.lock().unwrap();
+        let now = Instant::now();
+        match breaker.state {
+            BreakerState::Closed => true,
+            BreakerState::Open if now < breaker.since => false,
+            BreakerState::HalfOpen if now < breaker.since + cooldown => false,
+            BreakerState::Open | BreakerState::HalfOpen => {
+                breaker.state = BreakerState::HalfOpen;
+                breaker.since = now;
+                true
+            }
+        }
+    }
+
+    fn record_success(&self) {
+        let mut breaker = self.breaker.lock().unwrap();
+        breaker.state = BreakerState::Closed;
+        breaker.failures = 0;
+    }
+
+    fn record_failure(&self, failure_threshold: usize, cooldown: Duration) {
+        let mut breaker = self.breaker.lock().unwrap();
+        let now = Instant::now();
+        if breaker.state == BreakerState::Closed {
+            if breaker.failures == 0
+                || now.duration_since(breaker.first_failure_at)
+                    > Duration::from_secs(UPSTREAM_FAILURE_WINDOW_SECS)
+            {
+                breaker.failures = 0;
+                breaker.first_failure_at = now;
+            }
+            breaker.failures += 1;
+            if breaker.failures < failure_threshold {
+                return;
+            }
+        }
+        breaker.state = BreakerState::Open;
+        breaker.failures = 0;
+        breaker.since = now + cooldown;
+    }
+
+    fn set_cookie(&self, query: &mut Vec<u8>) -> Result<(), DoHError> {
+        let server_cookie = self.server_cookie.lock().unwrap().clone();
+        let res = if server_cookie.is_empty() {
+            dns::set_edns_cookie(query, self.client_cookie)
+        } else {
+            dns::set_edns_cookies(query, self.client_cookie, &server_cookie)
+        };
+        res.map_err(|_| DoHError::InvalidData)
+    }
+
+    fn verify_cookie(&self, packet: &mut Vec<u8>) -> Result<(), DoHError> {
+        let cookie = match dns::edns_cookie(packet).map_err(|_| DoHError::UpstreamIssue)? {
+            Some(cookie) => cookie,
+            None => return Ok(()),
+        };
+        if cookie[..8] != self.client_cookie {
+            return Err(DoHError::UpstreamIssue);
+        }
+        if cookie.len() > 8 {
+            *self.server_cookie.lock().unwrap() = cookie[8..].to_vec();
+        }
+        let _ = dns::strip_edns_cookie(packet);
+        Ok(())
+    }
+
+    fn local_bind_address(&self, local_bind_address: SocketAddr) -> SocketAddr {
+        match (self.address, local_bind_address) {
+            (SocketAddr::V4(_), SocketAddr::V4(_)) | (SocketAddr::V6(_), SocketAddr::V6(_)) => {
+                local_bind_address
+            }
+            (SocketAddr::V4(_), _) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+            (SocketAddr::V6(_), _) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+        }
+    }
+}
+
+/// The upstream servers, in order of preference. The list can be replaced while
+/// queries are in flight; servers that are kept retain their state.
+#[derive(Debug)]
+pub struct Upstreams(RwLock<Arc<Vec<Arc<Upstream>>>>);
+
+impl Upstreams {
+    pub fn new(addresses: &[SocketAddr]) -> Self {
+        let upstreams = addresses
+            .iter()
+            .map(|&address| Arc::new(Upstream::new(address)))
+            .collect();
+        Upstreams(RwLock::new(Arc::new(upstreams)))
+    }
+
+    pub fn current(&self) -> Arc<Vec<Arc<Upstream>>> {
+        self.0.read().unwrap().clone()
+    }
+
+    /// Returns `true` if the list of addresses changed
+    pub fn update(&self, addresses: &[SocketAddr]) -> bool {
+        let mut upstreams = self.0.write().unwrap();
+        if upstreams
+            .iter()
+            .map(|upstream| upstream.address)
+            .eq(addresses.iter().copied())
+        {
+            return false;
+        }
+        let updated = addresses
+            .iter()
+            .map(|&address| {
+                upstreams
+                    .iter()
+                    .find(|upstream| upstream.address == address)
+                    .cloned()
+                    .unwrap_or_else(|| Arc::new(Upstream::new(address)))
+            })
+            .collect();
+        *upstreams = Arc::new(updated);
+        true
+    }
+}
+
+// Responses whose question can't be parsed are logged as malformed
+pub(crate) fn response_matches(
+    globals: &Globals,
+    address: SocketAddr,
+    query: &[u8],
+    packet: &[u8],
+) -> bool {
+    match dns::question_matches(query, packet) {
+        Ok(matches) => matches,
+        Err(err) => {
+            globals
+                .malformed_packet_log
+                .log(PacketSource::Upstream(address), err, packet);
+            false
+        }
+    }
+}
+
+impl DoH {
+    pub(crate) async fn upstream_query(&self, query: &[u8]) -> Result<Vec<u8>, DoHError> {
+        let globals = &self.globals;
+        let current_upstreams = globals.upstreams.current();
+        let upstreams: Vec<&Upstream> = current_upstreams
+            .iter()
+            .map(|upstream| upstream.as_ref())
+            .filter(|upstream| upstream.is_available(globals.upstream_cooldown))
+            .collect();
+        if upstreams.is_empty() {
+            Metrics::inc(&globals.metrics.counters.upstream_errors_total);
+            return Err(DoHError::UpstreamIssue);
+        }
+        let mut last_res = Err(DoHError::UpstreamIssue);
+        // In race mode, the first two servers are queried simultaneously, and the slower
+        // query is cancelled as soon as one of them returns a usable response
+        if globals.race_upstreams && upstreams.len() > 1 {
+            let mut attempts: FuturesUnordered<_> = upstreams
+                .iter()
+                .take(2)
+                .map(|upstream| self.upstream_attempt(upstream, query, globals.timeout))
+                .collect();
+            while let Some(res) = attempts.next().await {
+                if matches!(&res, Ok(packet) if !dns::is_recoverable_error(packet)) {
+                    return res;
+                }
+                last_res = res;
+            }
+            return last_res;
+        }
+        let attempt_timeout = globals.timeout / upstreams.len() as u32;
+        for upstream in upstreams {
+            let res = self.upstream_attempt(upstream, query, attempt_timeout).await;
+            if matches!(&res, Ok(packet) if !dns::is_recoverable_error(packet)) {
+                return res;
+            }
+            last_res = res;
+        }
+        last_res
+    }
+
+    // Queries a single server, and updates its circuit breaker with the outcome
+    async fn upstream_attempt(
+        &self,
+        upstream: &Upstream,
+        query: &[u8],
+        timeout: Duration,
+    ) -> Result<Vec<u8>, DoHError> {
+        let globals = &self.globals;
+        // The breaker is only updated right before the query is sent, so that servers
+        // that end up not being queried keep their trial slot
+        if !upstream.try_acquire(globals.upstream_cooldown) {
+            return Err(DoHError::UpstreamIssue);
+        }
+        // A saturated server is skipped, so that a slow server doesn't hold up the
+        // queries that could be sent to the next ones
+        let max_inflight_queries = match globals.max_inflight_per_upstream {
+            0 => usize::MAX,
+            max_inflight_queries => max_inflight_queries,
+        };
+        let _inflight_query = match upstream.inflight_queries.try_acquire(max_inflight_queries) {
+            Some(inflight_query) => inflight_query,
+            None => {
+                Metrics::inc(&globals.metrics.counters.upstream_saturated_total);
+                return Err(DoHError::UpstreamIssue);
+            }
+        };
+        let res = match tokio::time::timeout(timeout, self.upstream_query_to(upstream, query)).await
+        {
+            Ok(res) => res,
+            Err(_) => {
+                Metrics::inc(&globals.metrics.counters.upstream_timeouts_total);
+                Err(DoHError::UpstreamTimeout)
+            }
+        };
+        match &res {
+            Ok(packet) if !dns::is_recoverable_error(packet) => upstream.record_success(),
+            Ok(_) => upstream.record_failure(
+                globals.upstream_failure_threshold,
+                globals.upstream_cooldown,
+            ),
+            Err(_) => {
+                Metrics::inc(&globals.metrics.counters.upstream_errors_total);
+                upstream.record_failure(
+                    globals.upstream_failure_threshold,
+                    globals.upstream_cooldown,
+                );
+            }
+        }
+        res
+    }
+
+    async fn upstream_query_to(
+        &self,
+        upstream: &Upstream,
+        query: &[u8],
+    ) -> Result<Vec<u8>, DoHError> {
+        let globals = &self.globals;
+        if globals.force_tcp {
+            let packet = self.tcp_query(upstream, query).await?;
+            return self.filter_poisoned(packet);
+        }
+        let mut packet = self.udp_query(upstream, query).await?;
+        let poisoned = globals.poison_filter.is_poisoned(&packet);
+        if dns::is_truncated(&packet) {
+            Metrics::inc(&globals.metrics.counters.tcp_fallbacks_total);
+            packet = self.tcp_query(upstream, query).await?;
+        } else if poisoned && !globals.poison_filter.strips() {
+            // Injected responses usually only affect UDP
+            Metrics::inc(&globals.metrics.counters.poisoned_responses_total);
+            packet = self.tcp_query(upstream, query).await?;
+        }
+        self.filter_poisoned(packet)
+    }
+
+    fn filter_poisoned(&self, mut packet: Vec<u8>) -> Result<Vec<u8>, DoHError> {
+        let poison_filter = &self.globals.poison_filter;
+        if !poison_filter.is_poisoned(&packet) {
+            return Ok(packet);
+        }
+        Metrics::inc(&self.globals.metrics.counters.poisoned_responses_total);
+        if !poison_filter.strips() {
+            return Err(DoHError::UpstreamIssue);
+        }
+        poison_filter
+            .strip(&mut packet)
+            .map_err(|_| DoHError::UpstreamIssue)?;
+        Ok(packet)
+    }
+
+    // Picks random ports in the configured range, and falls back to an ephemeral port
+    // if none of them is available
+    async fn bind_udp_socket(&self, upstream: &Upstream) -> Result<UdpSocket, DoHError> {
+        let globals = &self.globals;
+        let local_bind_address = upstream.local_bind_address(globals.local_bind_address);
+        if let Some((first_port, last_port)) = globals.local_bind_port_range {
+            let ports_count = (last_port - first_port) as u64 + 1;
+            for _ in 0..LOCAL_PORT_BIND_ATTEMPTS {
+                let port = first_port + (random_u64() % ports_count) as u16;
+                let address = SocketAddr::new(local_bind_address.ip(), port);
+                match UdpSocket::bind(&address).await {
+                    Ok(socket) => return Ok(socket),
+                    Err(e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+                    Err(e) => return Err(DoHError::Io(e)),
+                }
+            }
+            eprintln!(
+                "No available port in the {}-{} range, using an ephemeral port",
+                first_port, last_port
+            );
+        }
+        UdpSocket::bind(&local_bind_address)
+            .await
+            .map_err(DoHError::Io)
+    }
+
+    pub(crate) async fn udp_query(
+        &self,
+        upstream: &Upstream,
+        query: &[u8],
+    ) -> Result<Vec<u8>, DoHError> {
+        let globals = &self.globals;
+        let original_query = query;
+        let mut query = query.to_vec();
+        if globals.randomize_case {
+            dns::randomize_case(&mut query, &random_bytes(32))
+                .map_err(|_| DoHError::InvalidData)?;
+        }
+        upstream.set_cookie(&mut query)?;
+        if globals.adaptive_edns_size {
+            let edns_size = upstream.edns_size(globals.udp_edns_size);
+            let _ = dns::set_edns_max_payload_size(&mut query, edns_size);
+        }
+        let query = query.as_slice();
+        let mut socket = self.bind_udp_socket(upstream).await?;
+        let expected_server_address = upstream.address;
+        socket
+            .send_to(query, &upstream.address)
+            .map_err(DoHError::Io)
+            .await?;
+        let mut packet = vec![0; MAX_DNS_RESPONSE_LEN];
+        let deadline = Instant::now() + globals.upstream_timeout;
+        let mut retries_left = globals.upstream_retries;
+        let mut retransmit_delay =
+            globals.upstream_timeout / (2u32.saturating_pow(retries_left + 1) - 1).max(1);
+        let (len, response_server_address) = loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let wait = if retries_left > 0 {
+                let jitter_range = retransmit_delay.as_micros() as u64 / 4 + 1;
+                let jitter = Duration::from_micros(random_u64() % jitter_range);
+                (retransmit_delay + jitter).min(remaining)
+            } else {
+                remaining
+            };
+            // The same socket and transaction ID are reused, so a late response to an
+            // earlier transmission is accepted like any other
+            match tokio::time::timeout(wait, socket.recv_from(&mut packet)).await {
+                Ok(res) => break res.map_err(DoHError::Io)?,
+                Err(_) if retries_left > 0 && Instant::now() < deadline => {
+                    retries_left -= 1;
+                    retransmit_delay *= 2;
+                    socket
+                        .send_to(query, &upstream.address)
+                        .map_err(DoHError::Io)
+                        .await?;
+                }
+                // Queries cancelled before the deadline, such as the losers of a race,
+                // don't count as timeouts
+                Err(_) => {
+                    if globals.adaptive_edns_size
+                        && upstream.record_udp_timeout(globals.udp_edns_size)
+                    {
+                        Metrics::inc(&globals.metrics.counters.edns_size_reductions_total);
+                    }
+                    return Err(DoHError::UpstreamTimeout);
+                }
+            }
+        };
+        if len < MIN_DNS_PACKET_LEN || expected_server_address != response_server_address {
+            return Err(DoHError::UpstreamIssue);
+        }
+        packet.truncate(len);
+        if !response_matches(globals, upstream.address, query, &packet) {
+            return Err(DoHError::UpstreamIssue);
+        }
+        if globals.adaptive_edns_size {
+            upstream.record_udp_response(len);
+        }
+        upstream.verify_cookie(&mut packet)?;
+        if globals.randomize_case {
+            let qname = dns::expanded_qname(query).map_err(|_| DoHError::InvalidData)?;
+            if dns::expanded_qname(&packet).ok() != Some(qname) {
+                return Err(DoHError::UpstreamIssue);
+            }
+            dns::set_qname_case(&mut packet, original_query)
+                .map_err(|_| DoHError::UpstreamIssue)?;
+        }
+        Ok(packet)
+    }
+
+    pub(crate) async fn tcp_query(
+        &self,
+        upstream: &Upstream,
+        query: &[u8],
+    ) -> Result<Vec<u8>, DoHError> {
+        let mut query = query.to_vec();
+        upstream.set_cookie(&mut query)?;
+        let _ = dns::set_edns_max_payload_size(&mut query, self.globals.tcp_edns_size);
+        let mut packet = tokio::time::timeout(
+            self.globals.upstream_timeout,
+            self._tcp_query(upstream, &query),
+        )
+        .await
+        .map_err(|_| DoHError::UpstreamTimeout)??;
+        upstream.verify_cookie(&mut packet)?;
+        Ok(packet)
+    }
+
+    async fn _tcp_query(&self, upstream: &Upstream, query: &[u8]) -> Result<Vec<u8>, DoHError> {
+        let globals = &self.globals;
+        if globals.tcp_pool_size > 0 {
+            let pooled_response = upstream
+                .tcp_pool
+                .query(upstream.address, query, globals)
+                .await?;
+            if let Some(packet) = pooled_response {
+                return Ok(packet);
+            }
+        }
+        let mut stream = fastopen::connect_tcp(upstream.address, globals.upstream_tcp_fastopen)
+            .await
+            .map_err(DoHError::Io)?;
+        // The length and the query are written at once, so that they fit in the SYN
+        let mut frame = Vec::with_capacity(2 + query.len());
+        frame.extend_from_slice(&(query.len() as u16).to_be_bytes());
+        frame.extend_from_slice(query);
+        stream.write_all(&frame).await.map_err(DoHError::Io)?;
+        let mut binlen = [0u8; 2];
+        stream.read_exact(&mut binlen).await.map_err(DoHError::Io)?;
+        let len = BigEndian::read_u16(&binlen) as usize;
+        if len < MIN_DNS_PACKET_LEN {
+            return Err(DoHError::UpstreamIssue);
+        }
+        let mut packet = vec![0; len];
+        stream.read_exact(&mut packet).await.map_err(DoHError::Io)?;
+        if globals.upstream_tcp_fastopen && fastopen::used_fastopen(&stream) {
+            Metrics::inc(&globals.metrics.counters.tcp_fastopen_connections_total);
+        }
+        if !response_matches(globals, upstream.address, query, &packet) {
+            return Err(DoHError::UpstreamIssue);
+        }
+        Ok(packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    fn upstream_with_open_breaker() -> Upstream {
+        let upstream = Upstream::new("192.0.2.1:53".parse().unwrap());
+        upstream.record_failure(1, Duration::from_secs(0));
+        upstream
+    }
+
+    #[test]
+    fn availability_check_keeps_the_trial_slot() {
+        let upstream = upstream_with_open_breaker();
+        let cooldown = Duration::from_secs(60);
+        assert_eq!(upstream.breaker_state(), BreakerState::Open);
+        assert!(upstream.is_available(cooldown));
+        assert_eq!(upstream.breaker_state(), BreakerState::Open);
+        assert!(upstream.try_acquire(cooldown));
+        assert_eq!(upstream.breaker_state(), BreakerState::HalfOpen);
+        // The single trial query is in flight
+        assert!(!upstream.is_available(cooldown));
+        assert!(!upstream.try_acquire(cooldown));
+    }
+
+    #[test]
+    fn breaker_closes_after_a_successful_trial() {
+        let upstream = upstream_with_open_breaker();
+        assert!(upstream.try_acquire(Duration::from_secs(60)));
+        upstream.record_success();
+        assert_eq!(upstream.breaker_state(), BreakerState::Closed);
+    }
+
+    #[test]
+    fn truncated_udp_response_is_retried_over_tcp() {
+        testing::runtime().block_on(async {
+            let address = testing::udp_upstream(|query| {
+                let mut packet = dns::response_from_query(query, dns::DNS_RCODE_NOERROR).ok()?;
+                packet[2] |= 0x02;
+                Some(packet)
+            })
+            .await;
+            testing::tcp_upstream(address, testing::answer).await;
+            let doh = testing::doh(testing::globals(address));
+            let query = dns::build_query("example.com", dns::DNS_TYPE_A, false, false).unwrap();
+            let packet = doh.upstream_query(&query).await.unwrap();
+            assert!(!dns::is_truncated(&packet));
+            assert_eq!(dns::ancount(&packet), 1);
+        });
+    }
+
+    #[test]
+    fn forced_tcp_queries_skip_udp() {
+        testing::runtime().block_on(async {
+            // Only answers over TCP, and only with the TCP EDNS payload size
+            let address = testing::tcp_upstream("127.0.0.1:0".parse().unwrap(), |query| {
+                match dns::client_edns_size(query) {
+                    Some(4000) => testing::answer(query),
+                    _ => None,
+                }
+            })
+            .await;
+            let mut globals = testing::globals(address);
+            globals.force_tcp = true;
+            globals.tcp_edns_size = 4000;
+            let doh = testing::doh(globals);
+            let query = dns::build_query("example.com", dns::DNS_TYPE_A, false, false).unwrap();
+            let packet = doh.upstream_query(&query).await.unwrap();
+            assert_eq!(dns::ancount(&packet), 1);
+        });
+    }
+
+    #[test]
+    fn tcp_queries_can_use_fast_open() {
+        testing::runtime().block_on(async {
+            let address =
+                testing::tcp_upstream("127.0.0.1:0".parse().unwrap(), testing::answer).await;
+            let mut globals = testing::globals(address);
+            globals.force_tcp = true;
+            globals.upstream_tcp_fastopen = true;
+            let doh = testing::doh(globals);
+            let query = dns::build_query("example.com", dns::DNS_TYPE_A, false, false).unwrap();
+            let packet = doh.upstream_query(&query).await.unwrap();
+            assert_eq!(dns::ancount(&packet), 1);
+        });
+    }
+
+    #[test]
+    fn race_losers_dont_lower_the_edns_size() {
+        testing::runtime().block_on(async {
+            let fast_address = testing::udp_upstream(testing::answer).await;
+            // Only answers the first query, so that its response size is known
+            let answered = AtomicBool::new(false);
+            let slow_address = testing::udp_upstream(move |query| {
+                if answered.swap(true, Ordering::Relaxed) {
+                    return None;
+                }
+                testing::answer(query)
+            })
+            .await;
+            let mut globals = testing::globals(fast_address);
+            globals.upstreams = Upstreams::new(&[fast_address, slow_address]);
+            globals.race_upstreams = true;
+            globals.adaptive_edns_size = true;
+            globals.upstream_timeout = Duration::from_millis(100);
+            let doh = testing::doh(globals);
+            let upstreams = doh.globals.upstreams.current();
+            let slow_upstream = upstreams
+                .iter()
+                .find(|upstream| upstream.address == slow_address)
+                .unwrap();
+            let query = dns::build_query("example.com", dns::DNS_TYPE_A, false, false).unwrap();
+            doh.udp_query(slow_upstream, &query).await.unwrap();
+
+            for _ in 0..ADAPTIVE_EDNS_SIZE_TIMEOUTS {
+                let packet = doh.upstream_query(&query).await.unwrap();
+                assert_eq!(dns::ancount(&packet), 1);
+            }
+            assert_eq!(slow_upstream.edns_size(1232), 1232);
+
+            // Queries that actually time out still lower it
+            for _ in 0..ADAPTIVE_EDNS_SIZE_TIMEOUTS {
+                let res = doh.udp_query(slow_upstream, &query).await;
+                assert!(matches!(res, Err(DoHError::UpstreamTimeout)));
+            }
+            assert_eq!(
+                slow_upstream.edns_size(1232),
+                dns::DNS_MIN_EDNS_PAYLOAD_SIZE
+            );
+        });
+    }
+
+    // Answers with the client cookie of the query, followed by a server cookie
+    fn answer_with_cookie(query: &[u8], client_cookie: Option<[u8; 8]>) -> Option<Vec<u8>> {
+        let query_cookie = dns::edns_cookie(query).ok()??;
+        let mut packet = testing::answer(query)?;
+        let mut query_client_cookie = [0; 8];
+        query_client_cookie.copy_from_slice(&query_cookie[..8]);
+        let client_cookie = client_cookie.unwrap_or(query_client_cookie);
+        dns::set_edns_cookies(&mut packet, client_cookie, &[9; 16]).ok()?;
+        Some(packet)
+    }
+
+    #[test]
+    fn tcp_queries_carry_cookies() {
+        testing::runtime().block_on(async {
+            let queries = Arc::new(Mutex::new(vec![]));
+            let address = testing::tcp_upstream("127.0.0.1:0".parse().unwrap(), {
+                let queries = queries.clone();
+                move |query| {
+                    queries.lock().unwrap().push(query.to_vec());
+                    answer_with_cookie(query, None)
+                }
+            })
+            .await;
+            let mut globals = testing::globals(address);
+            globals.force_tcp = true;
+            let doh = testing::doh(globals);
+            let query = dns::build_query("example.com", dns::DNS_TYPE_A, false, false).unwrap();
+            for _ in 0..2 {
+                let packet = doh.upstream_query(&query).await.unwrap();
+                assert_eq!(dns::ancount(&packet), 1);
+                // The server cookie isn't sent to clients
+                assert_eq!(dns::edns_cookie(&packet), Ok(None));
+            }
+            let queries = queries.lock().unwrap();
+            let first_cookie = dns::edns_cookie(&queries[0]).unwrap().unwrap();
+            let second_cookie = dns::edns_cookie(&queries[1]).unwrap().unwrap();
+            assert_eq!(first_cookie.len(), 8);
+            assert_eq!(second_cookie[..8], first_cookie[..]);
+            assert_eq!(second_cookie[8..], [9; 16]);
+        });
+    }
+
+    #[test]
+    fn responses_with_another_client_cookie_are_rejected() {
+        testing::runtime().block_on(async {
+            let respond = |query: &[u8]| answer_with_cookie(query, Some([1; 8]));
+            let address = testing::udp_upstream(respond).await;
+            let tcp_address = testing::tcp_upstream("127.0.0.1:0".parse().unwrap(), respond).await;
+            let query = dns::build_query("example.com", dns::DNS_TYPE_A, false, false).unwrap();
+            for address in [address, tcp_address].iter() {
+                let mut globals = testing::globals(*address);
+                globals.force_tcp = *address == tcp_address;
+                let doh = testing::doh(globals);
+                let res = doh.upstream_query(&query).await;
+                assert!(matches!(res, Err(DoHError::UpstreamIssue)));
+            }
+        });
+    }
+}
@@ -0,0 +1,272 @@
+use crate::constants::*;
+use crate::dns;
+
+use byteorder::{BigEndian, ByteOrder};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+// (owner, type) -> [(TTL, rdata)]
+type ZoneRecords = HashMap<(String, u16), Vec<(u32, Vec<u8>)>>;
+
+/// Records loaded from a zone file (RFC 1035 master file format). Only A, AAAA,
+/// CNAME, MX, NS, PTR and TXT records are served; SOA records are ignored.
+#[derive(Debug, Default)]
+pub struct LocalZone {
+    records: ZoneRecords,
+}
+
+/// A record to add to the answer section of a response
+#[derive(Debug)]
+pub(crate) struct ZoneAnswer<'t> {
+    pub owner: &'t str,
+    pub rtype: u16,
+    pub ttl: u32,
+    pub rdata: &'t [u8],
+}
+
+fn invalid_line(line_number: usize, reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Local zone, line {}: {}", line_number, reason),
+    )
+}
+
+// Splits an entry into tokens, without comments and parentheses. Quoted strings
+// are returned as a single token, without the quotes.
+fn tokenize(entry: &str) -> Vec<String> {
+    let mut tokens = vec![];
+    let mut chars = entry.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            ';' => break,
+            '(' | ')' => {
+                chars.next();
+            }
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '"' => {
+                chars.next();
+                let mut token = String::new();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => token.extend(chars.next()),
+                        c => token.push(c),
+                    }
+                }
+                tokens.push(token);
+            }
+            _ => {
+                let mut token = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || c == ';' || c == '(' || c == ')' {
+                        break;
+                    }
+                    token.push(c);
+                    chars.next();
+                }
+                tokens.push(token);
+            }
+        }
+    }
+    tokens
+}
+
+// Names are stored lowercased, without the trailing dot
+fn absolute_name(name: &str, origin: &str) -> String {
+    let name = if name == "@" {
+        origin.to_string()
+    } else if let Some(name) = name.strip_suffix('.') {
+        name.to_string()
+    } else if origin.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", name, origin)
+    };
+    name.to_ascii_lowercase()
+}
+
+impl LocalZone {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let content = fs::read_to_string(path)?;
+        let mut records = ZoneRecords::new();
+        let mut origin = String::new();
+        let mut default_ttl = None;
+        let mut last_owner: Option<String> = None;
+        let mut last_ttl = None;
+        let mut lines = content.lines().enumerate();
+        while let Some((i, line)) = lines.next() {
+            let line_number = i + 1;
+            // Entries can span multiple lines within parentheses
+            let mut entry = line.to_string();
+            while entry.split(';').next().unwrap_or("").matches('(').count()
+                > entry.matches(')').count()
+            {
+                match lines.next() {
+                    Some((_, line)) => {
+                        entry.push(' ');
+                        entry.push_str(line.split(';').next().unwrap_or(""));
+                    }
+                    None => return Err(invalid_line(line_number, "unbalanced parentheses")),
+                }
+            }
+            let tokens = tokenize(&entry);
+            if tokens.is_empty() {
+                continue;
+            }
+            match tokens[0].to_ascii_uppercase().as_str() {
+                "$ORIGIN" => {
+                    let name = tokens
+                        .get(1)
+                        .ok_or_else(|| invalid_line(line_number, "missing origin"))?;
+                    origin = absolute_name(name, &origin);
+                    continue;
+                }
+                "$TTL" => {
+                    let ttl = tokens
+                        .get(1)
+                        .and_then(|ttl| ttl.parse().ok())
+                        .ok_or_else(|| invalid_line(line_number, "invalid TTL"))?;
+                    default_ttl = Some(ttl);
+                    continue;
+                }
+                directive if directive.starts_with('$') => {
+                    return Err(invalid_line(line_number, "unsupported directive"));
+                }
+                _ => {}
+            }
+            // A blank owner means the same owner as the previous record
+            let mut tokens = tokens.iter().map(String::as_str);
+            let owner = if line.starts_with(char::is_whitespace) {
+                last_owner
+                    .clone()
+                    .ok_or_else(|| invalid_line(line_number, "missing owner name"))?
+            } else {
+                absolute_name(tokens.next().unwrap(), &origin)
+            };
+            let mut ttl = None;
+            let mut rtype = None;
+            for token in &mut tokens {
+                if let Ok(value) = token.parse::<u32>() {
+                    ttl = Some(value);
+                } else if token.eq_ignore_ascii_case("IN") {
+                    continue;
+                } else {
+                    rtype = Some(token.to_ascii_uppercase());
+                    break;
+                }
+            }
+            let rtype = rtype.ok_or_else(|| invalid_line(line_number, "missing record type"))?;
+            let ttl = ttl
+                .or(default_ttl)
+                .or(last_ttl)
+                .unwrap_or(LOCAL_ZONE_DEFAULT_TTL);
+            last_owner = Some(owner.clone());
+            last_ttl = Some(ttl);
+            let args: Vec<&str> = tokens.collect();
+            let arg = |index: usize| {
+                args.get(index)
+                    .copied()
+                    .ok_or_else(|| invalid_line(line_number, "missing record data"))
+            };
+            let encode_name = |name: &str| {
+                dns::encode_name(&absolute_name(name, &origin))
+                    .map_err(|_| invalid_line(line_number, "invalid name"))
+            };
+            let (rtype, rdata) = match rtype.as_str() {
+                "A" => {
+                    let ip: Ipv4Addr = arg(0)?
+                        .parse()
+                        .map_err(|_| invalid_line(line_number, "invalid IPv4 address"))?;
+                    (dns::DNS_TYPE_A, ip.octets().to_vec())
+                }
+                "AAAA" => {
+                    let ip: Ipv6Addr = arg(0)?
+                        .parse()
+                        .map_err(|_| invalid_line(line_number, "invalid IPv6 address"))?;
+                    (dns::DNS_TYPE_AAAA, ip.octets().to_vec())
+                }
+                "CNAME" => (dns::DNS_TYPE_CNAME, encode_name(arg(0)?)?),
+                "NS" => (dns::DNS_TYPE_NS, encode_name(arg(0)?)?),
+                "PTR" => (dns::DNS_TYPE_PTR, encode_name(arg(0)?)?),
+                "MX" => {
+                    let preference: u16 = arg(0)?
+                        .parse()
+                        .map_err(|_| invalid_line(line_number, "invalid MX preference"))?;
+                    let mut rdata = vec![0u8; 2];
+                    BigEndian::write_u16(&mut rdata, preference);
+                    rdata.extend_from_slice(&encode_name(arg(1)?)?);
+                    (dns::DNS_TYPE_MX, rdata)
+                }
+                "TXT" => {
+                    arg(0)?;
+                    let mut rdata = vec![];
+                    for text in &args {
+                        if text.len() > 255 {
+                            return Err(invalid_line(line_number, "TXT string too long"));
+                        }
+                        rdata.push(text.len() as u8);
+                        rdata.extend_from_slice(text.as_bytes());
+                    }
+                    (dns::DNS_TYPE_TXT, rdata)
+                }
+                "SOA" => continue,
+                _ => return Err(invalid_line(line_number, "unsupported record type")),
+            };
+            records.entry((owner, rtype)).or_default().push((ttl, rdata));
+        }
+        Ok(LocalZone { records })
+    }
+
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        !self.records.is_empty()
+    }
+
+    /// Returns the records matching the name and type, following CNAME records
+    /// within the zone. `None` is returned if the zone has no matching records.
+    pub(crate) fn answer(&self, name: &str, qtype: u16) -> Option<Vec<ZoneAnswer<'_>>> {
+        let mut answers = vec![];
+        let mut name = name.trim_end_matches('.').to_ascii_lowercase();
+        for _ in 0..LOCAL_ZONE_MAX_CNAME_CHAIN {
+            if let Some((owner, rdatas)) = self.records.get_key_value(&(name.clone(), qtype)) {
+                answers.extend(rdatas.iter().map(|(ttl, rdata)| ZoneAnswer {
+                    owner: &owner.0,
+                    rtype: qtype,
+                    ttl: *ttl,
+                    rdata,
+                }));
+                break;
+            }
+            if qtype == dns::DNS_TYPE_CNAME {
+                break;
+            }
+            let (owner, rdatas) = match self
+                .records
+                .get_key_value(&(name.clone(), dns::DNS_TYPE_CNAME))
+            {
+                Some(cname) => cname,
+                None => break,
+            };
+            let (ttl, target) = &rdatas[0];
+            answers.push(ZoneAnswer {
+                owner: &owner.0,
+                rtype: dns::DNS_TYPE_CNAME,
+                ttl: *ttl,
+                rdata: target,
+            });
+            name = dns::name_to_string(target)
+                .trim_end_matches('.')
+                .to_string();
+        }
+        if answers.is_empty() {
+            None
+        } else {
+            Some(answers)
+        }
+    }
+}
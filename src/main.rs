@@ -13,14 +13,19 @@ use libdoh::*;
 use crate::config::*;
 use crate::constants::*;
 
+use clap::ArgMatches;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::sync::Arc;
 use std::time::Duration;
 
 fn main() {
+    with_matches(run)
+}
+
+fn run(matches: &ArgMatches) {
     let mut runtime_builder = tokio::runtime::Builder::new();
     runtime_builder.enable_all();
-    runtime_builder.threaded_scheduler();
+    configure_runtime(&mut runtime_builder, matches);
     runtime_builder.thread_name("doh-proxy");
     let mut runtime = runtime_builder.build().unwrap();
 
@@ -29,25 +34,105 @@ fn main() {
         tls_cert_path: None,
         #[cfg(feature = "tls")]
         tls_cert_key_path: None,
+        #[cfg(feature = "tls")]
+        enable_dot: false,
+        #[cfg(feature = "tls")]
+        dot_listen_address: DOT_LISTEN_ADDRESS.parse().unwrap(),
+        #[cfg(feature = "http3")]
+        enable_http3: false,
 
         listen_address: LISTEN_ADDRESS.parse().unwrap(),
+        reuse_port: false,
         local_bind_address: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
-        server_address: SERVER_ADDRESS.parse().unwrap(),
+        local_bind_port_range: None,
+        upstreams: Upstreams::new(&[SERVER_ADDRESS.parse().unwrap()]),
+        upstream_sources: vec![],
+        upstream_refresh_interval: None,
+        tcp_pool_size: TCP_POOL_SIZE,
+        tcp_idle_timeout: Duration::from_secs(TCP_IDLE_TIMEOUT_SEC),
+        tcp_keepalive: false,
+        upstream_tcp_fastopen: false,
+        udp_edns_size: UDP_EDNS_SIZE,
+        adaptive_edns_size: false,
+        tcp_edns_size: TCP_EDNS_SIZE,
+        force_tcp: false,
+        qname_minimization: false,
+        hostname: None,
+        response_headers: vec![],
+        server_timing: false,
         path: PATH.to_string(),
+        path_aliases: vec![],
         max_clients: MAX_CLIENTS,
+        max_request_size: MAX_REQUEST_SIZE,
+        max_client_packet_size: MAX_CLIENT_PACKET_SIZE,
+        body_read_timeout: Duration::from_millis(BODY_READ_TIMEOUT_MS),
+        body_min_rate: 0,
         timeout: Duration::from_secs(TIMEOUT_SEC),
+        shutdown_grace_period: Duration::from_secs(SHUTDOWN_GRACE_SECS),
+        upstream_timeout: Duration::from_secs(TIMEOUT_SEC),
+        upstream_retries: UPSTREAM_RETRIES,
+        upstream_failure_threshold: UPSTREAM_FAILURE_THRESHOLD,
+        upstream_cooldown: Duration::from_secs(UPSTREAM_COOLDOWN_SECS),
+        race_upstreams: false,
+        max_inflight_per_upstream: 0,
         clients_count: Default::default(),
+        inflight_queries: Default::default(),
+        inflight_upstream_queries: Default::default(),
+        max_concurrent_streams: MAX_CONCURRENT_STREAMS,
+        overload_servfail: false,
         min_ttl: MIN_TTL,
         max_ttl: MAX_TTL,
         err_ttl: ERR_TTL,
+        failure_ttls: dns::FailureTtls::uniform(ERR_TTL),
+        unreachable_ede_code: UNREACHABLE_EDE_CODE,
         keepalive: true,
         disable_post: false,
+        padding_block_size: 0,
+        randomize_case: false,
+        refuse_any: false,
+        allow_all_opcodes: false,
+        minimize_responses: false,
+        max_response_size: 0,
+        debug_packets: false,
+        cache: Default::default(),
+        max_cache_ttl: 0,
+        cache_persist_path: None,
+        ttl_jitter_pct: 0,
+        rate_limiter: Default::default(),
+        client_ip_header: None,
+        access_list: Default::default(),
+        trusted_proxies: Default::default(),
+        blocklist: Default::default(),
+        allowlist: Default::default(),
+        default_deny: false,
+        overrides: Default::default(),
+        local_zone: Default::default(),
+        poison_filter: Default::default(),
+        append_client_subnet: false,
+        allow_ping: false,
+        odoh_key_pair: None,
+        client_subnet_prefix_v4: CLIENT_SUBNET_PREFIX_V4,
+        client_subnet_prefix_v6: CLIENT_SUBNET_PREFIX_V6,
+        synthesize_aaaa: false,
+        dns64_prefix: DNS64_PREFIX.parse().unwrap(),
+        chaos_version: None,
+        chaos_hostname: None,
+        nsid: None,
+        rebinding_protection: Default::default(),
+        enable_metrics: false,
+        metrics_address: None,
+        metrics: Default::default(),
+        query_log: Default::default(),
+        malformed_packet_log: Default::default(),
+        health_check_name: None,
+        health_check: Default::default(),
 
         runtime_handle: runtime.handle().clone(),
     };
-    parse_opts(&mut globals);
+    parse_opts(&mut globals, matches);
     let doh = DoH {
         globals: Arc::new(globals),
+        remote_addr: None,
     };
     runtime.block_on(doh.entrypoint()).unwrap();
 }
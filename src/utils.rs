@@ -1,4 +1,5 @@
-use std::net::{SocketAddr, ToSocketAddrs};
+use libdoh::{Network, ResponseHeader};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, ToSocketAddrs};
 
 // functions to verify the startup arguments as correct
 pub(crate) fn verify_sock_addr(arg_val: String) -> Result<(), String> {
@@ -11,6 +12,16 @@ pub(crate) fn verify_sock_addr(arg_val: String) -> Result<(), String> {
     }
 }
 
+pub(crate) fn verify_ip_addr(arg_val: String) -> Result<(), String> {
+    match arg_val.parse::<IpAddr>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!(
+            "Could not parse \"{}\" as a valid IP address.",
+            arg_val
+        )),
+    }
+}
+
 pub(crate) fn verify_remote_server(arg_val: String) -> Result<(), String> {
     match arg_val.to_socket_addrs() {
         Ok(mut addr_iter) => match addr_iter.next() {
@@ -23,3 +34,89 @@ pub(crate) fn verify_remote_server(arg_val: String) -> Result<(), String> {
         Err(err) => Err(format!("{}", err)),
     }
 }
+
+pub(crate) fn verify_network(arg_val: String) -> Result<(), String> {
+    arg_val.parse::<Network>().map(|_| ())
+}
+
+pub(crate) fn verify_response_header(arg_val: String) -> Result<(), String> {
+    arg_val.parse::<ResponseHeader>().map(|_| ())
+}
+
+pub(crate) fn parse_dns64_prefix(arg_val: &str) -> Option<Ipv6Addr> {
+    match arg_val.parse::<Network>().ok()? {
+        network if network.prefix_len() == 96 => match network.address() {
+            IpAddr::V6(prefix) => Some(prefix),
+            IpAddr::V4(_) => None,
+        },
+        _ => None,
+    }
+}
+
+pub(crate) fn verify_dns64_prefix(arg_val: String) -> Result<(), String> {
+    match parse_dns64_prefix(&arg_val) {
+        Some(_) => Ok(()),
+        None => Err(format!(
+            "\"{}\" is not a valid NAT64 prefix (an IPv6 /96 network is required).",
+            arg_val
+        )),
+    }
+}
+
+pub(crate) fn parse_port_range(arg_val: &str) -> Option<(u16, u16)> {
+    let mut parts = arg_val.splitn(2, '-');
+    let first: u16 = parts.next()?.trim().parse().ok()?;
+    let last: u16 = match parts.next() {
+        Some(last) => last.trim().parse().ok()?,
+        None => first,
+    };
+    if first == 0 || first > last {
+        return None;
+    }
+    Some((first, last))
+}
+
+pub(crate) fn verify_port_range(arg_val: String) -> Result<(), String> {
+    match parse_port_range(&arg_val) {
+        Some(_) => Ok(()),
+        None => Err(format!(
+            "\"{}\" is not a valid port range (e.g. 50000-51000).",
+            arg_val
+        )),
+    }
+}
+
+pub(crate) fn verify_nonzero(arg_val: String) -> Result<(), String> {
+    match arg_val.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(()),
+        _ => Err(format!("\"{}\" is not a positive number.", arg_val)),
+    }
+}
+
+fn verify_prefix_len(arg_val: &str, max_prefix_len: u8) -> Result<(), String> {
+    match arg_val.parse::<u8>() {
+        Ok(prefix_len) if prefix_len <= max_prefix_len => Ok(()),
+        _ => Err(format!(
+            "\"{}\" is not a valid prefix length (0-{}).",
+            arg_val, max_prefix_len
+        )),
+    }
+}
+
+pub(crate) fn verify_ipv4_prefix_len(arg_val: String) -> Result<(), String> {
+    verify_prefix_len(&arg_val, 32)
+}
+
+pub(crate) fn verify_ipv6_prefix_len(arg_val: String) -> Result<(), String> {
+    verify_prefix_len(&arg_val, 128)
+}
+
+pub(crate) fn verify_edns_size(arg_val: String) -> Result<(), String> {
+    match arg_val.parse::<u16>() {
+        Ok(size) if (512..=4096).contains(&size) => Ok(()),
+        _ => Err(format!(
+            "\"{}\" is not a valid EDNS payload size (512-4096).",
+            arg_val
+        )),
+    }
+}